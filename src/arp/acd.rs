@@ -0,0 +1,269 @@
+// IPv4 Address Conflict Detection, RFC 5227.
+//
+// Before using an address we probe for it, ARP requests with a sender address of
+// 0 that anyone already using it will answer. If nobody does we announce it with
+// gratuitous ARP, and from then on defend it against other hosts claiming it.
+// Only addresses that made it through probing are answered for.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::BuildHasher;
+use std::time::{Duration, Instant};
+
+use super::ArpPacket;
+use crate::eth::EtherType;
+use crate::iface::Interface;
+
+/// Timing constants, RFC 5227 section 1.1.
+const PROBE_WAIT: Duration = Duration::from_secs(1);
+const PROBE_NUM: u32 = 3;
+const PROBE_MIN: Duration = Duration::from_secs(1);
+const PROBE_MAX: Duration = Duration::from_secs(2);
+const ANNOUNCE_WAIT: Duration = Duration::from_secs(2);
+const ANNOUNCE_NUM: u32 = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_CONFLICTS: u32 = 10;
+const RATE_LIMIT_INTERVAL: Duration = Duration::from_secs(60);
+const DEFEND_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ClaimState {
+    /// Checking nobody else has the address, with `sent` probes out so far.
+    Probing { sent: u32 },
+
+    /// The address is ours, `sent` gratuitous ARPs have told the link so far.
+    Announcing { sent: u32 },
+
+    /// The address is ours.
+    Bound,
+
+    /// Another host has the address, we don't use it.
+    Conflicted { mac: [u8; 6] },
+}
+
+struct Claim {
+    state: ClaimState,
+
+    /// When the next probe or announcement goes out.
+    next_at: Instant,
+
+    /// When we last defended the address, RFC 5227 section 2.4 (b).
+    defended_at: Option<Instant>,
+}
+
+/// The addresses we have claimed and how far each has got.
+#[derive(Default)]
+pub struct Acd {
+    claims: HashMap<u32, Claim>,
+
+    /// Picks the random delays between probes.
+    random: RandomState,
+    draws: u64,
+
+    /// Conflicts found while probing. Past `MAX_CONFLICTS` probing slows down.
+    conflicts: u32,
+
+    /// Probes and announcements to send, see `poll_transmit`.
+    outbound: VecDeque<Vec<u8>>,
+}
+
+impl Acd {
+    pub fn new() -> Self {
+        Acd::default()
+    }
+
+    /// Starts probing for `ip`. It is ours once probing finds no conflict.
+    pub fn add(&mut self, ip: u32, now: Instant) {
+        let wait = if self.conflicts >= MAX_CONFLICTS {
+            RATE_LIMIT_INTERVAL
+        } else {
+            self.random_between(Duration::from_secs(0), PROBE_WAIT)
+        };
+        self.claims.insert(
+            ip,
+            Claim {
+                state: ClaimState::Probing { sent: 0 },
+                next_at: now + wait,
+                defended_at: None,
+            },
+        );
+    }
+
+    /// Takes `ip` as ours straight away, without probing or announcing it.
+    pub fn add_unchecked(&mut self, ip: u32) {
+        let claim = Claim {
+            state: ClaimState::Bound,
+            next_at: Instant::now(),
+            defended_at: None,
+        };
+        self.claims.insert(ip, claim);
+    }
+
+    pub fn remove(&mut self, ip: u32) {
+        self.claims.remove(&ip);
+    }
+
+    pub fn state(&self, ip: u32) -> Option<ClaimState> {
+        self.claims.get(&ip).map(|claim| claim.state)
+    }
+
+    /// Whether `ip` is ours to use and answer for.
+    pub fn is_ours(&self, ip: u32) -> bool {
+        matches!(
+            self.state(ip),
+            Some(ClaimState::Announcing { .. }) | Some(ClaimState::Bound)
+        )
+    }
+
+    /// Sends the probes and announcements that are due.
+    pub fn on_tick(&mut self, iface: &Interface, now: Instant) {
+        let due: Vec<u32> = self
+            .claims
+            .iter()
+            .filter(|(_, claim)| claim.next_at <= now)
+            .map(|(ip, _)| *ip)
+            .collect();
+        for ip in due {
+            let gap = self.random_between(PROBE_MIN, PROBE_MAX);
+            let claim = self.claims.get_mut(&ip).unwrap();
+            let packet = match claim.state {
+                ClaimState::Probing { sent } if sent < PROBE_NUM => {
+                    let last = sent + 1 == PROBE_NUM;
+                    claim.state = ClaimState::Probing { sent: sent + 1 };
+                    claim.next_at = now + if last { ANNOUNCE_WAIT } else { gap };
+                    ArpPacket::probe(iface.mac(), ip).to_bytes()
+                }
+                ClaimState::Probing { .. } | ClaimState::Announcing { .. } => {
+                    let sent = match claim.state {
+                        ClaimState::Announcing { sent } => sent + 1,
+                        _ => {
+                            println!("[ARP] claimed {:X?}", ip);
+                            1
+                        }
+                    };
+                    claim.state = match sent {
+                        ANNOUNCE_NUM => ClaimState::Bound,
+                        _ => ClaimState::Announcing { sent },
+                    };
+                    claim.next_at = now + ANNOUNCE_INTERVAL;
+                    announcement(iface, ip)
+                }
+                ClaimState::Bound | ClaimState::Conflicted { .. } => {
+                    claim.next_at = now + RATE_LIMIT_INTERVAL;
+                    continue;
+                }
+            };
+            self.outbound
+                .push_back(iface.frame([0xFF; 6], EtherType::Arp, &packet));
+        }
+    }
+
+    /// Checks a received ARP packet against our claims, returning whether it
+    /// conflicts with one, in which case it shouldn't be learned from.
+    pub fn on_packet(
+        &mut self,
+        iface: &Interface,
+        sender_mac: [u8; 6],
+        sender_ip: u32,
+        target_ip: u32,
+        now: Instant,
+    ) -> bool {
+        if sender_mac == iface.mac() {
+            return false;
+        }
+        // Another host probing for an address we are probing for conflicts too,
+        // RFC 5227 section 2.1.1.
+        let (ip, probe) = match (self.claims.get(&sender_ip), sender_ip) {
+            (Some(_), _) => (sender_ip, false),
+            (None, 0) => (target_ip, true),
+            (None, _) => return false,
+        };
+        let claim = match self.claims.get_mut(&ip) {
+            Some(claim) => claim,
+            None => return false,
+        };
+
+        match claim.state {
+            ClaimState::Probing { .. } => {
+                println!("[ARP] {:X?} is in use by {:X?}", ip, sender_mac);
+                claim.state = ClaimState::Conflicted { mac: sender_mac };
+                self.conflicts += 1;
+            }
+            // Someone probing for an address we hold is answered by our reply as
+            // usual, only a host actually using it calls for defending it.
+            ClaimState::Announcing { .. } | ClaimState::Bound if probe => return false,
+            ClaimState::Announcing { .. } | ClaimState::Bound => match claim.defended_at {
+                Some(at) if now.duration_since(at) < DEFEND_INTERVAL => {
+                    println!("[ARP] giving up {:X?} to {:X?}", ip, sender_mac);
+                    claim.state = ClaimState::Conflicted { mac: sender_mac };
+                }
+                _ => {
+                    println!("[ARP] defending {:X?} against {:X?}", ip, sender_mac);
+                    claim.defended_at = Some(now);
+                    let frame = iface.frame([0xFF; 6], EtherType::Arp, &announcement(iface, ip));
+                    self.outbound.push_back(frame);
+                }
+            },
+            ClaimState::Conflicted { .. } => {}
+        }
+        true
+    }
+
+    /// Takes the next frame that should be sent.
+    pub fn poll_transmit(&mut self) -> Option<Vec<u8>> {
+        self.outbound.pop_front()
+    }
+
+    fn random_between(&mut self, min: Duration, max: Duration) -> Duration {
+        self.draws += 1;
+        let span = (max - min).as_millis() as u64 + 1;
+        min + Duration::from_millis(self.random.hash_one(self.draws) % span)
+    }
+}
+
+/// A gratuitous ARP announcing `ip` is ours, RFC 5227 section 2.3.
+fn announcement(iface: &Interface, ip: u32) -> [u8; 28] {
+    ArpPacket::announcement(iface.mac(), ip).to_bytes()
+}
+
+#[cfg(test)]
+#[test]
+fn test_claim() {
+    let start = Instant::now();
+    let mut acd = Acd::new();
+    let iface = Interface::default();
+    let ours = 0x0a000002;
+    let other = [0x02, 0, 0, 0, 0, 0x01];
+    acd.add(ours, start);
+    acd.add(0x0a000003, start);
+    assert!(!acd.is_ours(ours));
+
+    let mut probes = 0;
+    for secs in 0..12 {
+        acd.on_tick(&iface, start + Duration::from_secs(secs));
+    }
+    while let Some(frame) = acd.poll_transmit() {
+        let packet = &frame[18..];
+        if packet[14..18] == [0; 4] && packet[24..28] == ours.to_be_bytes() {
+            probes += 1;
+        }
+    }
+    assert_eq!(probes, PROBE_NUM);
+    assert_eq!(acd.state(ours), Some(ClaimState::Bound));
+
+    // The first claim is defended, a second one soon after wins.
+    let now = start + Duration::from_secs(20);
+    assert!(acd.on_packet(&iface, other, ours, ours, now));
+    assert!(acd.is_ours(ours));
+    assert_eq!(
+        acd.poll_transmit().unwrap()[18..],
+        announcement(&iface, ours)
+    );
+    assert!(acd.on_packet(&iface, other, ours, ours, now + Duration::from_secs(1)));
+    assert_eq!(acd.state(ours), Some(ClaimState::Conflicted { mac: other }));
+
+    // Somebody answering a probe.
+    acd.add(0x0a000004, now);
+    assert!(acd.on_packet(&iface, other, 0x0a000004, ours, now));
+    assert!(!acd.is_ours(0x0a000004));
+}
@@ -0,0 +1,225 @@
+// Watching for ARP spoofing. Anyone on the link can claim any address, so before
+// a packet changes the neighbor cache it is checked for the usual signs of a host
+// poisoning it:
+//
+// - an address whose MAC keeps changing, two hosts fighting over it,
+// - one MAC claiming a great many addresses,
+// - a reply nobody asked for replacing the MAC of a REACHABLE entry.
+//
+// Each is reported as a `SpoofEvent`. By default the update still goes ahead, in
+// refuse mode it is ignored and the cache keeps what it had.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use super::{Neighbor, NeighborState};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SpoofEvent {
+    /// `ip` moved from `old` to `new`, its `changes`th move within the window.
+    Flapping {
+        ip: u32,
+        old: [u8; 6],
+        new: [u8; 6],
+        changes: usize,
+    },
+
+    /// `mac` claimed `ip`, making `count` addresses within the window.
+    ManyAddresses { mac: [u8; 6], ip: u32, count: usize },
+
+    /// A reply we didn't ask for tried to move the REACHABLE `ip` from `old` to `new`.
+    UnsolicitedOverride { ip: u32, old: [u8; 6], new: [u8; 6] },
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SpoofConfig {
+    /// How far back changes and claims are remembered.
+    pub window: Duration,
+
+    /// MAC changes for one address within the window that count as flapping.
+    pub max_changes: usize,
+
+    /// Addresses one MAC may claim within the window.
+    pub max_addresses: usize,
+
+    /// Ignore updates that raise an event instead of just reporting them.
+    pub refuse: bool,
+}
+
+impl Default for SpoofConfig {
+    fn default() -> Self {
+        SpoofConfig {
+            window: Duration::from_secs(60),
+            max_changes: 3,
+            max_addresses: 32,
+            refuse: false,
+        }
+    }
+}
+
+/// Events are dropped, oldest first, past this many unpolled.
+const MAX_EVENTS: usize = 64;
+
+#[derive(Default)]
+pub struct SpoofGuard {
+    config: SpoofConfig,
+
+    /// When each address last changed MAC, within the window.
+    changes: HashMap<u32, VecDeque<Instant>>,
+
+    /// The addresses each MAC claimed within the window, and when last.
+    claims: HashMap<[u8; 6], HashMap<u32, Instant>>,
+
+    events: VecDeque<SpoofEvent>,
+}
+
+impl SpoofGuard {
+    pub fn new() -> Self {
+        SpoofGuard::default()
+    }
+
+    pub fn with_config(config: SpoofConfig) -> Self {
+        SpoofGuard {
+            config,
+            ..SpoofGuard::default()
+        }
+    }
+
+    pub fn config(&self) -> &SpoofConfig {
+        &self.config
+    }
+
+    pub fn set_refuse(&mut self, refuse: bool) {
+        self.config.refuse = refuse;
+    }
+
+    /// Checks `mac` claiming `ip` against `entry`, what the cache holds for it,
+    /// returning whether the cache should be updated.
+    pub fn check(
+        &mut self,
+        ip: u32,
+        mac: [u8; 6],
+        reply: bool,
+        entry: Option<&Neighbor>,
+        now: Instant,
+    ) -> bool {
+        let window = self.config.window;
+        let mut suspicious = false;
+
+        let claimed = self.claims.entry(mac).or_default();
+        claimed.retain(|_, at| now.saturating_duration_since(*at) < window);
+        let new_claim = claimed.insert(ip, now).is_none();
+        if new_claim && claimed.len() > self.config.max_addresses {
+            let count = claimed.len();
+            self.report(SpoofEvent::ManyAddresses { mac, ip, count });
+            suspicious = true;
+        }
+
+        if let Some(entry) = entry {
+            if let Some(old) = entry.mac.filter(|old| *old != mac) {
+                let changes = self.changes.entry(ip).or_default();
+                changes.retain(|at| now.saturating_duration_since(*at) < window);
+                changes.push_back(now);
+                let count = changes.len();
+                if count >= self.config.max_changes {
+                    self.report(SpoofEvent::Flapping {
+                        ip,
+                        old,
+                        new: mac,
+                        changes: count,
+                    });
+                    suspicious = true;
+                }
+                if reply && entry.state == NeighborState::Reachable {
+                    self.report(SpoofEvent::UnsolicitedOverride { ip, old, new: mac });
+                    suspicious = true;
+                }
+            }
+        }
+
+        if suspicious && self.config.refuse {
+            println!("[ARP] refusing {:X?} for {:X?}", mac, ip);
+            return false;
+        }
+        true
+    }
+
+    /// Takes the next event raised.
+    pub fn poll_event(&mut self) -> Option<SpoofEvent> {
+        self.events.pop_front()
+    }
+
+    /// Forgets changes and claims older than the window.
+    pub fn on_tick(&mut self, now: Instant) {
+        let window = self.config.window;
+        let recent = |at: &Instant| now.saturating_duration_since(*at) < window;
+        self.changes.retain(|_, changes| {
+            changes.retain(recent);
+            !changes.is_empty()
+        });
+        self.claims.retain(|_, claimed| {
+            claimed.retain(|_, at| recent(at));
+            !claimed.is_empty()
+        });
+    }
+
+    fn report(&mut self, event: SpoofEvent) {
+        println!("[ARP] possible spoofing: {:X?}", event);
+        if self.events.len() == MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_guard() {
+    use super::NeighborCache;
+
+    let start = Instant::now();
+    let at = |secs: u64| start + Duration::from_secs(secs);
+    let (a, b) = ([0x02, 0, 0, 0, 0, 0x0a], [0x02, 0, 0, 0, 0, 0x0b]);
+    let mut cache = NeighborCache::new();
+    let mut guard = SpoofGuard::with_config(SpoofConfig {
+        max_changes: 2,
+        max_addresses: 2,
+        ..SpoofConfig::default()
+    });
+
+    // A reply moving a REACHABLE entry.
+    cache.confirm(1, a, at(0));
+    assert!(guard.check(1, b, true, cache.get(1), at(1)));
+    let old = a;
+    let new = b;
+    assert_eq!(
+        guard.poll_event(),
+        Some(SpoofEvent::UnsolicitedOverride { ip: 1, old, new })
+    );
+    cache.learn(1, b, at(1));
+
+    // Moving back flaps, refused now.
+    guard.set_refuse(true);
+    assert!(!guard.check(1, a, false, cache.get(1), at(2)));
+    let flapping = SpoofEvent::Flapping {
+        ip: 1,
+        old: b,
+        new: a,
+        changes: 2,
+    };
+    assert_eq!(guard.poll_event(), Some(flapping));
+    assert_eq!(guard.poll_event(), None);
+
+    // A third address for one MAC, then forgotten with time.
+    assert!(guard.check(2, a, false, None, at(3)));
+    assert!(!guard.check(3, a, false, None, at(3)));
+    let many = SpoofEvent::ManyAddresses {
+        mac: a,
+        ip: 3,
+        count: 3,
+    };
+    assert_eq!(guard.poll_event(), Some(many));
+    guard.on_tick(at(100));
+    assert!(guard.claims.is_empty() && guard.changes.is_empty());
+    assert!(guard.check(4, a, false, None, at(100)));
+}
@@ -0,0 +1,267 @@
+// Rate limits on ARP, so a host flooding the link with requests, or a storm of
+// them, costs us little. Every packet is charged to its sender's bucket and then
+// to a global one, dropping it when either is empty, and the replies we send are
+// limited the same way by who asked. A single sender thus only ever uses up its
+// own share of the global bucket.
+//
+// Buckets are kept by sender MAC, forgotten once they have filled up again. The
+// global bucket is checked first, so a flood from spoofed MACs past the global
+// limit adds none, and there are never more than `MAX_SENDERS` of them.
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// A token bucket, `rate` packets a second with bursts of up to `burst`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RateLimit {
+    pub rate: u32,
+    pub burst: u32,
+}
+
+/// Limits for each sender and for everyone together.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LimitConfig {
+    pub sender: RateLimit,
+    pub global: RateLimit,
+}
+
+/// What was let through and what wasn't, since startup.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ArpStats {
+    pub received: u64,
+    pub dropped_requests: u64,
+    pub dropped_replies: u64,
+
+    /// Requests we would have answered but for the reply limits.
+    pub suppressed_replies: u64,
+}
+
+/// Tokens are counted in thousandths, so slow rates still refill every millisecond.
+const SCALE: u64 = 1000;
+
+/// The most senders with a bucket of their own. Packets from new senders past
+/// this are dropped until buckets are forgotten.
+const MAX_SENDERS: usize = 4096;
+
+/// How often full buckets are forgotten.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens: u64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Bucket {
+            tokens: limit.burst as u64 * SCALE,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_millis() as u64;
+        self.tokens = (self.tokens + elapsed * limit.rate as u64).min(limit.burst as u64 * SCALE);
+        self.updated = now;
+    }
+
+    fn is_full(&self, limit: RateLimit) -> bool {
+        self.tokens >= limit.burst as u64 * SCALE
+    }
+}
+
+struct Limiter {
+    config: LimitConfig,
+    senders: HashMap<[u8; 6], Bucket>,
+    global: Bucket,
+}
+
+impl Limiter {
+    fn new(config: LimitConfig, now: Instant) -> Self {
+        Limiter {
+            config,
+            senders: HashMap::new(),
+            global: Bucket::new(config.global, now),
+        }
+    }
+
+    /// Takes a token from `mac`'s bucket and the global one, if both have one.
+    fn allow(&mut self, mac: [u8; 6], now: Instant) -> bool {
+        let config = self.config;
+        self.global.refill(config.global, now);
+        if self.global.tokens < SCALE {
+            return false;
+        }
+        if self.senders.len() >= MAX_SENDERS && !self.senders.contains_key(&mac) {
+            return false;
+        }
+        let sender = self
+            .senders
+            .entry(mac)
+            .or_insert_with(|| Bucket::new(config.sender, now));
+        sender.refill(config.sender, now);
+        if sender.tokens < SCALE {
+            return false;
+        }
+        sender.tokens -= SCALE;
+        self.global.tokens -= SCALE;
+        true
+    }
+
+    fn prune(&mut self, now: Instant) {
+        let limit = self.config.sender;
+        self.senders.retain(|_, bucket| {
+            bucket.refill(limit, now);
+            !bucket.is_full(limit)
+        });
+    }
+}
+
+pub struct ArpLimits {
+    packets: Limiter,
+    replies: Limiter,
+    stats: ArpStats,
+
+    /// Senders currently being dropped, so each is only reported once.
+    limited: HashSet<[u8; 6]>,
+    pruned_at: Instant,
+}
+
+impl Default for ArpLimits {
+    fn default() -> Self {
+        let packets = LimitConfig {
+            sender: RateLimit {
+                rate: 10,
+                burst: 20,
+            },
+            global: RateLimit {
+                rate: 100,
+                burst: 200,
+            },
+        };
+        let replies = LimitConfig {
+            sender: RateLimit { rate: 5, burst: 10 },
+            global: RateLimit {
+                rate: 50,
+                burst: 100,
+            },
+        };
+        ArpLimits::new(packets, replies)
+    }
+}
+
+impl ArpLimits {
+    /// Limits on the packets processed and on the replies sent to them.
+    pub fn new(packets: LimitConfig, replies: LimitConfig) -> Self {
+        let now = Instant::now();
+        ArpLimits {
+            packets: Limiter::new(packets, now),
+            replies: Limiter::new(replies, now),
+            stats: ArpStats::default(),
+            limited: HashSet::new(),
+            pruned_at: now,
+        }
+    }
+
+    pub fn stats(&self) -> ArpStats {
+        self.stats
+    }
+
+    /// Whether a packet from `mac` should be processed at all.
+    pub fn allow_packet(&mut self, mac: [u8; 6], request: bool, now: Instant) -> bool {
+        self.stats.received += 1;
+        if self.packets.allow(mac, now) {
+            return true;
+        }
+        if request {
+            self.stats.dropped_requests += 1;
+        } else {
+            self.stats.dropped_replies += 1;
+        }
+        if self.limited.len() < MAX_SENDERS && self.limited.insert(mac) {
+            println!("[ARP] rate limiting {:X?}", mac);
+        }
+        false
+    }
+
+    /// Whether a request from `mac` may be answered.
+    pub fn allow_reply(&mut self, mac: [u8; 6], now: Instant) -> bool {
+        let allowed = self.replies.allow(mac, now);
+        if !allowed {
+            self.stats.suppressed_replies += 1;
+        }
+        allowed
+    }
+
+    /// Forgets senders that have been quiet long enough to fill their buckets.
+    pub fn on_tick(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.pruned_at) < PRUNE_INTERVAL {
+            return;
+        }
+        self.pruned_at = now;
+        self.packets.prune(now);
+        self.replies.prune(now);
+        let senders = &self.packets.senders;
+        self.limited.retain(|mac| senders.contains_key(mac));
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_limits() {
+    let start = Instant::now();
+    let at = |ms: u64| start + Duration::from_millis(ms);
+    let limit = |rate, burst| LimitConfig {
+        sender: RateLimit { rate, burst },
+        global: RateLimit {
+            rate: 2 * rate,
+            burst: 2 * burst,
+        },
+    };
+    let mut limits = ArpLimits::new(limit(10, 5), limit(1, 1));
+    let (a, b, c) = ([1; 6], [2; 6], [3; 6]);
+
+    // A burst, then one more every 100ms.
+    assert_eq!(
+        (0..8)
+            .filter(|_| limits.allow_packet(a, true, at(0)))
+            .count(),
+        5
+    );
+    assert!(!limits.allow_packet(a, true, at(50)));
+    assert!(limits.allow_packet(a, false, at(100)));
+    assert_eq!(limits.stats().dropped_requests, 4);
+
+    // Others have their own buckets, until the global one runs out.
+    assert_eq!(
+        (0..5)
+            .filter(|_| limits.allow_packet(b, true, at(100)))
+            .count(),
+        5
+    );
+    assert_eq!(
+        (0..5)
+            .filter(|_| limits.allow_packet(c, true, at(100)))
+            .count(),
+        1
+    );
+
+    assert!(limits.allow_reply(a, at(0)));
+    assert!(!limits.allow_reply(a, at(500)));
+    assert!(limits.allow_reply(a, at(1000)));
+    assert_eq!(limits.stats().suppressed_replies, 1);
+
+    limits.on_tick(at(10_000));
+    assert!(limits.packets.senders.is_empty());
+    assert!(limits.limited.is_empty());
+
+    // A flood from spoofed MACs only gets buckets while the global one lasts.
+    let flood = (0..1000u32).filter(|i| {
+        let mut mac = [0x02; 6];
+        mac[2..].clone_from_slice(&i.to_be_bytes());
+        limits.allow_packet(mac, true, at(10_000))
+    });
+    assert_eq!(flood.count(), 10);
+    assert_eq!(limits.packets.senders.len(), 10);
+}
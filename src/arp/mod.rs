@@ -1,6 +1,21 @@
-use std::collections::HashMap;
+mod acd;
+mod guard;
+mod limit;
+mod neighbor;
+mod rarp;
+
+use std::collections::{HashMap, VecDeque};
 use std::convert::TryInto;
-use tun_tap::Iface;
+use std::io;
+use std::time::Instant;
+
+pub use self::acd::{Acd, ClaimState};
+pub use self::guard::{SpoofConfig, SpoofEvent, SpoofGuard};
+pub use self::limit::{ArpLimits, ArpStats, LimitConfig, RateLimit};
+pub use self::neighbor::{Neighbor, NeighborCache, NeighborConfig, NeighborEvent, NeighborState};
+pub use self::rarp::Rarp;
+use crate::eth::{self, EtherType};
+use crate::iface::Interface;
 
 ///An ARP Packet.
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -30,24 +45,105 @@ pub struct ArpPacket {
 }
 
 impl ArpPacket {
-    pub fn from_slice(slice: &ArpPacketSlice) -> Self {
-        let ipv4_slice = ArpPacketSlice {
-            slice: &slice.slice,
-        };
-        ArpPacket {
+    /// The Ethernet/IPv4 packet in `slice`, None if it is for any other hardware
+    /// or protocol.
+    pub fn from_slice(slice: &ArpPacketSlice) -> Option<Self> {
+        if slice.hardware_type() != HARDWARE_ETHERNET
+            || slice.hardware_size() != 6
+            || slice.proto_type() != PROTO_IPV4
+            || slice.proto_size() != 4
+        {
+            return None;
+        }
+        let ip = |bytes: &[u8]| u32::from_be_bytes(bytes.try_into().unwrap());
+        Some(ArpPacket {
             hardware_type: slice.hardware_type(),
             proto_type: slice.proto_type(),
             hardware_size: slice.hardware_size(),
             proto_size: slice.proto_size(),
             opcode: slice.opcode(),
             ipv4_data: ArpIpv4 {
-                source_mac: ipv4_slice.source_mac(),
-                source_ip: ipv4_slice.source_ip(),
-                destination_mac: ipv4_slice.destination_mac(),
-                destination_ip: ipv4_slice.destination_ip(),
+                source_mac: slice.sender_hardware().try_into().unwrap(),
+                source_ip: ip(slice.sender_proto()),
+                destination_mac: slice.target_hardware().try_into().unwrap(),
+                destination_ip: ip(slice.target_proto()),
+            },
+        })
+    }
+
+    /// An Ethernet/IPv4 packet with the given sender and target <MAC, IP> pairs.
+    pub fn new(opcode: u16, sender: ([u8; 6], u32), target: ([u8; 6], u32)) -> Self {
+        ArpPacket {
+            hardware_type: HARDWARE_ETHERNET,
+            proto_type: PROTO_IPV4,
+            hardware_size: 6,
+            proto_size: 4,
+            opcode,
+            ipv4_data: ArpIpv4 {
+                source_mac: sender.0,
+                source_ip: sender.1,
+                destination_mac: target.0,
+                destination_ip: target.1,
             },
         }
     }
+
+    /// A request from `mac` at `ip` for whoever has `target_ip`.
+    pub fn request(mac: [u8; 6], ip: u32, target_ip: u32) -> Self {
+        ArpPacket::new(OP_REQUEST, (mac, ip), ([0; 6], target_ip))
+    }
+
+    /// The reply to `request`, saying the address it asked for is at `mac`.
+    pub fn reply(request: &ArpPacket, mac: [u8; 6]) -> Self {
+        let asker = &request.ipv4_data;
+        ArpPacket::new(
+            OP_REPLY,
+            (mac, asker.destination_ip),
+            (asker.source_mac, asker.source_ip),
+        )
+    }
+
+    /// A probe checking whether anyone has `ip`, with a sender address of 0,
+    /// RFC 5227 section 2.1.1.
+    pub fn probe(mac: [u8; 6], ip: u32) -> Self {
+        ArpPacket::request(mac, 0, ip)
+    }
+
+    /// A gratuitous request telling the link `ip` is at `mac`, RFC 5227 section 2.3.
+    pub fn announcement(mac: [u8; 6], ip: u32) -> Self {
+        ArpPacket::request(mac, ip, ip)
+    }
+
+    pub fn opcode(&self) -> u16 {
+        self.opcode
+    }
+
+    pub fn sender_mac(&self) -> [u8; 6] {
+        self.ipv4_data.source_mac
+    }
+
+    pub fn sender_ip(&self) -> u32 {
+        self.ipv4_data.source_ip
+    }
+
+    pub fn target_mac(&self) -> [u8; 6] {
+        self.ipv4_data.destination_mac
+    }
+
+    pub fn target_ip(&self) -> u32 {
+        self.ipv4_data.destination_ip
+    }
+
+    pub fn to_bytes(&self) -> [u8; 28] {
+        let data = &self.ipv4_data;
+        let mut packet = [0u8; 28];
+        ArpBuilder::new(self.hardware_type, self.proto_type, self.opcode)
+            .sender(&data.source_mac, &data.source_ip.to_be_bytes())
+            .target(&data.destination_mac, &data.destination_ip.to_be_bytes())
+            .write(&mut packet)
+            .unwrap();
+        packet
+    }
 }
 
 /// For the moment only supporting Ipv4 over Ethernet, meaning the entire packet should be 28
@@ -67,7 +163,8 @@ pub struct ArpIpv4 {
     destination_ip: u32,
 }
 
-///A slice containing an ARP Packet.
+///A slice containing an ARP Packet, of any hardware and protocol. The address
+/// fields are as long as `hardware_size` and `proto_size` say.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct ArpPacketSlice<'a> {
     slice: &'a [u8],
@@ -76,58 +173,127 @@ pub struct ArpPacketSlice<'a> {
 impl<'a> ArpPacketSlice<'a> {
     /// Using functions to grab this data so we can add to implementations and error/bounds
     /// checking easier.
-    fn hardware_type(&self) -> u16 {
+    pub fn hardware_type(&self) -> u16 {
         u16::from_be_bytes([self.slice[0], self.slice[1]])
     }
 
-    fn proto_type(&self) -> u16 {
+    pub fn proto_type(&self) -> u16 {
         u16::from_be_bytes([self.slice[2], self.slice[3]])
     }
 
-    fn hardware_size(&self) -> u8 {
+    pub fn hardware_size(&self) -> u8 {
         self.slice[4]
     }
 
-    fn proto_size(&self) -> u8 {
+    pub fn proto_size(&self) -> u8 {
         self.slice[5]
     }
 
-    fn opcode(&self) -> u16 {
+    pub fn opcode(&self) -> u16 {
         u16::from_be_bytes([self.slice[6], self.slice[7]])
     }
 
-    fn source_mac(&self) -> [u8; 6] {
-        self.slice[8..14]
-            .try_into()
-            .expect(format!("Error in source MAC {0}:{1}", file!(), line!()).as_str())
+    /// `len` bytes of address starting `offset` bytes past the header.
+    fn field(&self, offset: usize, len: u8) -> &'a [u8] {
+        &self.slice[8 + offset..8 + offset + len as usize]
     }
 
-    fn source_ip(&self) -> u32 {
-        u32::from_be_bytes([
-            self.slice[14],
-            self.slice[15],
-            self.slice[16],
-            self.slice[17],
-        ])
+    pub fn sender_hardware(&self) -> &'a [u8] {
+        self.field(0, self.hardware_size())
     }
 
-    fn destination_mac(&self) -> [u8; 6] {
-        self.slice[18..24]
-            .try_into()
-            .expect(format!("Error in destination MAC {0}:{1}", file!(), line!()).as_str())
+    pub fn sender_proto(&self) -> &'a [u8] {
+        self.field(self.hardware_size() as usize, self.proto_size())
     }
 
-    fn destination_ip(&self) -> u32 {
-        u32::from_be_bytes([
-            self.slice[24],
-            self.slice[25],
-            self.slice[26],
-            self.slice[27],
-        ])
+    pub fn target_hardware(&self) -> &'a [u8] {
+        let sender = self.hardware_size() as usize + self.proto_size() as usize;
+        self.field(sender, self.hardware_size())
     }
 
-    fn read_from_slice(data: &'a [u8; 28]) -> Self {
-        ArpPacketSlice { slice: data }
+    pub fn target_proto(&self) -> &'a [u8] {
+        let sender = self.hardware_size() as usize + self.proto_size() as usize;
+        self.field(sender + self.hardware_size() as usize, self.proto_size())
+    }
+
+    /// The packet at the start of `data`, None if it is too short to hold the
+    /// addresses its header says it has. Anything after them is padding.
+    pub fn read_from_slice(data: &'a [u8]) -> Option<Self> {
+        let header = data.get(..8)?;
+        let len = 8 + 2 * (header[4] as usize + header[5] as usize);
+        Some(ArpPacketSlice {
+            slice: data.get(..len)?,
+        })
+    }
+}
+
+/// Assembles an ARP packet for any hardware and protocol, the sizes taken from
+/// the addresses given.
+#[derive(Clone, Debug)]
+pub struct ArpBuilder<'a> {
+    hardware_type: u16,
+    proto_type: u16,
+    opcode: u16,
+    sender: (&'a [u8], &'a [u8]),
+    target: (&'a [u8], &'a [u8]),
+}
+
+impl<'a> ArpBuilder<'a> {
+    pub fn new(hardware_type: u16, proto_type: u16, opcode: u16) -> Self {
+        ArpBuilder {
+            hardware_type,
+            proto_type,
+            opcode,
+            sender: (&[], &[]),
+            target: (&[], &[]),
+        }
+    }
+
+    pub fn sender(mut self, hardware: &'a [u8], proto: &'a [u8]) -> Self {
+        self.sender = (hardware, proto);
+        self
+    }
+
+    pub fn target(mut self, hardware: &'a [u8], proto: &'a [u8]) -> Self {
+        self.target = (hardware, proto);
+        self
+    }
+
+    /// The packet's length.
+    pub fn size(&self) -> usize {
+        8 + 2 * (self.sender.0.len() + self.sender.1.len())
+    }
+
+    /// Writes the packet to the start of `buf`, returning its length. The sender's
+    /// and target's addresses must be the same sizes, no longer than 255 bytes,
+    /// and `buf` long enough for them, otherwise `InvalidInput`.
+    pub fn write(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let (hln, pln) = (self.sender.0.len(), self.sender.1.len());
+        if self.target.0.len() != hln
+            || self.target.1.len() != pln
+            || hln > u8::MAX as usize
+            || pln > u8::MAX as usize
+            || buf.len() < self.size()
+        {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        buf[0..2].clone_from_slice(&self.hardware_type.to_be_bytes());
+        buf[2..4].clone_from_slice(&self.proto_type.to_be_bytes());
+        buf[4] = hln as u8;
+        buf[5] = pln as u8;
+        buf[6..8].clone_from_slice(&self.opcode.to_be_bytes());
+        let mut at = 8;
+        for field in [self.sender.0, self.sender.1, self.target.0, self.target.1].iter() {
+            buf[at..at + field.len()].clone_from_slice(field);
+            at += field.len();
+        }
+        Ok(at)
+    }
+
+    pub fn build(&self) -> io::Result<Vec<u8>> {
+        let mut packet = vec![0; self.size()];
+        self.write(&mut packet)?;
+        Ok(packet)
     }
 }
 
@@ -184,59 +350,187 @@ impl<'a> ArpPacketSlice<'a> {
 //
 //
 //
-pub type TranslationTable = HashMap<u32, [u8; 6]>;
+/// ar$hrd for Ethernet, and ar$pro for IPv4, which takes the IPv4 EtherType.
+pub const HARDWARE_ETHERNET: u16 = 0x0001;
+pub const PROTO_IPV4: u16 = 0x0800;
+
+pub const OP_REQUEST: u16 = 0x1;
+pub const OP_REPLY: u16 = 0x2;
+
+/// Everything ARP keeps, threaded through packet processing as one.
+#[derive(Default)]
+pub struct Arp {
+    pub neighbors: NeighborCache,
+    pub resolver: Resolver,
+    pub acd: Acd,
+    pub rarp: Rarp,
+    pub limits: ArpLimits,
+    pub guard: SpoofGuard,
+
+    /// Prefixes we answer requests for with our own MAC, as (network, length).
+    proxy: Vec<(u32, u8)>,
+}
+
+/// The netmask for a prefix length.
+fn mask(len: u8) -> u32 {
+    u32::MAX.checked_shl(32 - len as u32).unwrap_or(0)
+}
+
+impl Arp {
+    pub fn new() -> Self {
+        Arp::default()
+    }
+
+    /// Answers requests for addresses in `network/len` with our MAC, so traffic
+    /// for a subnet behind us is sent to us. Hosts inside the prefix asking for
+    /// each other are left to answer for themselves.
+    pub fn add_proxy(&mut self, network: u32, len: u8) -> io::Result<()> {
+        if len > 32 {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let prefix = (network & mask(len), len);
+        if !self.proxy.contains(&prefix) {
+            self.proxy.push(prefix);
+        }
+        Ok(())
+    }
+
+    pub fn remove_proxy(&mut self, network: u32, len: u8) -> bool {
+        let before = self.proxy.len();
+        self.proxy
+            .retain(|prefix| *prefix != (network & mask(len.min(32)), len));
+        self.proxy.len() != before
+    }
+
+    /// Whether we answer for `target_ip` when `sender_ip` asks.
+    fn is_proxied(&self, sender_ip: u32, target_ip: u32) -> bool {
+        self.proxy.iter().any(|&(network, len)| {
+            let inside = |ip: u32| ip & mask(len) == network;
+            inside(target_ip) && !inside(sender_ip)
+        })
+    }
+
+    /// Sends an IPv4 packet, see `Resolver::send`.
+    pub fn send(&mut self, iface: &Interface, packet: Vec<u8>, now: Instant) {
+        self.resolver.send(iface, &mut self.neighbors, packet, now);
+    }
+
+    /// Runs the timers of resolution, address claims and RARP.
+    pub fn on_tick(&mut self, iface: &Interface, now: Instant) {
+        self.resolver.on_tick(iface, &mut self.neighbors, now);
+        self.acd.on_tick(iface, now);
+        self.rarp.on_tick(iface, now);
+        self.limits.on_tick(now);
+        self.guard.on_tick(now);
+    }
+
+    /// Takes the next frame that should be sent.
+    pub fn poll_transmit(&mut self) -> Option<Vec<u8>> {
+        self.acd
+            .poll_transmit()
+            .or_else(|| self.rarp.poll_transmit())
+            .or_else(|| self.resolver.poll_transmit())
+    }
 
+    /// Takes the next packet that couldn't be resolved, see `Resolver::poll_unreachable`.
+    pub fn poll_unreachable(&mut self) -> Option<Vec<u8>> {
+        self.resolver.poll_unreachable()
+    }
+}
+
+/// Handles a received ARP packet as in the "Packet Reception" algorithm above,
+/// returning the reply to send back, if any. Besides our own addresses we are
+/// the target of those in the proxied prefixes.
 pub fn read_packet(
+    iface: &Interface,
     data: &[u8],
-    eth_hdr: &crate::eth::EthernetFrameSlice,
-    table: &mut TranslationTable,
-    // not sure if we want to implement this and pass this to reply, etc, we won't
-    // have to hardcode anything anymore, but yeah. Probably should.
+    eth_hdr: &eth::EthernetFrameSlice,
+    arp: &mut Arp,
+    now: Instant,
 ) -> Option<[u8; 28]> {
-    let packet_slice = &ArpPacketSlice { slice: &data };
-    let packet = ArpPacket::from_slice(packet_slice);
+    let packet_slice = ArpPacketSlice::read_from_slice(data)?;
+    // Checked before anything else, printing included, a storm should cost as
+    // little as possible. The frame's source stands in for the sender, it is
+    // there whatever the hardware type.
+    let request = packet_slice.opcode() == OP_REQUEST;
+    if !arp.limits.allow_packet(eth_hdr.source(), request, now) {
+        return None;
+    }
+    let packet = match ArpPacket::from_slice(&packet_slice) {
+        Some(packet) => packet,
+        None => {
+            println!(
+                "[ARP] unsupported hardware type {} or protocol type {:#X}",
+                packet_slice.hardware_type(),
+                packet_slice.proto_type()
+            );
+            return None;
+        }
+    };
+    if eth_hdr.destination() == [0xFF; 6] {
+        println!("Broadcast Received");
+    }
+
+    let sender_mac = packet.ipv4_data.source_mac;
+    let sender_ip = packet.ipv4_data.source_ip;
+    let target_ip = packet.ipv4_data.destination_ip;
+    if arp
+        .acd
+        .on_packet(iface, sender_mac, sender_ip, target_ip, now)
+    {
+        return None;
+    }
+    let for_us = arp.acd.is_ours(target_ip);
+    // Probes and gratuitous requests aren't asking anyone to answer.
+    let proxied = !for_us
+        && packet.opcode == OP_REQUEST
+        && sender_ip != 0
+        && sender_ip != target_ip
+        && arp.is_proxied(sender_ip, target_ip);
+    let neighbors = &mut arp.neighbors;
+    // Whatever would change the cache is checked for spoofing first.
+    let merges = sender_ip != 0 && (neighbors.contains(sender_ip) || for_us || proxied);
+    let entry = neighbors.get(sender_ip);
+    let refused = merges
+        && !arp
+            .guard
+            .check(sender_ip, sender_mac, packet.opcode == OP_REPLY, entry, now);
+    // Only a reply to us says the sender can hear us, anything else it sends
+    // leaves its entry STALE.
+    let merge = |neighbors: &mut NeighborCache| match packet.opcode {
+        _ if refused => {}
+        OP_REPLY if for_us => neighbors.confirm(sender_ip, sender_mac, now),
+        _ => neighbors.learn(sender_ip, sender_mac, now),
+    };
+    let mut merge_flag = false;
+    if neighbors.contains(sender_ip) {
+        merge(neighbors);
+        merge_flag = true;
+    }
+
+    if !for_us && !proxied {
+        return None;
+    }
+    // A sender address of 0 is a host probing for a free address, not one to
+    // learn, RFC 5227.
+    if !merge_flag && sender_ip != 0 {
+        merge(neighbors);
+    }
 
     match packet.opcode {
-        0x1 => {
-            if packet.ipv4_data.destination_mac == [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF] {
-                // If we get here, we have a broadcast.
-                println!("Broadcast Received");
+        OP_REQUEST => {
+            if !arp.limits.allow_reply(sender_mac, now) {
+                return None;
             }
-            // We don't need to do that much here,
-            // as we implement the rest of the
-            // parsing logic after this match.
-            // Lookup MAC in hashtable, attempt to get corresponding MAC
-            let res = table.get(&packet.ipv4_data.destination_ip);
-            match res {
-                Some(x) => {
-                    // If we have a corresponding MAC already.
-                    let reply_pkt = reply(packet_slice, *x, eth_hdr);
-                    Some(reply_pkt)
-                }
-
-                None => {
-                    println!(
-                        "No MAC Available for IP: {:X?}. Doing Nothing...",
-                        &packet.ipv4_data.destination_ip
-                    );
-                    // We don't have it stored, and as such don't need to
-                    // respond. We can simply wait for a reply, and store that.
-                    None
-                }
+            if proxied {
+                println!("[ARP] answering for {:X?} by proxy", target_ip);
             }
+            Some(ArpPacket::reply(&packet, iface.mac()).to_bytes())
         }
-
-        0x2 => {
+        OP_REPLY => {
             println!("ARP Reply Received");
-            // Update table with IP and corresponding MAC.
-            update_table(
-                table,
-                packet.ipv4_data.destination_mac,
-                packet.ipv4_data.destination_ip,
-            );
             None
         }
-
         _ => {
             eprintln!("Opcode not supported.");
             None
@@ -244,61 +538,351 @@ pub fn read_packet(
     }
 }
 
-/// Reply returns the ARP reply ONLY.
-fn reply(
-    packet_buf: &ArpPacketSlice,
-    found_mac: [u8; 6],
-    eth_hdr: &crate::eth::EthernetFrameSlice,
-) -> [u8; 28] {
-    assert_eq!(packet_buf.opcode(), 0x1);
+/// Packets held for each unresolved address, beyond this the oldest are dropped.
+const MAX_PENDING: usize = 16;
 
-    let mut new_packet = [0u8; 28];
+/// Active address resolution. IPv4 packets for a next hop with no usable entry in
+/// the neighbor cache are held while it resolves the address, then sent once a
+/// reply fills it in. If none comes they are dropped, and an ICMP host
+/// unreachable is produced for each so the sender can be told.
+#[derive(Default)]
+pub struct Resolver {
+    /// Packets waiting on each next hop.
+    pending: HashMap<u32, VecDeque<Vec<u8>>>,
 
-    // Copy in hardware type
-    new_packet[0] = (packet_buf.hardware_type() >> 8) as u8;
-    new_packet[1] = packet_buf.hardware_type() as u8;
+    /// Frames ready to go out, see `poll_transmit`.
+    outbound: VecDeque<Vec<u8>>,
 
-    // Copy in protocol type
-    new_packet[2] = (packet_buf.proto_type() >> 8) as u8;
-    new_packet[3] = packet_buf.proto_type() as u8;
+    /// ICMP host unreachable packets for what was dropped, see `poll_unreachable`.
+    unreachable: VecDeque<Vec<u8>>,
+}
 
-    // Copy in hardware and protcol length
-    new_packet[4] = packet_buf.hardware_size();
-    new_packet[5] = packet_buf.proto_size();
+impl Resolver {
+    pub fn new() -> Self {
+        Resolver::default()
+    }
 
-    // Copy in opcode
-    new_packet[6] = 0x00;
-    new_packet[7] = 0x02;
+    /// Sends an IPv4 packet to its destination, which we take to be on the link.
+    pub fn send(
+        &mut self,
+        iface: &Interface,
+        neighbors: &mut NeighborCache,
+        packet: Vec<u8>,
+        now: Instant,
+    ) {
+        if packet.len() > iface.mtu() {
+            println!("[ARP] {} byte packet over the MTU, dropping", packet.len());
+            return;
+        }
+        if packet.len() < 20 {
+            println!(
+                "[ARP] {} byte packet has no IPv4 header, dropping",
+                packet.len()
+            );
+            return;
+        }
+        let next_hop = u32::from_be_bytes(packet[16..20].try_into().unwrap());
+        if let Some(mac) = neighbors.lookup(next_hop, now) {
+            self.outbound
+                .push_back(iface.frame(mac, EtherType::Ipv4, &packet));
+            return;
+        }
 
-    let new_dest_mac: [u8; 6] = eth_hdr.source();
+        if neighbors.resolve(next_hop, now) {
+            let source = u32::from_be_bytes(packet[12..16].try_into().unwrap());
+            self.send_request(iface, source, next_hop, None);
+        } else if !neighbors.contains(next_hop) {
+            // No room to resolve it, nothing would ever send or fail the packet.
+            println!("[ARP] can't resolve {:X?}, dropping", next_hop);
+            return;
+        }
+        let pending = self.pending.entry(next_hop).or_default();
+        if pending.len() == MAX_PENDING {
+            println!("[ARP] queue for {:X?} full, dropping oldest", next_hop);
+            pending.pop_front();
+        }
+        pending.push_back(packet);
+    }
 
-    // Change source MAC to our NIC's source mac
-    new_packet[8..14].clone_from_slice(&found_mac);
+    /// Runs the neighbor cache's timers, sending the requests it asks for and
+    /// dropping what waited on addresses that failed, then sends whatever now has
+    /// an address. Should be called regularly, and after ARP packets arrive.
+    pub fn on_tick(&mut self, iface: &Interface, neighbors: &mut NeighborCache, now: Instant) {
+        for event in neighbors.on_tick(now) {
+            match event {
+                NeighborEvent::Request { ip, mac } => {
+                    let source = self
+                        .pending
+                        .get(&ip)
+                        .and_then(|packets| packets.front())
+                        .map(|packet| u32::from_be_bytes(packet[12..16].try_into().unwrap()))
+                        .or_else(|| iface.source_for(ip))
+                        .unwrap_or(0);
+                    self.send_request(iface, source, ip, mac);
+                }
+                NeighborEvent::Failed(ip) => {
+                    println!("[ARP] no reply from {:X?}, host unreachable", ip);
+                    for packet in self.pending.remove(&ip).unwrap_or_default() {
+                        self.unreachable.push_back(host_unreachable(&packet));
+                    }
+                }
+            }
+        }
 
-    new_packet[14..18].clone_from_slice(&packet_buf.destination_ip().to_be_bytes());
+        let next_hops: Vec<u32> = self.pending.keys().copied().collect();
+        for next_hop in next_hops {
+            match neighbors.lookup(next_hop, now) {
+                Some(mac) => {
+                    for packet in self.pending.remove(&next_hop).unwrap_or_default() {
+                        self.outbound
+                            .push_back(iface.frame(mac, EtherType::Ipv4, &packet));
+                    }
+                }
+                // The entry was evicted while resolving, start over.
+                None if neighbors.resolve(next_hop, now) => {
+                    let source = iface.source_for(next_hop).unwrap_or(0);
+                    self.send_request(iface, source, next_hop, None);
+                }
+                None => {}
+            }
+        }
+    }
 
-    new_packet[18..24].clone_from_slice(&new_dest_mac);
+    /// Takes the next frame that should be sent.
+    pub fn poll_transmit(&mut self) -> Option<Vec<u8>> {
+        self.outbound.pop_front()
+    }
 
-    // Change destination IP to request packet's source IP
-    new_packet[24..28].clone_from_slice(&packet_buf.slice[14..18]);
+    /// Takes the next ICMP host unreachable, an IPv4 packet addressed to whoever
+    /// sent the packet that was dropped.
+    pub fn poll_unreachable(&mut self) -> Option<Vec<u8>> {
+        self.unreachable.pop_front()
+    }
 
-    new_packet
+    /// Sends a request for `target_ip`, from whichever of our addresses the waiting
+    /// packets come from. It is broadcast unless we are checking a known `mac`.
+    fn send_request(
+        &mut self,
+        iface: &Interface,
+        source_ip: u32,
+        target_ip: u32,
+        mac: Option<[u8; 6]>,
+    ) {
+        let request = ArpPacket::request(iface.mac(), source_ip, target_ip).to_bytes();
+        let destination = mac.unwrap_or([0xFF; 6]);
+        self.outbound
+            .push_back(iface.frame(destination, EtherType::Arp, &request));
+    }
 }
 
-// Query HashMap, if not found update.
-fn update_table(map: &mut TranslationTable, found_mac: [u8; 6], ip: u32) {
-    match map.get(&ip) {
-        Some(x) => {
-            // got a corresponding IP.
-            if *x == found_mac {
-                return;
-            } else {
-                map.insert(ip, found_mac);
-            }
+/// An ICMP host unreachable about `packet`, from and to its sender.
+fn host_unreachable(packet: &[u8]) -> Vec<u8> {
+    let icmp = crate::icmp::destination_unreachable(crate::icmp::HOST_UNREACHABLE, packet);
+    let source = u32::from_be_bytes(packet[12..16].try_into().unwrap());
+    let mut unreachable =
+        crate::pkt::build_ipv4_header(source, source, crate::ipv4::ProtoType::ICMP, icmp.len())
+            .to_vec();
+    unreachable.extend_from_slice(&icmp);
+    unreachable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const PEER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+    const PEER_IP: u32 = 0x0a000001;
+    const OUR_IP: u32 = 0x0a000002;
+
+    #[test]
+    fn test_generic() {
+        let iface = Interface::default();
+        // An 8 byte hardware address and a 16 byte protocol one.
+        let (sender_hw, sender_proto) = ([1; 8], [2; 16]);
+        let (target_hw, target_proto) = ([3; 8], [4; 16]);
+        let builder = ArpBuilder::new(0x0020, 0x86DD, OP_REQUEST)
+            .sender(&sender_hw, &sender_proto)
+            .target(&target_hw, &target_proto);
+        let mut packet = builder.build().unwrap();
+        assert_eq!(packet.len(), 8 + 2 * (8 + 16));
+        packet.extend_from_slice(&[0; 6]);
+
+        let slice = ArpPacketSlice::read_from_slice(&packet).unwrap();
+        assert_eq!((slice.hardware_size(), slice.proto_size()), (8, 16));
+        assert_eq!(slice.sender_proto(), sender_proto);
+        assert_eq!(slice.target_hardware(), target_hw);
+        assert_eq!(slice.target_proto(), target_proto);
+        assert_eq!(ArpPacket::from_slice(&slice), None);
+        assert!(ArpPacketSlice::read_from_slice(&packet[..40]).is_none());
+        assert!(builder.target(&target_hw, &[4; 4]).build().is_err());
+
+        // The typed packets come out as they go in.
+        let request = ArpPacket::request(PEER_MAC, PEER_IP, OUR_IP);
+        let bytes = request.to_bytes();
+        let slice = ArpPacketSlice::read_from_slice(&bytes).unwrap();
+        assert_eq!(ArpPacket::from_slice(&slice), Some(request.clone()));
+        let reply = ArpPacket::reply(&request, iface.mac());
+        assert_eq!(
+            (reply.sender_mac(), reply.sender_ip()),
+            (iface.mac(), OUR_IP)
+        );
+        assert_eq!((reply.target_mac(), reply.target_ip()), (PEER_MAC, PEER_IP));
+        assert_eq!(ArpPacket::probe(PEER_MAC, PEER_IP).sender_ip(), 0);
+        assert_eq!(
+            ArpPacket::announcement(PEER_MAC, PEER_IP).target_ip(),
+            PEER_IP
+        );
+    }
+
+    #[test]
+    fn test_read_packet() {
+        let iface = Interface::default();
+        let mut arp = Arp::new();
+        let mut eth = [0u8; 14];
+        eth[..6].clone_from_slice(&[0xFF; 6]);
+        eth[6..12].clone_from_slice(&PEER_MAC);
+        let eth_hdr = eth::EthernetFrameSlice::read_from_slice(&eth);
+        let our_ip = OUR_IP;
+        arp.acd.add_unchecked(our_ip);
+
+        // A request for us is answered with our MAC, and teaches us the sender.
+        let request = ArpPacket::new(OP_REQUEST, (PEER_MAC, PEER_IP), ([0; 6], our_ip)).to_bytes();
+        let reply = read_packet(&iface, &request, &eth_hdr, &mut arp, Instant::now()).unwrap();
+        assert_eq!(
+            reply,
+            ArpPacket::new(OP_REPLY, (iface.mac(), our_ip), (PEER_MAC, PEER_IP)).to_bytes()
+        );
+        assert_eq!(arp.neighbors.get(PEER_IP).unwrap().mac, Some(PEER_MAC));
+        assert_eq!(
+            arp.neighbors.get(PEER_IP).unwrap().state,
+            NeighborState::Stale
+        );
+
+        // Entries learned from others are never answered for.
+        let other = 0x0a000009;
+        let request = ArpPacket::new(OP_REQUEST, ([0x02; 6], other), ([0; 6], PEER_IP)).to_bytes();
+        assert_eq!(
+            read_packet(&iface, &request, &eth_hdr, &mut arp, Instant::now()),
+            None
+        );
+        assert_eq!(arp.neighbors.get(other), None);
+
+        // A reply updates the sender's entry, not the target's.
+        let moved = [0x02, 0, 0, 0, 0, 0x02];
+        let reply = ArpPacket::new(OP_REPLY, (moved, PEER_IP), (iface.mac(), our_ip)).to_bytes();
+        assert_eq!(
+            read_packet(&iface, &reply, &eth_hdr, &mut arp, Instant::now()),
+            None
+        );
+        assert_eq!(arp.neighbors.get(PEER_IP).unwrap().mac, Some(moved));
+        assert_eq!(
+            arp.neighbors.get(PEER_IP).unwrap().state,
+            NeighborState::Reachable
+        );
+        assert_eq!(arp.neighbors.get(our_ip), None);
+
+        // Another reply moving it back so soon is refused in refuse mode.
+        arp.guard.set_refuse(true);
+        let reply = ArpPacket::new(OP_REPLY, (PEER_MAC, PEER_IP), (iface.mac(), our_ip)).to_bytes();
+        assert_eq!(
+            read_packet(&iface, &reply, &eth_hdr, &mut arp, Instant::now()),
+            None
+        );
+        assert_eq!(arp.neighbors.get(PEER_IP).unwrap().mac, Some(moved));
+        assert!(matches!(
+            arp.guard.poll_event(),
+            Some(SpoofEvent::UnsolicitedOverride { .. })
+        ));
+    }
+
+    #[test]
+    fn test_proxy() {
+        let iface = Interface::default();
+        let mut arp = Arp::new();
+        let eth_hdr = eth::EthernetFrameSlice::read_from_slice(&[0xFF; 14]);
+        arp.add_proxy(0x0a0100ff, 24).unwrap();
+        assert!(arp.add_proxy(0, 33).is_err());
+
+        // Hosts outside the prefix are told to send to us, ones inside aren't.
+        let behind = 0x0a010005;
+        let request = ArpPacket::new(OP_REQUEST, (PEER_MAC, PEER_IP), ([0; 6], behind)).to_bytes();
+        let reply = read_packet(&iface, &request, &eth_hdr, &mut arp, Instant::now()).unwrap();
+        assert_eq!(
+            reply,
+            ArpPacket::new(OP_REPLY, (iface.mac(), behind), (PEER_MAC, PEER_IP)).to_bytes()
+        );
+        let request =
+            ArpPacket::new(OP_REQUEST, (PEER_MAC, 0x0a010006), ([0; 6], behind)).to_bytes();
+        assert_eq!(
+            read_packet(&iface, &request, &eth_hdr, &mut arp, Instant::now()),
+            None
+        );
+
+        // Nor are probes.
+        let probe = ArpPacket::new(OP_REQUEST, (PEER_MAC, 0), ([0; 6], behind)).to_bytes();
+        assert_eq!(
+            read_packet(&iface, &probe, &eth_hdr, &mut arp, Instant::now()),
+            None
+        );
+
+        assert!(arp.remove_proxy(0x0a010000, 24));
+        assert_eq!(
+            read_packet(&iface, &request, &eth_hdr, &mut arp, Instant::now()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_resolver() {
+        let iface = Interface::default();
+        let mut table = NeighborCache::new();
+        let mut resolver = Resolver::new();
+        let now = Instant::now();
+        let packet = |dest: u32| {
+            let mut packet =
+                crate::pkt::build_ipv4_header(OUR_IP, dest, crate::ipv4::ProtoType::TCP, 8)
+                    .to_vec();
+            packet.extend_from_slice(&[0x13, 0x88, 0, 80, 0, 0, 0, 1]);
+            packet
+        };
+
+        // Both packets wait on one broadcast request.
+        resolver.send(&iface, &mut table, packet(PEER_IP), now);
+        resolver.send(&iface, &mut table, packet(PEER_IP), now);
+        let request = resolver.poll_transmit().unwrap();
+        assert_eq!(request[4..10], [0xFF; 6]);
+        assert_eq!(
+            request[18..],
+            ArpPacket::new(OP_REQUEST, (iface.mac(), OUR_IP), ([0; 6], PEER_IP)).to_bytes()
+        );
+        assert_eq!(resolver.poll_transmit(), None);
+
+        table.confirm(PEER_IP, PEER_MAC, now);
+        resolver.on_tick(&iface, &mut table, now);
+        for _ in 0..2 {
+            let frame = resolver.poll_transmit().unwrap();
+            assert_eq!(frame[4..10], PEER_MAC);
+            assert_eq!(frame[18..], packet(PEER_IP)[..]);
         }
 
-        None => {
-            map.insert(ip, found_mac);
+        // Without a reply the request is sent three times, then the packet dropped.
+        let silent = 0x0a000009;
+        resolver.send(&iface, &mut table, packet(silent), now);
+        for secs in 1..=3 {
+            resolver.on_tick(&iface, &mut table, now + Duration::from_secs(secs));
+        }
+        for _ in 0..3 {
+            assert_eq!(resolver.poll_transmit().unwrap()[16..18], [0x08, 0x06]);
         }
+        assert_eq!(resolver.poll_transmit(), None);
+        let unreachable = resolver.poll_unreachable().unwrap();
+        let original = crate::icmp::unreachable_original(&unreachable[20..]).unwrap();
+        assert_eq!(original, &packet(silent)[..]);
+        assert_eq!(resolver.poll_unreachable(), None);
+
+        // Packets too short to have a destination are dropped.
+        resolver.send(&iface, &mut table, packet(PEER_IP)[..12].to_vec(), now);
+        assert_eq!(resolver.poll_transmit(), None);
     }
 }
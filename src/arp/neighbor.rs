@@ -0,0 +1,466 @@
+// The neighbor cache: what we know of the link layer addresses of hosts on the
+// link, and how much we trust it. Entry states follow the Neighbor Unreachability
+// Detection of RFC 4861 section 7.3, which suits ARP just as well:
+//
+// - INCOMPLETE: a request has been broadcast and no answer has come yet.
+// - REACHABLE: the neighbor answered recently.
+// - STALE: it is a while since we heard from it, or we only heard of it in
+//   passing. The address is still used, but the next use checks it.
+// - PROBE: being checked, with requests sent straight to the cached address.
+// - FAILED: nobody answered, packets to it can't be sent.
+// - PERMANENT: configured, never aged, probed or changed by what the link says.
+//
+// RFC 4861's DELAY state is left out, nothing above us confirms reachability
+// so there is nothing worth waiting for before probing.
+//
+// Permanent entries can be loaded from a file in the format `arp -f` takes, and
+// the whole cache dumped in the format of Linux's /proc/net/arp.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use crate::eth;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NeighborState {
+    Incomplete,
+    Reachable,
+    Stale,
+    Probe,
+    Failed,
+    Permanent,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Neighbor {
+    /// None until the neighbor first answers.
+    pub mac: Option<[u8; 6]>,
+    pub state: NeighborState,
+
+    /// When the state last changed.
+    pub updated: Instant,
+
+    /// When a packet was last sent using the entry, for LRU eviction.
+    pub used: Instant,
+
+    /// Requests sent in the current INCOMPLETE or PROBE state.
+    probes: u32,
+
+    /// When the next request is due, or the resolution gives up.
+    next_probe: Instant,
+}
+
+/// Timeouts and limits, defaulting to the values Linux uses.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NeighborConfig {
+    /// How long an answer keeps an entry REACHABLE.
+    pub reachable_time: Duration,
+
+    /// Time between requests for the same address.
+    pub retrans_time: Duration,
+
+    /// Broadcast requests sent while INCOMPLETE before giving up.
+    pub max_broadcast_probes: u32,
+
+    /// Unicast requests sent while in PROBE before giving up.
+    pub max_unicast_probes: u32,
+
+    /// STALE and FAILED entries unused for this long are forgotten.
+    pub gc_stale_time: Duration,
+
+    /// Most entries kept, the least recently used is evicted beyond this.
+    pub capacity: usize,
+}
+
+impl Default for NeighborConfig {
+    fn default() -> Self {
+        NeighborConfig {
+            reachable_time: Duration::from_secs(30),
+            retrans_time: Duration::from_secs(1),
+            max_broadcast_probes: 3,
+            max_unicast_probes: 3,
+            gc_stale_time: Duration::from_secs(60),
+            capacity: 1024,
+        }
+    }
+}
+
+/// What the cache needs done, see `NeighborCache::on_tick`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NeighborEvent {
+    /// Send a request for `ip`, to `mac` if there is one, otherwise broadcast.
+    Request { ip: u32, mac: Option<[u8; 6]> },
+
+    /// Resolving `ip` failed, anything waiting on it should be dropped.
+    Failed(u32),
+}
+
+#[derive(Default)]
+pub struct NeighborCache {
+    entries: HashMap<u32, Neighbor>,
+    config: NeighborConfig,
+}
+
+impl NeighborCache {
+    pub fn new() -> Self {
+        NeighborCache::default()
+    }
+
+    pub fn with_config(config: NeighborConfig) -> Self {
+        NeighborCache {
+            entries: HashMap::new(),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &NeighborConfig {
+        &self.config
+    }
+
+    pub fn get(&self, ip: u32) -> Option<&Neighbor> {
+        self.entries.get(&ip)
+    }
+
+    pub fn contains(&self, ip: u32) -> bool {
+        self.entries.contains_key(&ip)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&u32, &Neighbor)> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn remove(&mut self, ip: u32) -> Option<Neighbor> {
+        self.entries.remove(&ip)
+    }
+
+    /// Adds a PERMANENT entry, replacing whatever was known of `ip`. These are
+    /// configured, not learned from the link, so they go in past `capacity`.
+    pub fn add_static(&mut self, ip: u32, mac: [u8; 6], now: Instant) {
+        let entry = new_entry(Some(mac), NeighborState::Permanent, now);
+        self.entries.insert(ip, entry);
+    }
+
+    /// Loads PERMANENT entries from lines of an address and a MAC, as `arp -f`
+    /// reads them:
+    ///
+    /// ```text
+    /// # gateway
+    /// 10.0.0.1  02:00:00:00:00:01
+    /// ```
+    ///
+    /// Returns how many were added. Nothing is added if any line is bad. Like
+    /// `add_static` this ignores `capacity`, the file is what bounds them.
+    pub fn load_static(&mut self, reader: impl BufRead, now: Instant) -> io::Result<usize> {
+        let mut loaded = vec![];
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.split('#').next().unwrap_or("");
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let entry = match fields[..] {
+                [] => continue,
+                [ip, mac] => ip
+                    .parse::<Ipv4Addr>()
+                    .ok()
+                    .zip(eth::parse_mac(mac))
+                    .map(|(ip, mac)| (u32::from(ip), mac)),
+                _ => None,
+            };
+            let bad = || {
+                let message = format!("line {}: expected an address and a MAC", number + 1);
+                io::Error::new(io::ErrorKind::InvalidData, message)
+            };
+            loaded.push(entry.ok_or_else(bad)?);
+        }
+        for &(ip, mac) in loaded.iter() {
+            self.add_static(ip, mac, now);
+        }
+        Ok(loaded.len())
+    }
+
+    /// Writes every entry in the format of /proc/net/arp, as seen on `device`.
+    /// Flags are 0x2 for a known MAC, with 0x4 added for PERMANENT entries.
+    pub fn export(&self, device: &str, mut out: impl Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "IP address       HW type     Flags       HW address            Mask     Device"
+        )?;
+        let mut ips: Vec<&u32> = self.entries.keys().collect();
+        ips.sort();
+        for ip in ips {
+            let entry = &self.entries[ip];
+            let flags = match (entry.state, entry.mac) {
+                (NeighborState::Permanent, _) => 0x6,
+                (NeighborState::Incomplete, _) | (NeighborState::Failed, _) | (_, None) => 0x0,
+                _ => 0x2,
+            };
+            let mac = match flags {
+                0x0 => [0; 6],
+                _ => entry.mac.unwrap_or([0; 6]),
+            };
+            writeln!(
+                out,
+                "{:<16} 0x1         {:<11} {:<21} *        {}",
+                Ipv4Addr::from(*ip),
+                format!("{:#x}", flags),
+                eth::format_mac(mac),
+                device
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The MAC to send a packet for `ip` to, if it is known. Using a STALE entry
+    /// starts probing it.
+    pub fn lookup(&mut self, ip: u32, now: Instant) -> Option<[u8; 6]> {
+        let entry = self.entries.get_mut(&ip)?;
+        entry.used = now;
+        match entry.state {
+            NeighborState::Reachable | NeighborState::Probe | NeighborState::Permanent => entry.mac,
+            NeighborState::Stale => {
+                set_state(entry, NeighborState::Probe, now);
+                entry.mac
+            }
+            NeighborState::Incomplete | NeighborState::Failed => None,
+        }
+    }
+
+    /// Starts resolving `ip`, returning whether a request should be broadcast now.
+    /// False while a resolution is already under way, or the MAC is known.
+    pub fn resolve(&mut self, ip: u32, now: Instant) -> bool {
+        match self.entries.get(&ip).map(|e| e.state) {
+            None | Some(NeighborState::Failed) => {}
+            Some(_) => return false,
+        }
+        let mut entry = new_entry(None, NeighborState::Incomplete, now);
+        entry.probes = 1;
+        entry.next_probe = now + self.config.retrans_time;
+        self.insert(ip, entry, now)
+    }
+
+    /// The neighbor answered us, so it is REACHABLE at `mac`.
+    pub fn confirm(&mut self, ip: u32, mac: [u8; 6], now: Instant) {
+        match self.entries.get_mut(&ip) {
+            Some(entry) if entry.state == NeighborState::Permanent => {}
+            Some(entry) => {
+                moved(ip, entry, mac);
+                entry.mac = Some(mac);
+                set_state(entry, NeighborState::Reachable, now);
+            }
+            None => {
+                let entry = new_entry(Some(mac), NeighborState::Reachable, now);
+                self.insert(ip, entry, now);
+            }
+        }
+    }
+
+    /// We heard of `mac` for `ip` without asking, eg. from its own request. That
+    /// says nothing about whether it hears us, so new or changed entries are STALE.
+    pub fn learn(&mut self, ip: u32, mac: [u8; 6], now: Instant) {
+        match self.entries.get_mut(&ip) {
+            Some(entry) if entry.mac == Some(mac) && entry.state != NeighborState::Failed => {}
+            Some(entry) if entry.state == NeighborState::Permanent => {}
+            Some(entry) => {
+                moved(ip, entry, mac);
+                entry.mac = Some(mac);
+                set_state(entry, NeighborState::Stale, now);
+            }
+            None => {
+                let entry = new_entry(Some(mac), NeighborState::Stale, now);
+                self.insert(ip, entry, now);
+            }
+        }
+    }
+
+    /// Runs the entries' timers: REACHABLE entries go STALE, requests are resent
+    /// or given up on, and old entries are forgotten.
+    pub fn on_tick(&mut self, now: Instant) -> Vec<NeighborEvent> {
+        let config = &self.config;
+        let mut events = vec![];
+        self.entries.retain(|&ip, entry| {
+            match entry.state {
+                NeighborState::Reachable
+                    if now.duration_since(entry.updated) >= config.reachable_time =>
+                {
+                    set_state(entry, NeighborState::Stale, now);
+                }
+                NeighborState::Incomplete | NeighborState::Probe if entry.next_probe <= now => {
+                    let (max, mac) = match entry.state {
+                        NeighborState::Incomplete => (config.max_broadcast_probes, None),
+                        _ => (config.max_unicast_probes, entry.mac),
+                    };
+                    if entry.probes < max {
+                        entry.probes += 1;
+                        entry.next_probe = now + config.retrans_time;
+                        events.push(NeighborEvent::Request { ip, mac });
+                    } else {
+                        println!("[ARP] {:X?} failed after {} requests", ip, entry.probes);
+                        set_state(entry, NeighborState::Failed, now);
+                        events.push(NeighborEvent::Failed(ip));
+                    }
+                }
+                NeighborState::Stale | NeighborState::Failed => {
+                    let idle = now.duration_since(entry.used.max(entry.updated));
+                    return idle < config.gc_stale_time;
+                }
+                _ => {}
+            }
+            true
+        });
+        events
+    }
+
+    /// Adds an entry, evicting the least recently used one if the cache is full.
+    /// PERMANENT entries are never evicted, when they fill the cache the new entry
+    /// is dropped instead. Returns whether it was added.
+    fn insert(&mut self, ip: u32, entry: Neighbor, now: Instant) -> bool {
+        if !self.entries.contains_key(&ip) && self.entries.len() >= self.config.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .filter(|(_, e)| e.state != NeighborState::Permanent)
+                .min_by_key(|(_, e)| e.used.max(e.updated))
+                .map(|(ip, _)| *ip);
+            match oldest {
+                Some(oldest) => {
+                    println!("[ARP] neighbor cache full, evicting {:X?}", oldest);
+                    self.entries.remove(&oldest);
+                }
+                None => {
+                    println!(
+                        "[ARP] neighbor cache full of static entries, dropping {:X?}",
+                        ip
+                    );
+                    return false;
+                }
+            }
+        }
+        let mut entry = entry;
+        entry.used = now;
+        self.entries.insert(ip, entry);
+        true
+    }
+}
+
+fn new_entry(mac: Option<[u8; 6]>, state: NeighborState, now: Instant) -> Neighbor {
+    Neighbor {
+        mac,
+        state,
+        updated: now,
+        used: now,
+        probes: 0,
+        next_probe: now,
+    }
+}
+
+/// Changes state, restarting the probe count. A new PROBE sends its first request
+/// on the next tick.
+fn set_state(entry: &mut Neighbor, state: NeighborState, now: Instant) {
+    entry.state = state;
+    entry.updated = now;
+    entry.probes = 0;
+    entry.next_probe = now;
+}
+
+fn moved(ip: u32, entry: &Neighbor, mac: [u8; 6]) {
+    if let Some(old) = entry.mac.filter(|old| *old != mac) {
+        println!("[ARP] {:X?} moved from {:X?} to {:X?}", ip, old, mac);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_states() {
+    let start = Instant::now();
+    let at = |secs: u64| start + Duration::from_secs(secs);
+    let mac = [0x02, 0, 0, 0, 0, 0x01];
+    let mut cache = NeighborCache::with_config(NeighborConfig {
+        capacity: 2,
+        ..NeighborConfig::default()
+    });
+
+    assert!(cache.resolve(1, at(0)));
+    assert!(!cache.resolve(1, at(0)));
+    assert_eq!(cache.lookup(1, at(0)), None);
+    cache.confirm(1, mac, at(0));
+    assert_eq!(cache.lookup(1, at(0)), Some(mac));
+
+    // Aged out, the next use probes it, and silence fails it.
+    assert!(cache.on_tick(at(30)).is_empty());
+    assert_eq!(cache.get(1).unwrap().state, NeighborState::Stale);
+    assert_eq!(cache.lookup(1, at(31)), Some(mac));
+    assert_eq!(cache.get(1).unwrap().state, NeighborState::Probe);
+    for secs in 31..34 {
+        let request = NeighborEvent::Request {
+            ip: 1,
+            mac: Some(mac),
+        };
+        assert_eq!(cache.on_tick(at(secs)), vec![request]);
+    }
+    assert_eq!(cache.on_tick(at(34)), vec![NeighborEvent::Failed(1)]);
+    assert_eq!(cache.lookup(1, at(34)), None);
+
+    // A third entry pushes out the least recently used.
+    cache.learn(2, mac, at(35));
+    cache.learn(3, mac, at(36));
+    assert!(!cache.contains(1));
+    assert_eq!(cache.get(3).unwrap().state, NeighborState::Stale);
+
+    cache.on_tick(at(200));
+    assert!(cache.is_empty());
+
+    // Static entries go in regardless, and when they fill it nothing is learned.
+    cache.add_static(4, mac, at(200));
+    cache.add_static(5, mac, at(200));
+    cache.add_static(6, mac, at(200));
+    cache.learn(7, mac, at(200));
+    assert!(!cache.resolve(8, at(200)));
+    assert_eq!(cache.len(), 3);
+    assert!(!cache.contains(7) && !cache.contains(8));
+}
+
+#[cfg(test)]
+#[test]
+fn test_static() {
+    let now = Instant::now();
+    let (gateway, other) = ([0x02, 0, 0, 0, 0, 0x01], [0x02, 0, 0, 0, 0, 0x02]);
+    let mut cache = NeighborCache::new();
+    let file = "# gateway\n10.0.0.1  02:00:00:00:00:01\n\n10.0.0.3 02:00:00:00:00:03 # printer\n";
+    assert_eq!(cache.load_static(file.as_bytes(), now).unwrap(), 2);
+    assert!(cache
+        .load_static("10.0.0.5 02:00:00\n".as_bytes(), now)
+        .is_err());
+    assert_eq!(cache.len(), 2);
+
+    // What the link says doesn't change them, and they never age.
+    cache.learn(0x0a000001, other, now);
+    cache.confirm(0x0a000001, other, now);
+    cache.on_tick(now + Duration::from_secs(3600));
+    assert_eq!(cache.lookup(0x0a000001, now), Some(gateway));
+    assert_eq!(
+        cache.get(0x0a000001).unwrap().state,
+        NeighborState::Permanent
+    );
+
+    cache.learn(0x0a000002, other, now);
+    assert!(cache.resolve(0x0a000004, now));
+    let mut out = vec![];
+    cache.export("tap0", &mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "IP address       HW type     Flags       HW address            Mask     Device\n\
+         10.0.0.1         0x1         0x6         02:00:00:00:00:01     *        tap0\n\
+         10.0.0.2         0x1         0x2         02:00:00:00:00:02     *        tap0\n\
+         10.0.0.3         0x1         0x6         02:00:00:00:00:03     *        tap0\n\
+         10.0.0.4         0x1         0x0         00:00:00:00:00:00     *        tap0\n"
+    );
+}
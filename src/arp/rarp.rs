@@ -0,0 +1,188 @@
+// Reverse ARP, RFC 903. A host that knows only its MAC broadcasts a request for
+// its own IPv4 address and a server that knows it answers. The packets are ARP's
+// with their own EtherType (0x8035) and opcodes, the MAC asked about goes in the
+// target hardware address and the answer in the target protocol address.
+//
+// In server mode we answer from a configured MAC to IPv4 map, in client mode we
+// ask for our own address until a server answers, and the link takes it on, see
+// `pkt::Link::on_tick`.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use super::{ArpPacket, ArpPacketSlice};
+use crate::eth::EtherType;
+use crate::iface::Interface;
+
+const OP_REQUEST_REVERSE: u16 = 0x3;
+const OP_REPLY_REVERSE: u16 = 0x4;
+
+/// Requests a client sends before giving up, and how far apart.
+const REQUEST_RETRIES: u32 = 5;
+const REQUEST_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Client mode, asking for our address.
+struct Client {
+    requests: u32,
+    next_at: Instant,
+}
+
+#[derive(Default)]
+pub struct Rarp {
+    /// Server mode, the addresses we answer with, by MAC. Empty when not serving.
+    addresses: HashMap<[u8; 6], u32>,
+
+    client: Option<Client>,
+
+    /// The address a server gave us, and the server's address.
+    assigned: Option<(u32, u32)>,
+
+    /// The assignment until `poll_assigned` takes it.
+    pending: Option<(u32, u32)>,
+
+    /// Requests to send, see `poll_transmit`.
+    outbound: VecDeque<Vec<u8>>,
+}
+
+impl Rarp {
+    pub fn new() -> Self {
+        Rarp::default()
+    }
+
+    /// Answers requests from `mac` with `ip`.
+    pub fn serve(&mut self, mac: [u8; 6], ip: u32) {
+        self.addresses.insert(mac, ip);
+    }
+
+    pub fn unserve(&mut self, mac: [u8; 6]) -> Option<u32> {
+        self.addresses.remove(&mac)
+    }
+
+    /// Starts asking for our own address, see `assigned`.
+    pub fn request_address(&mut self, now: Instant) {
+        self.assigned = None;
+        self.pending = None;
+        self.client = Some(Client {
+            requests: 0,
+            next_at: now,
+        });
+    }
+
+    /// The address a server gave us, and the server's, once one has answered.
+    pub fn assigned(&self) -> Option<(u32, u32)> {
+        self.assigned
+    }
+
+    /// Takes the address a server just gave us, once, to be put on the interface.
+    pub fn poll_assigned(&mut self) -> Option<(u32, u32)> {
+        self.pending.take()
+    }
+
+    /// Handles a received RARP packet, returning the reply to send back if we
+    /// are serving the MAC asked about.
+    pub fn read_packet(&mut self, iface: &Interface, data: &[u8]) -> Option<[u8; 28]> {
+        let packet = match ArpPacket::from_slice(&ArpPacketSlice::read_from_slice(data)?) {
+            Some(packet) => packet,
+            None => {
+                println!("[RARP] unsupported hardware or protocol type");
+                return None;
+            }
+        };
+
+        let target_mac = packet.ipv4_data.destination_mac;
+        match packet.opcode {
+            OP_REQUEST_REVERSE => {
+                let ip = *self.addresses.get(&target_mac)?;
+                println!("[RARP] {:X?} is {:X?}", target_mac, ip);
+                Some(
+                    ArpPacket::new(
+                        OP_REPLY_REVERSE,
+                        (iface.mac(), iface.source_for(ip).unwrap_or(0)),
+                        (target_mac, ip),
+                    )
+                    .to_bytes(),
+                )
+            }
+            OP_REPLY_REVERSE if target_mac == iface.mac() && self.client.is_some() => {
+                let ip = packet.ipv4_data.destination_ip;
+                let server = packet.ipv4_data.source_ip;
+                println!("[RARP] assigned {:X?} by {:X?}", ip, server);
+                self.assigned = Some((ip, server));
+                self.pending = self.assigned;
+                self.client = None;
+                None
+            }
+            OP_REPLY_REVERSE => None,
+            _ => {
+                eprintln!("Opcode not supported.");
+                None
+            }
+        }
+    }
+
+    /// Sends the client's requests as they fall due.
+    pub fn on_tick(&mut self, iface: &Interface, now: Instant) {
+        let client = match &mut self.client {
+            Some(client) if client.next_at <= now => client,
+            _ => return,
+        };
+        if client.requests == REQUEST_RETRIES {
+            println!("[RARP] no server answered");
+            self.client = None;
+            return;
+        }
+        client.requests += 1;
+        client.next_at = now + REQUEST_INTERVAL;
+        let request =
+            ArpPacket::new(OP_REQUEST_REVERSE, (iface.mac(), 0), (iface.mac(), 0)).to_bytes();
+        self.outbound
+            .push_back(iface.frame([0xFF; 6], EtherType::Rarp, &request));
+    }
+
+    /// Takes the next frame that should be sent.
+    pub fn poll_transmit(&mut self) -> Option<Vec<u8>> {
+        self.outbound.pop_front()
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_server() {
+    // The request and reply from sample_pcaps/rarp_req_reply.pcap, without their
+    // Ethernet headers.
+    let request = [
+        0x00, 0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x03, 0x00, 0x0c, 0x29, 0x34, 0x0b, 0xde, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x0c, 0x29, 0x34, 0x0b, 0xde, 0x00, 0x00, 0x00, 0x00,
+    ];
+    let reply = [
+        0x00, 0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x04, 0x00, 0x0c, 0x29, 0xc5, 0xf6, 0x9b, 0x0a,
+        0x01, 0x01, 0x0a, 0x00, 0x0c, 0x29, 0x34, 0x0b, 0xde, 0x0a, 0x01, 0x01, 0x64,
+    ];
+    let client_mac = [0x00, 0x0c, 0x29, 0x34, 0x0b, 0xde];
+
+    let mut rarp = Rarp::new();
+    let iface = Interface::default();
+    assert_eq!(rarp.read_packet(&iface, &request), None);
+    rarp.serve(client_mac, 0x0a010164);
+    let answer = rarp.read_packet(&iface, &request).unwrap();
+    assert_eq!(answer[..8], reply[..8]);
+    assert_eq!(answer[8..14], iface.mac());
+    assert_eq!(answer[18..], reply[18..]);
+
+    // As a client, asking until the server answers.
+    let now = Instant::now();
+    rarp.request_address(now);
+    rarp.on_tick(&iface, now);
+    rarp.on_tick(&iface, now);
+    let frame = rarp.poll_transmit().unwrap();
+    assert_eq!(frame[16..18], [0x80, 0x35]);
+    assert_eq!(
+        frame[18..],
+        ArpPacket::new(OP_REQUEST_REVERSE, (iface.mac(), 0), (iface.mac(), 0)).to_bytes()
+    );
+    assert_eq!(rarp.poll_transmit(), None);
+    let mut reply = reply;
+    reply[18..24].clone_from_slice(&iface.mac());
+    assert_eq!(rarp.read_packet(&iface, &reply), None);
+    assert_eq!(rarp.assigned(), Some((0x0a010164, 0x0a01010a)));
+}
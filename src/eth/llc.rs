@@ -0,0 +1,226 @@
+// IEEE 802.3 frames, where the type field holds the payload's length instead,
+// which then starts with an 802.2 LLC header: destination and source SAPs saying
+// what protocol it is, and a control field. SAP 0xAA is SNAP, another 5 bytes
+// with an OUI and a protocol, an EtherType when the OUI is zero (RFC 1042).
+//
+// IP and ARP sometimes come SNAP encapsulated, those frames are turned into
+// Ethernet II ones so the rest of the stack needn't care. Bridges speak STP
+// directly over LLC, and Cisco gear its CDP, VTP and friends over SNAP, we only
+// recognise those.
+
+/// Type fields up to this are lengths, 802.3.
+pub const MAX_LENGTH: u16 = 1500;
+
+pub const SAP_STP: u8 = 0x42;
+pub const SAP_SNAP: u8 = 0xAA;
+
+/// Unnumbered information, the only control field SNAP uses.
+pub const CONTROL_UI: u16 = 0x03;
+
+/// SNAP OUIs whose protocol is an EtherType: RFC 1042 and 802.1H bridge tunneling.
+pub const OUI_ETHERTYPE: [u8; 3] = [0x00, 0x00, 0x00];
+pub const OUI_BRIDGE_TUNNEL: [u8; 3] = [0x00, 0x00, 0xF8];
+pub const OUI_CISCO: [u8; 3] = [0x00, 0x00, 0x0C];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SnapHeader {
+    pub oui: [u8; 3],
+    pub protocol: u16,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LlcHeader {
+    pub dsap: u8,
+    pub ssap: u8,
+
+    /// One byte for U-format frames, two for I and S-format ones.
+    pub control: u16,
+    pub snap: Option<SnapHeader>,
+}
+
+/// What an LLC frame carries, as far as we can tell.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LlcProtocol {
+    /// SNAP with an EtherType, eg. IPv4 or ARP.
+    EtherType(u16),
+
+    /// Spanning tree BPDUs, 802.1D.
+    Stp,
+
+    /// Cisco's per-VLAN spanning tree, PVST+.
+    Pvst,
+
+    /// Cisco Discovery Protocol.
+    Cdp,
+
+    /// VLAN Trunking Protocol.
+    Vtp,
+
+    /// Dynamic Trunking Protocol.
+    Dtp,
+
+    /// Unidirectional Link Detection.
+    Udld,
+    Unknown,
+}
+
+impl LlcHeader {
+    /// A SNAP header for `protocol` of `oui`.
+    pub fn snap(oui: [u8; 3], protocol: u16) -> Self {
+        LlcHeader {
+            dsap: SAP_SNAP,
+            ssap: SAP_SNAP,
+            control: CONTROL_UI,
+            snap: Some(SnapHeader { oui, protocol }),
+        }
+    }
+
+    /// The header at the start of an 802.3 frame's payload, None if it is cut short.
+    pub fn read_from_slice(data: &[u8]) -> Option<Self> {
+        let (dsap, ssap) = (*data.first()?, *data.get(1)?);
+        let control = match *data.get(2)? {
+            control if control & 0x03 == 0x03 => control as u16,
+            control => u16::from_be_bytes([control, *data.get(3)?]),
+        };
+        let mut header = LlcHeader {
+            dsap,
+            ssap,
+            control,
+            snap: None,
+        };
+        if dsap == SAP_SNAP && ssap == SAP_SNAP && control == CONTROL_UI {
+            let snap = data.get(3..8)?;
+            header.snap = Some(SnapHeader {
+                oui: [snap[0], snap[1], snap[2]],
+                protocol: u16::from_be_bytes([snap[3], snap[4]]),
+            });
+        }
+        Some(header)
+    }
+
+    pub fn size(&self) -> usize {
+        let control = if self.control & 0x03 == 0x03 { 1 } else { 2 };
+        2 + control + self.snap.map(|_| 5).unwrap_or(0)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.dsap, self.ssap];
+        if self.control & 0x03 == 0x03 {
+            bytes.push(self.control as u8);
+        } else {
+            bytes.extend_from_slice(&self.control.to_be_bytes());
+        }
+        if let Some(snap) = self.snap {
+            bytes.extend_from_slice(&snap.oui);
+            bytes.extend_from_slice(&snap.protocol.to_be_bytes());
+        }
+        bytes
+    }
+
+    pub fn protocol(&self) -> LlcProtocol {
+        let snap = match self.snap {
+            Some(snap) => snap,
+            None if self.dsap == SAP_STP && self.ssap == SAP_STP => return LlcProtocol::Stp,
+            None => return LlcProtocol::Unknown,
+        };
+        match (snap.oui, snap.protocol) {
+            (OUI_ETHERTYPE, protocol) | (OUI_BRIDGE_TUNNEL, protocol) => {
+                LlcProtocol::EtherType(protocol)
+            }
+            (OUI_CISCO, 0x010B) => LlcProtocol::Pvst,
+            (OUI_CISCO, 0x0111) => LlcProtocol::Udld,
+            (OUI_CISCO, 0x2000) => LlcProtocol::Cdp,
+            (OUI_CISCO, 0x2003) => LlcProtocol::Vtp,
+            (OUI_CISCO, 0x2004) => LlcProtocol::Dtp,
+            _ => LlcProtocol::Unknown,
+        }
+    }
+}
+
+/// Whether a frame's type field is its length, making it an 802.3 frame.
+pub fn is_length(type_field: u16) -> bool {
+    type_field <= MAX_LENGTH
+}
+
+/// Reads the LLC header of the `len` byte 802.3 frame in `buf`, behind the
+/// preamble and untagged. If it is SNAP with an EtherType the frame is turned into
+/// the Ethernet II one, without the header or any padding. Returns the header and
+/// the frame's new length, None for Ethernet II frames or ones cut short.
+pub fn strip_llc(buf: &mut [u8], len: usize) -> Option<(LlcHeader, usize)> {
+    let length = u16::from_be_bytes([*buf.get(16)?, *buf.get(17)?]);
+    if !is_length(length) || 18 + length as usize > len {
+        return None;
+    }
+    let len = 18 + length as usize;
+    let llc = LlcHeader::read_from_slice(&buf[18..len])?;
+    match llc.protocol() {
+        LlcProtocol::EtherType(ethertype) => {
+            let size = llc.size();
+            buf.copy_within(18 + size..len, 18);
+            buf[16..18].clone_from_slice(&ethertype.to_be_bytes());
+            Some((llc, len - size))
+        }
+        _ => Some((llc, len)),
+    }
+}
+
+/// Turns the `len` byte Ethernet II frame in `buf`, behind the preamble and
+/// untagged, back into an 802.3 one with `llc`. Returns the frame's new length,
+/// None if `buf` can't hold it.
+pub fn insert_llc(buf: &mut [u8], len: usize, llc: &LlcHeader) -> Option<usize> {
+    let header = llc.to_bytes();
+    if len < 18 || len + header.len() > buf.len() {
+        return None;
+    }
+    buf.copy_within(18..len, 18 + header.len());
+    buf[18..18 + header.len()].clone_from_slice(&header);
+    let length = (len - 18 + header.len()) as u16;
+    buf[16..18].clone_from_slice(&length.to_be_bytes());
+    Some(len + header.len())
+}
+
+#[cfg(test)]
+#[test]
+fn test_llc() {
+    // SNAP encapsulated ARP, padded, becomes the plain frame and back.
+    let arp = [0xAB; 28];
+    let llc = LlcHeader::snap(OUI_ETHERTYPE, 0x0806);
+    let mut payload = llc.to_bytes();
+    payload.extend_from_slice(&arp);
+    let mut buf = [0u8; 128];
+    buf[4..10].clone_from_slice(&[0xFF; 6]);
+    buf[16..18].clone_from_slice(&(payload.len() as u16).to_be_bytes());
+    buf[18..18 + payload.len()].clone_from_slice(&payload);
+    let (header, len) = strip_llc(&mut buf, 4 + 60).unwrap();
+    assert_eq!(header, llc);
+    assert_eq!(len, 18 + arp.len());
+    assert_eq!(buf[16..len], [&[0x08, 0x06][..], &arp].concat()[..]);
+    let len = insert_llc(&mut buf, len, &header).unwrap();
+    assert_eq!(buf[16..18], [0, 36]);
+    assert_eq!(buf[18..len], payload[..]);
+
+    // STP and CDP are recognised, and left alone.
+    let stp = [SAP_STP, SAP_STP, 0x03, 0, 0, 0, 0];
+    buf[16..18].clone_from_slice(&(stp.len() as u16).to_be_bytes());
+    buf[18..18 + stp.len()].clone_from_slice(&stp);
+    let (header, len) = strip_llc(&mut buf, 4 + 60).unwrap();
+    assert_eq!((header.protocol(), len), (LlcProtocol::Stp, 18 + stp.len()));
+    assert_eq!(header.size(), 3);
+    let cdp = LlcHeader::snap(OUI_CISCO, 0x2000).to_bytes();
+    assert_eq!(
+        LlcHeader::read_from_slice(&cdp).unwrap().protocol(),
+        LlcProtocol::Cdp
+    );
+
+    // I-format control fields are two bytes, lengths past the frame are bad.
+    assert_eq!(
+        LlcHeader::read_from_slice(&[0xF0, 0xF0, 0x00, 0x02])
+            .unwrap()
+            .size(),
+        4
+    );
+    buf[16..18].clone_from_slice(&1000u16.to_be_bytes());
+    assert_eq!(strip_llc(&mut buf, 4 + 60), None);
+    buf[16..18].clone_from_slice(&0x0800u16.to_be_bytes());
+    assert_eq!(strip_llc(&mut buf, 4 + 60), None);
+}
@@ -1,7 +1,11 @@
+mod llc;
+
 use std::convert::TryInto;
-use tun_tap::Iface;
 
-pub static MAC: [u8; 6] = [0xbe, 0xe9, 0x7d, 0x63, 0x31, 0xbc];
+pub use self::llc::{
+    insert_llc, is_length, strip_llc, LlcHeader, LlcProtocol, SnapHeader, OUI_CISCO, OUI_ETHERTYPE,
+    SAP_SNAP, SAP_STP,
+};
 
 ///Ether type enum present in ethernet II header.
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -9,6 +13,7 @@ pub enum EtherType {
     Ipv4 = 0x0800,
     Ipv6 = 0x86dd,
     Arp = 0x0806,
+    Rarp = 0x8035,
     WakeOnLan = 0x0842,
     VlanTaggedFrame = 0x8100,
     ProviderBridging = 0x88A8,
@@ -23,6 +28,7 @@ impl EtherType {
             0x0800 => Some(Ipv4),
             0x86dd => Some(Ipv6),
             0x0806 => Some(Arp),
+            0x8035 => Some(Rarp),
             0x0842 => Some(WakeOnLan),
             0x88A8 => Some(ProviderBridging),
             0x8100 => Some(VlanTaggedFrame),
@@ -40,14 +46,16 @@ pub struct EthernetHeader {
     /// smac is the source MAC address.
     pub source_mac: [u8; 6],
 
+    /// 802.1Q tags, outermost first. Two for QinQ.
+    pub tags: Vec<VlanTag>,
+
     /// Ethertype is a two-octect (2 byte) field, used to indicate the protocol is in the payload
-    /// of the frame and how the layer 2 of the receviing end should process it.
+    /// of the frame and how the layer 2 of the receviing end should process it. For tagged
+    /// frames, the one after the tags.
     pub ethertype: u16,
     // The Frame Check Sequence is a 4-byte CRC that allows deteection of corrupted data within
-    // the entire frame as it is received on the receiver side.
-    // We can compute this with a similar algo to this: https://stackoverflow.com/questions/9286631/ethernet-crc32-calculation-software-vs-algorithmic-result
-    // TODO implement this, we will skip checking this field for now.
-    //frame_check_sequence: u32,
+    // the entire frame as it is received on the receiver side. Most devices strip it, for
+    // those that don't see `check_fcs` and `append_fcs`.
 }
 
 impl EthernetHeader {
@@ -55,7 +63,8 @@ impl EthernetHeader {
         EthernetHeader {
             destination_mac: slice.destination(),
             source_mac: slice.source(),
-            ethertype: slice.ethertype(),
+            tags: slice.tags(),
+            ethertype: slice.payload_ethertype(),
         }
     }
 }
@@ -85,6 +94,26 @@ impl<'a> EthernetFrameSlice<'a> {
         u16::from_be_bytes([self.slice[12], self.slice[13]])
     }
 
+    /// The 802.1Q tags after the MACs, as many as the slice holds.
+    pub fn tags(&self) -> Vec<VlanTag> {
+        let mut tags = vec![];
+        let mut at = 12;
+        while let Some(tag) = self.slice.get(at..at + 4).and_then(VlanTag::from_bytes) {
+            tags.push(tag);
+            at += 4;
+        }
+        tags
+    }
+
+    /// The EtherType of the payload, after any tags.
+    pub fn payload_ethertype(&self) -> u16 {
+        let at = 12 + 4 * self.tags().len();
+        match self.slice.get(at..at + 2) {
+            Some(bytes) => u16::from_be_bytes([bytes[0], bytes[1]]),
+            None => self.ethertype(),
+        }
+    }
+
     pub fn read_from_slice(data: &'a [u8]) -> Self {
         EthernetFrameSlice { slice: data }
     }
@@ -96,12 +125,207 @@ impl<'a> EthernetFrameSlice<'a> {
     }
 }
 
-pub fn nic_init(table: &mut crate::arp::TranslationTable) {
-    table.insert(u32::from_be_bytes([0x0a, 0x0, 0x0, 0x02]), MAC);
-    table.insert(u32::from_be_bytes([0x0a, 0x0, 0x0, 0x04]), MAC);
+/// Frames `payload` for `destination_mac` from `source_mac`, behind the 4 byte
+/// preamble the tap device expects, with `tags` if it goes out on a VLAN.
+pub fn frame(
+    destination_mac: [u8; 6],
+    source_mac: [u8; 6],
+    tags: &[VlanTag],
+    ethertype: EtherType,
+    payload: &[u8],
+) -> Vec<u8> {
+    let header = 18 + 4 * tags.len();
+    let mut buf = vec![0u8; header + payload.len()];
+    buf[4..10].clone_from_slice(&destination_mac);
+    buf[10..16].clone_from_slice(&source_mac);
+    for (i, tag) in tags.iter().enumerate() {
+        buf[16 + 4 * i..20 + 4 * i].clone_from_slice(&tag.to_bytes());
+    }
+    buf[header - 2..header].clone_from_slice(&u16::to_be_bytes(ethertype as u16));
+    buf[header..].clone_from_slice(payload);
+    buf
+}
+
+/// An IEEE 802.1Q tag: the TPID saying a tag follows, then priority, drop
+/// eligibility and VLAN ID packed into the TCI.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct VlanTag {
+    pub tpid: u16,
+
+    /// Priority code point, 0 to 7.
+    pub pcp: u8,
+
+    /// Drop eligible indicator.
+    pub dei: bool,
+
+    /// 1 to 4094, 0 and 4095 are reserved.
+    pub vid: u16,
+}
+
+impl VlanTag {
+    /// A customer tag, the only or inner one.
+    pub fn new(vid: u16) -> Self {
+        VlanTag {
+            tpid: EtherType::VlanTaggedFrame as u16,
+            pcp: 0,
+            dei: false,
+            vid,
+        }
+    }
+
+    /// A service tag, the outer one of QinQ, 802.1ad.
+    pub fn service(vid: u16) -> Self {
+        VlanTag {
+            tpid: EtherType::ProviderBridging as u16,
+            ..VlanTag::new(vid)
+        }
+    }
+
+    /// The tag in `bytes`, None unless they start with a tag's TPID.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let tpid = u16::from_be_bytes([*bytes.first()?, *bytes.get(1)?]);
+        match EtherType::from_u16(tpid) {
+            Some(EtherType::VlanTaggedFrame)
+            | Some(EtherType::ProviderBridging)
+            | Some(EtherType::VlanDoubleTaggedFrame) => {}
+            _ => return None,
+        }
+        let tci = u16::from_be_bytes([*bytes.get(2)?, *bytes.get(3)?]);
+        Some(VlanTag {
+            tpid,
+            pcp: (tci >> 13) as u8,
+            dei: tci & 0x1000 != 0,
+            vid: tci & 0x0FFF,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; 4] {
+        let tci = (self.pcp as u16 & 0x7) << 13 | (self.dei as u16) << 12 | (self.vid & 0x0FFF);
+        let mut bytes = [0u8; 4];
+        bytes[..2].clone_from_slice(&self.tpid.to_be_bytes());
+        bytes[2..].clone_from_slice(&tci.to_be_bytes());
+        bytes
+    }
+}
+
+/// Removes the tags from the `len` byte frame in `buf`, behind the preamble,
+/// returning them and the frame's new length.
+pub fn strip_tags(buf: &mut [u8], len: usize) -> (Vec<VlanTag>, usize) {
+    let tags = EthernetFrameSlice::read_from_slice(&buf[4..len]).tags();
+    let tagged = 4 * tags.len();
+    buf.copy_within(16 + tagged..len, 16);
+    (tags, len - tagged)
+}
+
+/// Tags the `len` byte frame in `buf`, behind the preamble, inserting `tags` after
+/// the MACs. Returns the frame's new length, None if `buf` can't hold it.
+pub fn insert_tags(buf: &mut [u8], len: usize, tags: &[VlanTag]) -> Option<usize> {
+    let tagged = 4 * tags.len();
+    if len + tagged > buf.len() {
+        return None;
+    }
+    buf.copy_within(16..len, 16 + tagged);
+    for (i, tag) in tags.iter().enumerate() {
+        buf[16 + 4 * i..20 + 4 * i].clone_from_slice(&tag.to_bytes());
+    }
+    Some(len + tagged)
+}
+
+/// Parses a MAC written as six colon separated hex bytes, eg. `02:00:00:00:00:01`.
+pub fn parse_mac(s: &str) -> Option<[u8; 6]> {
+    let mut mac = [0u8; 6];
+    let mut bytes = s.split(':');
+    for byte in mac.iter_mut() {
+        let hex = bytes.next()?;
+        if hex.is_empty() || hex.len() > 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(hex, 16).ok()?;
+    }
+    match bytes.next() {
+        None => Some(mac),
+        Some(_) => None,
+    }
+}
+
+/// Writes a MAC as `parse_mac` reads it.
+pub fn format_mac(mac: [u8; 6]) -> String {
+    let bytes: Vec<String> = mac.iter().map(|b| format!("{:02x}", b)).collect();
+    bytes.join(":")
+}
+
+/// The CRC-32 of IEEE 802.3, reflected polynomial 0x04C11DB7, one entry per byte.
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// The CRC-32 Ethernet uses for its frame check sequence.
+pub fn crc32(data: &[u8]) -> u32 {
+    let crc = data.iter().fold(0xFFFF_FFFF, |crc: u32, byte| {
+        CRC32_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    });
+    !crc
+}
+
+/// Frames shorter than this, FCS excluded, are padded before the FCS is added.
+pub const MIN_FRAME: usize = 60;
+
+/// Whether the FCS ending `frame`, a frame without the preamble, is right.
+pub fn check_fcs(frame: &[u8]) -> bool {
+    if frame.len() < 4 + 14 {
+        return false;
+    }
+    let (data, fcs) = frame.split_at(frame.len() - 4);
+    crc32(data).to_le_bytes() == fcs
+}
+
+/// Pads the `len` byte frame in `buf`, behind the preamble, to the minimum size
+/// and appends its FCS. Returns the frame's new length, None if `buf` can't hold it.
+pub fn append_fcs(buf: &mut [u8], len: usize) -> Option<usize> {
+    let padded = len.max(4 + MIN_FRAME);
+    if padded + 4 > buf.len() {
+        return None;
+    }
+    for byte in buf[len..padded].iter_mut() {
+        *byte = 0;
+    }
+    let fcs = crc32(&buf[4..padded]).to_le_bytes();
+    buf[padded..padded + 4].clone_from_slice(&fcs);
+    Some(padded + 4)
+}
+
+#[cfg(test)]
+#[test]
+fn test_fcs() {
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
 
-    table.insert(
-        u32::from_be_bytes([0x7f, 0x0, 0x0, 0x01]),
-        [0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
-    );
+    // A short ARP frame is padded to the minimum, then the FCS follows.
+    let mut buf = frame([0xFF; 6], [0x02; 6], &[], EtherType::Arp, &[0xAB; 28]);
+    let len = buf.len();
+    buf.resize(128, 0xEE);
+    let len = append_fcs(&mut buf, len).unwrap();
+    assert_eq!(len, 4 + MIN_FRAME + 4);
+    assert!(buf[4 + 42..4 + MIN_FRAME].iter().all(|b| *b == 0));
+    assert!(check_fcs(&buf[4..len]));
+    buf[20] ^= 1;
+    assert!(!check_fcs(&buf[4..len]));
+    assert_eq!(append_fcs(&mut buf[..64], 60), None);
 }
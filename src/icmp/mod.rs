@@ -7,6 +7,7 @@
 //
 
 use crate::eth;
+use crate::iface::Interface;
 use std::convert::TryInto;
 #[derive(Clone, Debug, Eq, PartialEq)]
 enum IcmpType {
@@ -101,21 +102,44 @@ impl<'a> IcmpPacketSlice<'a> {
             .expect("couldn't convert data slice into array")
     }
 
-    fn rest_of_packet(&self, len: usize) -> &[u8] {
-        &self.slice[8..len]
-    }
-
     pub fn read_from_slice(data: &'a [u8]) -> Self {
         IcmpPacketSlice { slice: data }
     }
 }
 
+/// Destination Unreachable code for a host we couldn't reach, RFC 792.
+pub const HOST_UNREACHABLE: u8 = 1;
+
+/// A Destination Unreachable message about `original`, an IPv4 packet, carrying
+/// its header and the first 8 bytes of its data as RFC 792 asks.
+pub fn destination_unreachable(code: u8, original: &[u8]) -> Vec<u8> {
+    let header_len = (original[0] & 0x0f) as usize * 4;
+    let quoted = &original[..original.len().min(header_len + 8)];
+
+    let mut buf = vec![0u8; 8 + quoted.len()];
+    buf[0] = IcmpType::DstUnreachable as u8;
+    buf[1] = code;
+    buf[8..].clone_from_slice(quoted);
+    let csum = crate::ipv4::calculate_checksum(&buf);
+    buf[2..4].clone_from_slice(&csum.to_be_bytes());
+    buf
+}
+
+/// The packet a Destination Unreachable message is about, as far as it was quoted.
+pub fn unreachable_original(icmpframe: &[u8]) -> Option<&[u8]> {
+    match IcmpType::from_u8(*icmpframe.first()?)? {
+        IcmpType::DstUnreachable => icmpframe.get(8..),
+        _ => None,
+    }
+}
+
 pub fn read_packet(
+    iface: &Interface,
     etherframe: &eth::EthernetFrameSlice,
     ipframe: &crate::ipv4::Ipv4PacketSlice,
     icmpframe: &[u8],
 ) -> Option<[u8; 1500]> {
-    let packet_slice = &IcmpPacketSlice { slice: &icmpframe };
+    let packet_slice = &IcmpPacketSlice { slice: icmpframe };
     let icmp_data = IcmpPacket::from_slice(packet_slice);
 
     match icmp_data.msg_type {
@@ -128,12 +152,11 @@ pub fn read_packet(
         }
         IcmpType::Echo => {
             // If we have a request, reply!
-            let total_buf_size: usize = 18 + ipframe.slice.len() + icmpframe.len();
             let mut buf = [0u8; 1500];
             let new_dest_mac: [u8; 6] = etherframe.source();
 
             buf[4..10].clone_from_slice(&new_dest_mac);
-            buf[10..16].clone_from_slice(&crate::eth::MAC);
+            buf[10..16].clone_from_slice(&iface.mac());
             buf[16..18].clone_from_slice(&etherframe.slice[12..14]);
             // Ethernet Frame Done
 
@@ -141,8 +164,8 @@ pub fn read_packet(
 
             let new_dest_ip = &ipframe.slice[12..16];
             let new_src_ip = &ipframe.slice[16..20];
-            buf[30..34].clone_from_slice(&new_src_ip);
-            buf[34..38].clone_from_slice(&new_dest_ip);
+            buf[30..34].clone_from_slice(new_src_ip);
+            buf[34..38].clone_from_slice(new_dest_ip);
             // NOTE: Don't need to recalculate anything since we don't change any IPv4 Data..
             buf[38..38 + icmpframe.len()].clone_from_slice(icmpframe);
 
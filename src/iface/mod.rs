@@ -0,0 +1,370 @@
+// The network interface we run on: the tap device's name, our MAC on it, the
+// IPv4 addresses assigned to it with their prefix lengths, and the largest packet
+// it carries. Everything that builds frames or picks one of our addresses takes
+// it from here.
+//
+// On a trunk port each VLAN gets a sub-interface of its own, sharing the device
+// and MAC but with its own addresses, whose frames carry its 802.1Q tags.
+//
+// The interface also decides which frames are for us: those sent to our MAC, to
+// broadcast, or to the multicast MAC of an IPv4 group we joined. In promiscuous
+// mode, for debugging, everything is.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::eth::{self, EtherType, VlanTag};
+
+/// The Ethernet MTU.
+pub const DEFAULT_MTU: usize = 1500;
+
+/// The smallest MTU IPv4 allows, RFC 791.
+pub const MIN_MTU: usize = 68;
+
+/// 224.0.0.1, which every host is a member of, RFC 1112.
+pub const ALL_HOSTS: u32 = 0xe0000001;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InterfaceConfig {
+    /// The tap device to open.
+    pub name: String,
+    pub mac: [u8; 6],
+
+    /// Our addresses as (address, prefix length). The first is the one used when
+    /// no other fits.
+    pub addresses: Vec<(u32, u8)>,
+    pub mtu: usize,
+
+    /// The tags our frames carry, outermost first. Empty for the untagged link.
+    pub vlan: Vec<VlanTag>,
+
+    /// The device hands us frames with their FCS and expects ours to have one.
+    pub fcs: bool,
+
+    /// Accept every frame, whoever it is for.
+    pub promiscuous: bool,
+
+    /// An `arp -f` style file of permanent neighbors, loaded when the link comes up.
+    pub static_neighbors: Option<PathBuf>,
+
+    /// Where to keep a copy of the neighbor table, in the format of /proc/net/arp.
+    pub neighbor_table: Option<PathBuf>,
+}
+
+impl Default for InterfaceConfig {
+    fn default() -> Self {
+        InterfaceConfig {
+            name: "tap0".to_string(),
+            mac: [0xbe, 0xe9, 0x7d, 0x63, 0x31, 0xbc],
+            addresses: vec![(0x0a000002, 24), (0x0a000004, 24)],
+            mtu: DEFAULT_MTU,
+            vlan: vec![],
+            fcs: false,
+            promiscuous: false,
+            static_neighbors: None,
+            neighbor_table: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Interface {
+    name: String,
+    mac: [u8; 6],
+    addresses: Vec<(u32, u8)>,
+    mtu: usize,
+    vlan: Vec<VlanTag>,
+    fcs: bool,
+    promiscuous: bool,
+    static_neighbors: Option<PathBuf>,
+    neighbor_table: Option<PathBuf>,
+
+    /// The IPv4 multicast groups joined, all-hosts always among them.
+    groups: Vec<u32>,
+}
+
+impl Default for Interface {
+    fn default() -> Self {
+        Interface::new(InterfaceConfig::default()).unwrap()
+    }
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, what.to_string())
+}
+
+/// Whether `ip` is in the prefix `network/len`.
+fn in_prefix(ip: u32, (network, len): (u32, u8)) -> bool {
+    let mask = u32::MAX.checked_shl(32 - len as u32).unwrap_or(0);
+    ip & mask == network & mask
+}
+
+/// Whether `ip` is an IPv4 multicast address, 224.0.0.0/4.
+pub fn is_multicast(ip: u32) -> bool {
+    ip >> 28 == 0xe
+}
+
+/// The MAC frames for the multicast group `group` are sent to: 01:00:5e and the
+/// group's low 23 bits, RFC 1112 section 6.4.
+pub fn multicast_mac(group: u32) -> [u8; 6] {
+    let [_, b, c, d] = group.to_be_bytes();
+    [0x01, 0x00, 0x5e, b & 0x7f, c, d]
+}
+
+impl Interface {
+    /// Checks `config` makes sense: a unicast MAC, prefix lengths of at most 32,
+    /// no address twice, an MTU IPv4 can use and at most two valid VLAN tags.
+    pub fn new(config: InterfaceConfig) -> io::Result<Self> {
+        if config.mac[0] & 0x01 != 0 || config.mac == [0; 6] {
+            return Err(invalid("the MAC must be a unicast address"));
+        }
+        if config.mtu < MIN_MTU {
+            return Err(invalid("MTU too small"));
+        }
+        if config.vlan.len() > 2 {
+            return Err(invalid("at most two VLAN tags"));
+        }
+        for tag in config.vlan.iter() {
+            if tag.vid == 0 || tag.vid >= 0xFFF || tag.pcp > 7 {
+                return Err(invalid("VLAN IDs go from 1 to 4094, priorities to 7"));
+            }
+        }
+        let mut iface = Interface {
+            name: config.name,
+            mac: config.mac,
+            addresses: vec![],
+            mtu: config.mtu,
+            vlan: config.vlan,
+            fcs: config.fcs,
+            promiscuous: config.promiscuous,
+            static_neighbors: config.static_neighbors,
+            neighbor_table: config.neighbor_table,
+            groups: vec![ALL_HOSTS],
+        };
+        for (ip, len) in config.addresses {
+            iface.add_address(ip, len)?;
+        }
+        Ok(iface)
+    }
+
+    /// A sub-interface for the VLAN `vlan` on the same device, with `addresses`.
+    /// Its neighbors are its own, neither loaded nor exported.
+    pub fn sub_interface(
+        &self,
+        vlan: Vec<VlanTag>,
+        addresses: Vec<(u32, u8)>,
+    ) -> io::Result<Interface> {
+        Interface::new(InterfaceConfig {
+            name: self.name.clone(),
+            mac: self.mac,
+            addresses,
+            mtu: self.mtu,
+            vlan,
+            fcs: self.fcs,
+            promiscuous: self.promiscuous,
+            static_neighbors: None,
+            neighbor_table: None,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn vlan(&self) -> &[VlanTag] {
+        &self.vlan
+    }
+
+    /// Whether frames with `tags` are ours, going by their VLAN IDs.
+    pub fn is_vlan(&self, tags: &[VlanTag]) -> bool {
+        self.vlan.len() == tags.len() && self.vlan.iter().zip(tags).all(|(a, b)| a.vid == b.vid)
+    }
+
+    /// Whether frames on the device carry an FCS.
+    pub fn fcs(&self) -> bool {
+        self.fcs
+    }
+
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    pub fn set_mtu(&mut self, mtu: usize) -> io::Result<()> {
+        if mtu < MIN_MTU {
+            return Err(invalid("MTU too small"));
+        }
+        self.mtu = mtu;
+        Ok(())
+    }
+
+    pub fn addresses(&self) -> &[(u32, u8)] {
+        &self.addresses
+    }
+
+    pub fn add_address(&mut self, ip: u32, len: u8) -> io::Result<()> {
+        if len > 32 {
+            return Err(invalid("prefix length over 32"));
+        }
+        if self.has_address(ip) {
+            return Err(io::Error::from(io::ErrorKind::AddrInUse));
+        }
+        self.addresses.push((ip, len));
+        Ok(())
+    }
+
+    pub fn remove_address(&mut self, ip: u32) -> bool {
+        let before = self.addresses.len();
+        self.addresses.retain(|(address, _)| *address != ip);
+        self.addresses.len() != before
+    }
+
+    /// Whether `ip` is one of ours.
+    pub fn has_address(&self, ip: u32) -> bool {
+        self.addresses.iter().any(|(address, _)| *address == ip)
+    }
+
+    /// Whether `ip` is on the link, in the prefix of one of our addresses.
+    pub fn is_on_link(&self, ip: u32) -> bool {
+        self.addresses.iter().any(|&prefix| in_prefix(ip, prefix))
+    }
+
+    /// The address to send to `destination` from: one in the same prefix if we
+    /// have one, otherwise the first. None without any address.
+    pub fn source_for(&self, destination: u32) -> Option<u32> {
+        self.addresses
+            .iter()
+            .find(|&&prefix| in_prefix(destination, prefix))
+            .or_else(|| self.addresses.first())
+            .map(|(address, _)| *address)
+    }
+
+    pub fn static_neighbors(&self) -> Option<&Path> {
+        self.static_neighbors.as_deref()
+    }
+
+    pub fn neighbor_table(&self) -> Option<&Path> {
+        self.neighbor_table.as_deref()
+    }
+
+    pub fn promiscuous(&self) -> bool {
+        self.promiscuous
+    }
+
+    pub fn set_promiscuous(&mut self, promiscuous: bool) {
+        self.promiscuous = promiscuous;
+    }
+
+    pub fn multicast_groups(&self) -> &[u32] {
+        &self.groups
+    }
+
+    /// Joins the multicast group `group`, so frames sent to it are accepted.
+    pub fn join_multicast(&mut self, group: u32) -> io::Result<()> {
+        if !is_multicast(group) {
+            return Err(invalid("not a multicast address"));
+        }
+        if !self.groups.contains(&group) {
+            self.groups.push(group);
+        }
+        Ok(())
+    }
+
+    /// Leaves the multicast group `group`, returning whether we were in it.
+    /// All-hosts can't be left.
+    pub fn leave_multicast(&mut self, group: u32) -> bool {
+        let before = self.groups.len();
+        self.groups
+            .retain(|joined| *joined != group || *joined == ALL_HOSTS);
+        self.groups.len() != before
+    }
+
+    /// Whether a frame sent to `destination_mac` is for us.
+    pub fn accepts(&self, destination_mac: [u8; 6]) -> bool {
+        if self.promiscuous || destination_mac == self.mac || destination_mac == [0xFF; 6] {
+            return true;
+        }
+        // Several groups share a MAC, IP drops what isn't for a group we joined.
+        destination_mac[0] & 0x01 != 0
+            && self
+                .groups
+                .iter()
+                .any(|group| multicast_mac(*group) == destination_mac)
+    }
+
+    /// Frames `payload` for `destination_mac` from our MAC, tagged for our VLAN if
+    /// we are on one and with an FCS if the device wants it, see `eth::frame`.
+    pub fn frame(&self, destination_mac: [u8; 6], ethertype: EtherType, payload: &[u8]) -> Vec<u8> {
+        let mut frame = eth::frame(destination_mac, self.mac, &self.vlan, ethertype, payload);
+        if self.fcs {
+            let len = frame.len();
+            frame.resize(len.max(4 + eth::MIN_FRAME) + 4, 0);
+            let _ = eth::append_fcs(&mut frame, len);
+        }
+        frame
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_interface() {
+    let mut iface = Interface::new(InterfaceConfig {
+        addresses: vec![(0x0a000002, 24), (0xc0a80102, 16)],
+        ..InterfaceConfig::default()
+    })
+    .unwrap();
+    assert!(iface.has_address(0xc0a80102));
+    assert!(iface.is_on_link(0xc0a8ff01) && !iface.is_on_link(0x0a000102));
+    assert_eq!(iface.source_for(0xc0a80001), Some(0xc0a80102));
+    assert_eq!(iface.source_for(0x08080808), Some(0x0a000002));
+    assert!(iface.add_address(0x0a000002, 24).is_err());
+    assert!(iface.add_address(0x0a000003, 33).is_err());
+    assert!(iface.set_mtu(60).is_err());
+
+    let frame = iface.frame([0xFF; 6], EtherType::Arp, &[1, 2]);
+    assert_eq!(frame[10..16], iface.mac());
+    assert_eq!(frame[16..], [0x08, 0x06, 1, 2]);
+
+    assert!(iface.remove_address(0x0a000002));
+    assert_eq!(iface.source_for(0x0a000001), Some(0xc0a80102));
+    let multicast = InterfaceConfig {
+        mac: [0x01, 0, 0x5e, 0, 0, 1],
+        ..InterfaceConfig::default()
+    };
+    assert!(Interface::new(multicast).is_err());
+
+    // A QinQ sub-interface tags its frames, outer tag first.
+    let vlan = vec![VlanTag::service(100), VlanTag::new(20)];
+    let sub = iface.sub_interface(vlan, vec![(0xac100002, 24)]).unwrap();
+    assert!(sub.is_vlan(&[VlanTag::new(100), VlanTag::new(20)]) && !iface.is_vlan(sub.vlan()));
+    let frame = sub.frame([0xFF; 6], EtherType::Arp, &[1, 2]);
+    assert_eq!(
+        frame[16..],
+        [0x88, 0xA8, 0, 100, 0x81, 0, 0, 20, 0x08, 0x06, 1, 2]
+    );
+    assert!(iface
+        .sub_interface(vec![VlanTag::new(4095)], vec![])
+        .is_err());
+
+    // Our MAC, broadcast and joined groups only, unless promiscuous.
+    let group = 0xe00000fb;
+    assert_eq!(
+        multicast_mac(0xefff0001),
+        [0x01, 0x00, 0x5e, 0x7f, 0x00, 0x01]
+    );
+    assert!(iface.accepts(iface.mac()) && iface.accepts([0xFF; 6]));
+    assert!(iface.accepts(multicast_mac(ALL_HOSTS)));
+    assert!(!iface.accepts(multicast_mac(group)));
+    iface.join_multicast(group).unwrap();
+    assert!(iface.accepts(multicast_mac(group)));
+    assert!(iface.join_multicast(0x0a000001).is_err());
+    assert!(iface.leave_multicast(group) && !iface.leave_multicast(ALL_HOSTS));
+    assert!(!iface.accepts(multicast_mac(group)));
+    let other = [0x02, 0, 0, 0, 0, 0x01];
+    assert!(!iface.accepts(other));
+    iface.set_promiscuous(true);
+    assert!(iface.accepts(other));
+}
@@ -1,6 +1,4 @@
-use std::collections::HashMap;
 use std::convert::TryInto;
-use tun_tap::Iface;
 
 /// what protocol?
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -136,7 +134,7 @@ pub struct Ipv4PacketSlice<'a> {
 impl<'a> Ipv4PacketSlice<'a> {
     /// Grabs the first half of the first byte of the IPv4 Header.
     pub fn version(&self) -> u8 {
-        return 0xF0 & self.slice[0];
+        0xF0 & self.slice[0]
     }
 
     /// Grabs the second half of the first byte of the IPv4 Header.
@@ -220,7 +218,35 @@ pub fn calculate_checksum(header: &[u8]) -> u16 {
         sum += header[i - 1] as u32;
     }
 
-    sum = (sum & 0xffff) + (sum >> 16);
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !sum as u16
+}
+
+/// The checksum TCP and UDP use, over `data` behind the IPv4 pseudo-header of
+/// addresses, protocol and length. Checking received data, checksum field
+/// included, gives 0.
+pub fn pseudo_header_checksum(
+    source_ip: u32,
+    dest_ip: u32,
+    protocol: ProtoType,
+    data: &[u8],
+) -> u16 {
+    let mut sum: u32 = (source_ip >> 16) + (source_ip & 0xffff);
+    sum += (dest_ip >> 16) + (dest_ip & 0xffff);
+    sum += ProtoType::to_u8(&Some(protocol)) as u32;
+    sum += data.len() as u32;
+
+    for chunk in data.chunks(2) {
+        sum += u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]) as u32;
+        if sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
     !sum as u16
 }
 
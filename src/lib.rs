@@ -3,6 +3,9 @@ extern crate tun_tap;
 pub mod arp;
 pub mod eth;
 pub mod icmp;
+pub mod iface;
 pub mod ipv4;
 pub mod pkt;
+pub mod ports;
 pub mod tcp;
+pub mod wol;
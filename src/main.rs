@@ -1,27 +1,90 @@
-use pct::pkt;
+use pct::iface::{Interface, InterfaceConfig};
+use pct::pkt::{self, Link};
+use std::env;
 use std::io;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
 
 fn main() -> io::Result<()> {
-    let nic = tun_tap::Iface::new("tap0", tun_tap::Mode::Tap)?;
+    let iface = Interface::new(InterfaceConfig {
+        // Permanent neighbors to start with, as `arp -f` reads them, and where to
+        // keep a copy of the neighbor table, like /proc/net/arp.
+        static_neighbors: env::var_os("PCT_ETHERS").map(PathBuf::from),
+        neighbor_table: env::var_os("PCT_ARP_TABLE").map(PathBuf::from),
+        ..InterfaceConfig::default()
+    })?;
+    let nic = tun_tap::Iface::new(iface.name(), tun_tap::Mode::Tap)?;
+    // Non blocking so the TCP timers still run while the link is quiet.
+    nic.set_non_blocking()?;
 
-    let _nic_ip = 0x0a000002_u32;
-
-    // At the moment the IP has to be hardcoded, to implement automatically at some point.
-
-    let mut table = pct::arp::TranslationTable::new();
-    pct::eth::nic_init(&mut table);
-    let mut buf = [0u8; 1522];
+    // Sub-interfaces for VLANs on a trunk port are links of their own, eg.
+    // `iface.sub_interface(vec![VlanTag::new(10)], vec![(0x0a0a0002, 24)])`.
+    let mut links = vec![Link::new(iface, Instant::now())];
+    for link in links.iter_mut() {
+        let loaded = link.load_static_neighbors(Instant::now())?;
+        if loaded > 0 {
+            println!("[ARP] loaded {} static neighbors", loaded);
+        }
+    }
+    let mut exported_at = Instant::now();
+    let mut connections = pct::tcp::Connections::new();
+    // Room for the largest frame any link takes: the tun preamble, the Ethernet
+    // header, two VLAN tags, an MTU's worth of payload and the FCS.
+    let mtu = links.iter().map(|link| link.iface.mtu()).max().unwrap_or(0);
+    let mut buf = vec![0u8; 4 + 14 + 2 * 4 + mtu + 4];
 
     loop {
-        let _data_len = nic.recv(&mut buf)?;
-        let pkt = pkt::read_and_reply(&mut buf, _data_len, &mut table);
-        if pkt.0 {
-            match nic.send(&buf[..pkt.1]) {
-                Ok(x) => {
-                    println!("Sent data of len {}", x);
+        match nic.recv(&mut buf) {
+            Ok(_data_len) => {
+                let pkt = pkt::dispatch(&mut buf, _data_len, &mut links, &mut connections);
+                if pkt.0 {
+                    match nic.send(&buf[..pkt.1]) {
+                        Ok(x) => {
+                            println!("Sent data of len {}", x);
+                        }
+                        Err(e) => {
+                            println!("Error: {:?} in sending data {:X?}", e, buf);
+                        }
+                    }
                 }
-                Err(e) => {
-                    println!("Error: {:?} in sending data {:X?}", e, buf);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(1));
+            }
+            Err(e) => return Err(e),
+        }
+
+        let now = Instant::now();
+        connections.on_tick(now);
+        while let Some(segment) = connections.poll_transmit() {
+            if let Some(link) = pkt::link_for(&mut links, segment.quad.local.0) {
+                pkt::send_segment(&segment, &link.iface, &mut link.arp, now);
+            }
+        }
+        for link in links.iter_mut() {
+            link.on_tick(now);
+            while let Some(packet) = link.arp.poll_unreachable() {
+                pkt::deliver_icmp_error(&packet, &mut connections);
+            }
+            while let Some(frame) = link
+                .arp
+                .poll_transmit()
+                .or_else(|| link.wol.poll_transmit())
+            {
+                if let Err(e) = nic.send(&frame) {
+                    println!("Error: {:?} in sending frame {:X?}", e, frame);
+                }
+            }
+            while let Some(event) = link.wol.poll_event() {
+                println!("[WOL] wake up requested by {:X?}", event.source);
+            }
+        }
+        if now.saturating_duration_since(exported_at) >= Duration::from_secs(1) {
+            exported_at = now;
+            for link in links.iter() {
+                if let Err(e) = link.export_neighbors() {
+                    println!("[ARP] couldn't export the neighbor table: {}", e);
                 }
             }
         }
@@ -3,18 +3,22 @@
 
 use crate::arp;
 use crate::eth;
+use crate::iface::Interface;
 use crate::ipv4;
 use crate::tcp;
+use crate::wol;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::time::Instant;
 
-pub fn build_eth(eth_frame: &eth::EthernetFrameSlice, flip: bool) -> [u8; 18] {
+pub fn build_eth(iface: &Interface, eth_frame: &eth::EthernetFrameSlice, flip: bool) -> [u8; 18] {
     let mut ret_pkt = [0u8; 18];
     if flip {
         // Note we alloc 4 bytes as preamble.
 
         // Store destination mac in buffer as we are
         // overwriting with old source address.
-        // HARDCODED MAC
-        let new_src_mac = [0xbe, 0xe9, 0x7d, 0x63, 0x31, 0xbc];
+        let new_src_mac = iface.mac();
         let new_dest_mac: [u8; 6] = eth_frame.source();
         let proto = eth_frame.ethertype();
 
@@ -47,23 +51,266 @@ pub fn build_ip(ip_frame: &ipv4::Ipv4PacketSlice, flip: bool) -> [u8; 20] {
         ret_pkt[10..12].clone_from_slice(&[0, 0]);
         ret_pkt[12..16].clone_from_slice(&u32::to_be_bytes(new_src_ip));
         ret_pkt[16..20].clone_from_slice(&u32::to_be_bytes(new_dest_ip));
-        let csum = ipv4::calculate_checksum(&mut ret_pkt);
+        let csum = ipv4::calculate_checksum(&ret_pkt);
         ret_pkt[10..12].clone_from_slice(&u16::to_be_bytes(csum));
-        ret_pkt
+    }
+    ret_pkt
+}
+
+/// Builds a fresh IPv4 header, for packets that are not a reply to one we received.
+pub fn build_ipv4_header(
+    source_ip: u32,
+    dest_ip: u32,
+    protocol: ipv4::ProtoType,
+    payload_len: usize,
+) -> [u8; 20] {
+    let mut ret_pkt = [0u8; 20];
+    // version 4, 5 word header.
+    ret_pkt[0] = 0x45;
+    ret_pkt[2..4].clone_from_slice(&u16::to_be_bytes(20 + payload_len as u16));
+    // Don't Fragment
+    ret_pkt[6] = 0x40;
+    ret_pkt[8] = 64;
+    ret_pkt[9] = ipv4::ProtoType::to_u8(&Some(protocol));
+    ret_pkt[12..16].clone_from_slice(&u32::to_be_bytes(source_ip));
+    ret_pkt[16..20].clone_from_slice(&u32::to_be_bytes(dest_ip));
+    let csum = ipv4::calculate_checksum(&ret_pkt);
+    ret_pkt[10..12].clone_from_slice(&u16::to_be_bytes(csum));
+    ret_pkt
+}
+
+/// Sets the total length of an IPv4 header, recalculating its checksum.
+fn set_ip_total_len(ip_header: &mut [u8], total_len: usize) {
+    ip_header[2..4].clone_from_slice(&u16::to_be_bytes(total_len as u16));
+    ip_header[10..12].clone_from_slice(&[0, 0]);
+    let csum = ipv4::calculate_checksum(&ip_header[..20]);
+    ip_header[10..12].clone_from_slice(&u16::to_be_bytes(csum));
+}
+
+/// Hands a segment the TCP layer wants sent to ARP, which frames it once the
+/// peer's MAC is known, see `arp::Resolver`.
+pub fn send_segment(segment: &tcp::Segment, iface: &Interface, arp: &mut arp::Arp, now: Instant) {
+    let mut packet = build_ipv4_header(
+        segment.quad.local.0,
+        segment.quad.remote.0,
+        ipv4::ProtoType::TCP,
+        segment.data.len(),
+    )
+    .to_vec();
+    packet.extend_from_slice(&segment.data);
+    arp.send(iface, packet, now);
+}
+
+/// Delivers an ICMP error we produced ourselves, such as ARP's host unreachable,
+/// to the transport the packet it is about came from.
+pub fn deliver_icmp_error(packet: &[u8], connections: &mut tcp::Connections) {
+    let header_len = (packet[0] & 0x0f) as usize * 4;
+    let original = match crate::icmp::unreachable_original(&packet[header_len..]) {
+        Some(original) if original.len() >= 24 => original,
+        _ => return,
+    };
+    let original_len = (original[0] & 0x0f) as usize * 4;
+    let protocol = ipv4::ProtoType::from_u8(original[9]);
+    match (protocol, original.get(original_len..original_len + 4)) {
+        (Some(ipv4::ProtoType::TCP), Some(ports)) => {
+            let quad = tcp::Quad {
+                local: (
+                    u32::from_be_bytes([original[12], original[13], original[14], original[15]]),
+                    u16::from_be_bytes([ports[0], ports[1]]),
+                ),
+                remote: (
+                    u32::from_be_bytes([original[16], original[17], original[18], original[19]]),
+                    u16::from_be_bytes([ports[2], ports[3]]),
+                ),
+            };
+            connections.on_host_unreachable(&quad);
+        }
+        _ => println!("[ICMP] host unreachable for {:X?}", &original[16..20]),
+    }
+}
+
+/// An interface and the ARP and Wake-on-LAN state that go with it. Each VLAN
+/// sub-interface is a link of its own, with its own neighbors and address claims.
+pub struct Link {
+    pub iface: Interface,
+    pub arp: arp::Arp,
+    pub wol: wol::Wol,
+}
+
+impl Link {
+    /// A link on `iface`, starting to claim its addresses.
+    pub fn new(iface: Interface, now: Instant) -> Self {
+        let mut arp = arp::Arp::new();
+        for (ip, _) in iface.addresses() {
+            arp.acd.add(*ip, now);
+        }
+        Link {
+            iface,
+            arp,
+            wol: wol::Wol::new(),
+        }
+    }
+
+    /// Loads the interface's static neighbors file, if it has one, returning how
+    /// many entries it had. See `NeighborCache::load_static`.
+    pub fn load_static_neighbors(&mut self, now: Instant) -> io::Result<usize> {
+        match self.iface.static_neighbors() {
+            Some(path) => {
+                let file = BufReader::new(File::open(path)?);
+                self.arp.neighbors.load_static(file, now)
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// Rewrites the interface's neighbor table file, if it has one. The table is
+    /// written beside it first, so readers never see half of one.
+    pub fn export_neighbors(&self) -> io::Result<()> {
+        let path = match self.iface.neighbor_table() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let partial = path.with_extension("partial");
+        let mut out = BufWriter::new(File::create(&partial)?);
+        self.arp.neighbors.export(self.iface.name(), &mut out)?;
+        out.flush()?;
+        fs::rename(&partial, path)
+    }
+
+    /// Runs the link's ARP timers, then takes on any address RARP got us, claiming
+    /// it through ACD like the configured ones.
+    pub fn on_tick(&mut self, now: Instant) {
+        self.arp.on_tick(&self.iface, now);
+        if let Some((ip, server)) = self.arp.rarp.poll_assigned() {
+            // RARP gives no netmask, the address's class has to do.
+            let len = match ip >> 29 {
+                0..=3 => 8,
+                4 | 5 => 16,
+                _ => 24,
+            };
+            match self.iface.add_address(ip, len) {
+                Ok(()) => self.arp.acd.add(ip, now),
+                Err(e) => println!("[RARP] can't use {:X?} from {:X?}: {}", ip, server, e),
+            }
+        }
+    }
+}
+
+/// The link to send from `source_ip` on, the first if none has the address.
+pub fn link_for(links: &mut [Link], source_ip: u32) -> Option<&mut Link> {
+    let index = links
+        .iter()
+        .position(|link| link.iface.has_address(source_ip))
+        .unwrap_or(0);
+    links.get_mut(index)
+}
+
+/// Handles a received frame on whichever link its VLAN tags say it came in on,
+/// untagged frames on the untagged one. The tags are stripped before the frame
+/// is handled and put back on the reply, so everything else sees plain frames.
+/// Likewise the FCS, if the device keeps it, which all links share, and the
+/// LLC/SNAP header of 802.3 frames carrying IP or ARP.
+pub fn dispatch(
+    buf: &mut [u8],
+    buf_len: usize,
+    links: &mut [Link],
+    connections: &mut tcp::Connections,
+) -> (bool, usize) {
+    // Frames for others, flooded to us by a switch, are dropped before any work.
+    if buf_len < 18 {
+        return (false, 0);
+    }
+    let mut destination = [0u8; 6];
+    destination.clone_from_slice(&buf[4..10]);
+    if !links.iter().any(|link| link.iface.accepts(destination)) {
+        return (false, 0);
+    }
+    let fcs = links.first().map(|link| link.iface.fcs()).unwrap_or(false);
+    let buf_len = match fcs {
+        true if eth::check_fcs(&buf[4..buf_len]) => buf_len - 4,
+        true => {
+            println!("[ETH] bad FCS, dropping frame");
+            return (false, 0);
+        }
+        false => buf_len,
+    };
+    if buf_len < 18 {
+        return (false, 0);
+    }
+    let (tags, len) = eth::strip_tags(buf, buf_len);
+    // 802.3 frames only get further if they carry SNAP encapsulated IPv4 or ARP.
+    // The rest, bridges' STP and the like, is dropped quietly, as a switch sends
+    // it every few seconds.
+    let (llc, len) = if eth::is_length(u16::from_be_bytes([buf[16], buf[17]])) {
+        let carries_ip = |llc: &eth::LlcHeader| match llc.protocol() {
+            eth::LlcProtocol::EtherType(ethertype) => {
+                ethertype == eth::EtherType::Ipv4 as u16 || ethertype == eth::EtherType::Arp as u16
+            }
+            _ => false,
+        };
+        match eth::strip_llc(buf, len) {
+            Some((llc, len)) if carries_ip(&llc) => (Some(llc), len),
+            _ => return (false, 0),
+        }
     } else {
-        return ret_pkt;
+        (None, len)
+    };
+    let link = match links.iter_mut().find(|link| link.iface.is_vlan(&tags)) {
+        Some(link) => link,
+        None => {
+            let vids: Vec<u16> = tags.iter().map(|tag| tag.vid).collect();
+            println!("[ETH] no interface on VLAN {:?}", vids);
+            return (false, 0);
+        }
+    };
+    match read_and_reply(
+        buf,
+        len,
+        &link.iface,
+        &mut link.arp,
+        &mut link.wol,
+        connections,
+    ) {
+        (true, reply_len) => {
+            let reply_len = match llc {
+                Some(llc) => match eth::insert_llc(buf, reply_len, &llc) {
+                    Some(len) => len,
+                    None => return (false, 0),
+                },
+                None => reply_len,
+            };
+            let tagged = eth::insert_tags(buf, reply_len, link.iface.vlan());
+            match tagged.and_then(|len| {
+                if fcs {
+                    eth::append_fcs(buf, len)
+                } else {
+                    Some(len)
+                }
+            }) {
+                Some(reply_len) => (true, reply_len),
+                None => (false, 0),
+            }
+        }
+        none => none,
     }
 }
 
 pub fn read_and_reply(
     buf: &mut [u8],
     buf_len: usize,
-    table: &mut crate::arp::TranslationTable,
+    iface: &Interface,
+    arp: &mut arp::Arp,
+    wol: &mut wol::Wol,
+    connections: &mut tcp::Connections,
 ) -> (bool, usize) {
     let mut frame_buf = [0u8; 18];
     frame_buf.clone_from_slice(&buf[4..22]);
     let frame = eth::EthernetFrameSlice::read_from_slice(&frame_buf);
     let header = eth::EthernetHeader::from_header_slice(&frame);
+    // `dispatch` let it through for some link, a multicast group may be another's.
+    if !iface.accepts(header.destination_mac) {
+        return (false, 0);
+    }
     let proto = eth::EtherType::from_u16(header.ethertype);
 
     let mut buf_cnt = 0;
@@ -71,80 +318,102 @@ pub fn read_and_reply(
         Some(x) => {
             if x == &eth::EtherType::Arp {
                 assert!(buf_cnt == 0);
-                let eth_reply_frame = build_eth(&frame, true);
+                let eth_reply_frame = build_eth(iface, &frame, true);
                 buf_cnt += 18;
-                let arp_pkt = arp::read_packet(&buf[18..], &frame, table);
-                if arp_pkt == None {
-                    return (false, 0);
-                } else {
-                    let pkt_len = arp_pkt.unwrap().len();
-                    buf[..buf_cnt].clone_from_slice(&eth_reply_frame);
-                    buf[buf_cnt..buf_cnt + pkt_len].clone_from_slice(&arp_pkt.unwrap());
-                    return (true, buf_cnt + pkt_len);
+                match arp::read_packet(iface, &buf[18..], &frame, arp, Instant::now()) {
+                    None => return (false, 0),
+                    Some(arp_pkt) => {
+                        let pkt_len = arp_pkt.len();
+                        buf[..buf_cnt].clone_from_slice(&eth_reply_frame);
+                        buf[buf_cnt..buf_cnt + pkt_len].clone_from_slice(&arp_pkt);
+                        return (true, buf_cnt + pkt_len);
+                    }
                 }
+            } else if x == &eth::EtherType::Rarp {
+                let rarp_pkt = match arp.rarp.read_packet(iface, &buf[18..]) {
+                    None => return (false, 0),
+                    Some(rarp_pkt) => rarp_pkt,
+                };
+                buf[..18].clone_from_slice(&build_eth(iface, &frame, true));
+                buf[18..18 + rarp_pkt.len()].clone_from_slice(&rarp_pkt);
+                return (true, 18 + rarp_pkt.len());
+            } else if x == &eth::EtherType::WakeOnLan {
+                wol.read_frame(iface, frame.source(), &buf[18..buf_len]);
             } else if x == &eth::EtherType::Ipv4 {
-                match ipv4::read_packet(&buf[18..38]) {
-                    Some(x) => {
-                        assert!(buf_cnt == 0);
-
-                        let eth_reply_frame = build_eth(&frame, true);
-                        buf[..18].clone_from_slice(&eth_reply_frame);
-                        buf_cnt += 18;
-
-                        let mut ip_buf = [0u8; 20];
-                        ip_buf.clone_from_slice(&buf[18..38]);
-                        let ip_slice = ipv4::Ipv4PacketSlice { slice: &ip_buf };
-
-                        let ip_reply_frame = build_ip(&ip_slice, true);
-                        buf[buf_cnt..buf_cnt + ip_reply_frame.len()]
-                            .clone_from_slice(&ip_reply_frame);
-                        buf_cnt += ip_reply_frame.len();
-
-                        use ipv4::ProtoType::*;
-                        match x {
-                            ICMP => {
-                                println!("[ICMP] processing...");
-                                let icmp_pkt = crate::icmp::read_packet(
-                                    &frame,
-                                    &ip_slice,
-                                    &buf[buf_cnt..buf_cnt + buf_len],
-                                );
-                                if icmp_pkt == None {
-                                    return (false, 0);
-                                } else {
-                                    let pkt_len = icmp_pkt.unwrap().len();
-                                    buf[buf_cnt..buf_cnt + pkt_len]
-                                        .clone_from_slice(&icmp_pkt.unwrap());
+                if let Some(x) = ipv4::read_packet(&buf[18..38]) {
+                    assert!(buf_cnt == 0);
+
+                    let eth_reply_frame = build_eth(iface, &frame, true);
+                    buf[..18].clone_from_slice(&eth_reply_frame);
+                    buf_cnt += 18;
+
+                    let mut ip_buf = [0u8; 20];
+                    ip_buf.clone_from_slice(&buf[18..38]);
+                    let ip_slice = ipv4::Ipv4PacketSlice { slice: &ip_buf };
+
+                    let ip_reply_frame = build_ip(&ip_slice, true);
+                    buf[buf_cnt..buf_cnt + ip_reply_frame.len()].clone_from_slice(&ip_reply_frame);
+                    buf_cnt += ip_reply_frame.len();
+
+                    use ipv4::ProtoType::*;
+                    match x {
+                        ICMP => {
+                            println!("[ICMP] processing...");
+                            let message = match buf.get(buf_cnt..buf_len) {
+                                Some(message) if message.len() >= 8 => message,
+                                _ => return (false, 0),
+                            };
+                            match crate::icmp::read_packet(iface, &frame, &ip_slice, message) {
+                                None => return (false, 0),
+                                Some(icmp_pkt) => {
+                                    let pkt_len = icmp_pkt.len();
+                                    buf[buf_cnt..buf_cnt + pkt_len].clone_from_slice(&icmp_pkt);
                                     buf_cnt += pkt_len;
                                     return (true, buf_cnt);
                                 }
                             }
-                            UDP => {
+                        }
+                        UDP => {
+                            let datagram = match buf.get(buf_cnt..buf_len) {
+                                Some(datagram) => datagram,
+                                None => return (false, 0),
+                            };
+                            if datagram.len() >= 4
+                                && u16::from_be_bytes([datagram[2], datagram[3]]) == wol::UDP_PORT
+                            {
+                                let (source_ip, dest_ip) =
+                                    (ip_slice.source_ip(), ip_slice.destination_ip());
+                                wol.read_udp(iface, source_ip, dest_ip, datagram);
+                            } else {
                                 println!("[UDP] nop");
-                                return (false, 0);
                             }
-                            TCP => {
-                                println!("[TCP] processing...");
-                                let tcp_pkt = tcp::read_packet(
-                                    &buf[buf_cnt..buf_cnt + buf_len],
-                                    &ipv4::IPv4Packet::from_slice(ip_slice),
-                                );
-                                println!("LINE: {}", line!());
-                                if tcp_pkt == None {
-                                    return (false, 0);
-                                } else {
-                                    let pkt = tcp_pkt.unwrap();
-                                    println!("buf cnt: {}", buf_cnt + pkt.1);
+                            return (false, 0);
+                        }
+                        TCP => {
+                            println!("[TCP] processing...");
+                            // Runt frames end before the segment starts.
+                            let segment = match buf.get(buf_cnt..buf_len) {
+                                Some(segment) => segment,
+                                None => return (false, 0),
+                            };
+                            let tcp_pkt = tcp::read_packet(
+                                segment,
+                                &ipv4::IPv4Packet::from_slice(ip_slice),
+                                connections,
+                            );
+                            match tcp_pkt {
+                                None => return (false, 0),
+                                Some(pkt) => {
                                     buf[buf_cnt..buf_cnt + pkt.1].clone_from_slice(&pkt.0[..pkt.1]);
+                                    set_ip_total_len(&mut buf[18..38], 20 + pkt.1);
                                     return (true, buf_cnt + pkt.1);
                                 }
                             }
-                            IGMP => {
-                                println!("[IGMP] nop");
-                            }
+                        }
+                        IGMP => {
+                            println!("[IGMP] nop");
                         }
                     }
-                    None => {}
                 }
             }
         }
@@ -153,5 +422,183 @@ pub fn read_and_reply(
             println!("Bad Protocol. Received Packets: 0x{:X?}", header.ethertype);
         }
     }
-    return (false, 0);
+    (false, 0)
+}
+
+#[cfg(test)]
+#[test]
+fn test_dispatch_vlan() {
+    use crate::eth::{EtherType, VlanTag};
+
+    let now = Instant::now();
+    let base = Interface::default();
+    let sub = base
+        .sub_interface(vec![VlanTag::new(10)], vec![(0x0a0a0002, 24)])
+        .unwrap();
+    let mut links = vec![Link::new(base, now), Link::new(sub, now)];
+    links[1].arp.acd.add_unchecked(0x0a0a0002);
+    let mut connections = tcp::Connections::new();
+
+    // A request tagged for VLAN 10, with a priority, is answered on it.
+    let peer = [0x02, 0, 0, 0, 0, 0x01];
+    let request = arp::ArpPacket::request(peer, 0x0a0a0001, 0x0a0a0002).to_bytes();
+    let tag = VlanTag {
+        pcp: 5,
+        ..VlanTag::new(10)
+    };
+    let frame = eth::frame([0xFF; 6], peer, &[tag], EtherType::Arp, &request);
+    let mut buf = [0u8; 1522];
+    buf[..frame.len()].clone_from_slice(&frame);
+    let (reply, len) = dispatch(&mut buf, frame.len(), &mut links, &mut connections);
+    assert!(reply);
+    let slice = eth::EthernetFrameSlice::read_from_slice(&buf[4..len]);
+    assert_eq!(slice.tags(), vec![VlanTag::new(10)]);
+    assert_eq!(slice.payload_ethertype(), EtherType::Arp as u16);
+    let answer = arp::ArpPacket::reply(
+        &arp::ArpPacket::request(peer, 0x0a0a0001, 0x0a0a0002),
+        links[1].iface.mac(),
+    );
+    assert_eq!(buf[22..len], answer.to_bytes());
+    assert!(links[1].arp.neighbors.contains(0x0a0a0001));
+
+    // Other VLANs, and QinQ frames with the right inner tag only, aren't ours.
+    let qinq = [VlanTag::service(20), VlanTag::new(10)];
+    let frame = eth::frame([0xFF; 6], peer, &qinq, EtherType::Arp, &request);
+    buf[..frame.len()].clone_from_slice(&frame);
+    assert!(!dispatch(&mut buf, frame.len(), &mut links, &mut connections).0);
+}
+
+#[cfg(test)]
+#[test]
+fn test_dispatch_filter() {
+    use crate::eth::EtherType;
+
+    let now = Instant::now();
+    let mut links = vec![Link::new(Interface::default(), now)];
+    links[0].arp.acd.add_unchecked(0x0a000002);
+    let mut connections = tcp::Connections::new();
+    let peer = [0x02, 0, 0, 0, 0, 0x01];
+    let request = arp::ArpPacket::request(peer, 0x0a000001, 0x0a000002).to_bytes();
+    let mut buf = [0u8; 1522];
+    let mut deliver = |links: &mut Vec<Link>, destination: [u8; 6]| {
+        let frame = eth::frame(destination, peer, &[], EtherType::Arp, &request);
+        buf[..frame.len()].clone_from_slice(&frame);
+        dispatch(&mut buf, frame.len(), links, &mut connections).0
+    };
+
+    // Our MAC, broadcast and the groups we joined only, unless promiscuous.
+    let group = crate::iface::multicast_mac(0xe00000fb);
+    let mac = links[0].iface.mac();
+    assert!(deliver(&mut links, mac) && deliver(&mut links, [0xFF; 6]));
+    assert!(!deliver(&mut links, peer) && !deliver(&mut links, group));
+    links[0].iface.join_multicast(0xe00000fb).unwrap();
+    assert!(deliver(&mut links, group));
+    links[0].iface.set_promiscuous(true);
+    assert!(deliver(&mut links, peer));
+}
+
+#[cfg(test)]
+#[test]
+fn test_runt_frame() {
+    let now = Instant::now();
+    let mut links = vec![Link::new(Interface::default(), now)];
+    let mut connections = tcp::Connections::new();
+    let mut buf = [0u8; 1522];
+
+    // Frames that end inside the IP header, behind which an earlier, longer frame
+    // left what looks like the rest of it.
+    let peer = [0x02, 0, 0, 0, 0, 0x01];
+    use ipv4::ProtoType::*;
+    for protocol in [ICMP, TCP, UDP] {
+        let header = build_ipv4_header(0x0a000001, 0x0a000002, protocol, 20);
+        let mac = links[0].iface.mac();
+        let frame = eth::frame(mac, peer, &[], eth::EtherType::Ipv4, &header);
+        buf[..frame.len()].clone_from_slice(&frame);
+        assert!(!dispatch(&mut buf, 30, &mut links, &mut connections).0);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_rarp_address() {
+    let now = Instant::now();
+    let mut links = vec![Link::new(Interface::default(), now)];
+    let mut connections = tcp::Connections::new();
+    links[0].arp.rarp.request_address(now);
+    links[0].on_tick(now);
+
+    // The server's reply puts the address on the interface, and ACD probes for it.
+    let server = [0x02, 0, 0, 0, 0, 0x01];
+    let mac = links[0].iface.mac();
+    let reply = arp::ArpPacket::new(4, (server, 0xc0a80001), (mac, 0xc0a80064)).to_bytes();
+    let frame = eth::frame(mac, server, &[], eth::EtherType::Rarp, &reply);
+    let mut buf = [0u8; 1522];
+    buf[..frame.len()].clone_from_slice(&frame);
+    assert!(!dispatch(&mut buf, frame.len(), &mut links, &mut connections).0);
+    links[0].on_tick(now);
+    assert!(links[0].iface.has_address(0xc0a80064));
+    assert!(links[0].iface.is_on_link(0xc0a80001));
+    let probing = arp::ClaimState::Probing { sent: 0 };
+    assert_eq!(links[0].arp.acd.state(0xc0a80064), Some(probing));
+    assert_eq!(links[0].arp.rarp.poll_assigned(), None);
+}
+
+#[cfg(test)]
+#[test]
+fn test_neighbor_files() {
+    let now = Instant::now();
+    let dir = std::env::temp_dir();
+    let ethers = dir.join(format!("pct-ethers-{}", std::process::id()));
+    let table = dir.join(format!("pct-arp-{}", std::process::id()));
+    fs::write(&ethers, "# gateway\n10.0.0.1 02:00:00:00:00:01\n").unwrap();
+    let iface = Interface::new(crate::iface::InterfaceConfig {
+        static_neighbors: Some(ethers.clone()),
+        neighbor_table: Some(table.clone()),
+        ..crate::iface::InterfaceConfig::default()
+    })
+    .unwrap();
+    let mut link = Link::new(iface, now);
+    assert_eq!(link.load_static_neighbors(now).unwrap(), 1);
+    link.export_neighbors().unwrap();
+    let exported = fs::read_to_string(&table).unwrap();
+    assert!(exported.lines().nth(1).unwrap().starts_with("10.0.0.1 "));
+    assert!(exported.contains("02:00:00:00:00:01"));
+    fs::remove_file(ethers).unwrap();
+    fs::remove_file(table).unwrap();
+}
+
+#[cfg(test)]
+#[test]
+fn test_dispatch_snap() {
+    let now = Instant::now();
+    let mut links = vec![Link::new(Interface::default(), now)];
+    links[0].arp.acd.add_unchecked(0x0a000002);
+    let mut connections = tcp::Connections::new();
+    let peer = [0x02, 0, 0, 0, 0, 0x01];
+    let mut buf = [0u8; 1522];
+
+    // SNAP encapsulated ARP is answered the same way.
+    let snap = eth::LlcHeader::snap(eth::OUI_ETHERTYPE, eth::EtherType::Arp as u16);
+    let request = arp::ArpPacket::request(peer, 0x0a000001, 0x0a000002).to_bytes();
+    let payload = [&snap.to_bytes()[..], &request].concat();
+    let mut frame = eth::frame([0xFF; 6], peer, &[], eth::EtherType::Arp, &payload);
+    frame[16..18].clone_from_slice(&(payload.len() as u16).to_be_bytes());
+    buf[..frame.len()].clone_from_slice(&frame);
+    let (reply, len) = dispatch(&mut buf, frame.len(), &mut links, &mut connections);
+    assert!(reply);
+    assert_eq!(buf[16..18], ((len - 18) as u16).to_be_bytes());
+    assert_eq!(buf[18..26], snap.to_bytes()[..]);
+    let answer = arp::ArpPacket::reply(
+        &arp::ArpPacket::request(peer, 0x0a000001, 0x0a000002),
+        links[0].iface.mac(),
+    );
+    assert_eq!(buf[26..len], answer.to_bytes());
+
+    // A spanning tree BPDU goes no further, even sent to us.
+    let bpdu = [eth::SAP_STP, eth::SAP_STP, 0x03, 0, 0, 0, 0];
+    let mac = links[0].iface.mac();
+    let mut frame = eth::frame(mac, peer, &[], eth::EtherType::Arp, &bpdu);
+    frame[16..18].clone_from_slice(&(bpdu.len() as u16).to_be_bytes());
+    buf[..frame.len()].clone_from_slice(&frame);
+    assert!(!dispatch(&mut buf, frame.len(), &mut links, &mut connections).0);
 }
@@ -0,0 +1,255 @@
+// Local port management: which ports are listened on, handing out ephemeral
+// ports for connections that don't pick their own, and the rules for when a
+// port may be bound again. Transports describe their connections as `Endpoint`s
+// rather than this module knowing about any of them.
+//
+// Ephemeral ports are chosen with RFC 6056 algorithm 3, a keyed hash of the
+// addresses picks where in the range the search for a free port starts.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::io;
+
+/// The dynamic port range, RFC 6335 section 6.
+pub const EPHEMERAL_RANGE: (u16, u16) = (49152, 65535);
+
+/// Any local address, for listening on every address we have.
+pub const ANY_ADDR: u32 = 0;
+
+/// A connection a transport has open, as far as port allocation cares.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Endpoint {
+    pub local: (u32, u16),
+    pub remote: (u32, u16),
+
+    /// Lingering in TIME-WAIT, which `reuse_addr` lets a new binding take over.
+    pub time_wait: bool,
+}
+
+/// A listening address and port.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Listener {
+    addr: u32,
+}
+
+pub struct Ports {
+    /// Ephemeral ports are taken from here, both ends included.
+    range: (u16, u16),
+
+    /// Keys the hash ephemeral port searches start from.
+    secret: RandomState,
+
+    /// Bumped after every allocation, so a busy four-tuple moves on, RFC 6056 3.3.3.
+    next_ephemeral: u16,
+
+    listeners: HashMap<u16, Vec<Listener>>,
+
+    /// Like SO_REUSEADDR, lets a port be bound while connections in TIME-WAIT, or
+    /// when listening any connections at all, still hold it.
+    reuse_addr: bool,
+}
+
+impl Default for Ports {
+    fn default() -> Self {
+        Ports::new()
+    }
+}
+
+/// Whether two local addresses overlap, either being the wildcard.
+fn overlaps(a: u32, b: u32) -> bool {
+    a == ANY_ADDR || b == ANY_ADDR || a == b
+}
+
+fn in_use() -> io::Error {
+    io::Error::from(io::ErrorKind::AddrInUse)
+}
+
+impl Ports {
+    pub fn new() -> Self {
+        Ports {
+            range: EPHEMERAL_RANGE,
+            secret: RandomState::new(),
+            next_ephemeral: 0,
+            listeners: HashMap::new(),
+            reuse_addr: false,
+        }
+    }
+
+    /// Sets the range ephemeral ports come from, both ends included.
+    pub fn set_ephemeral_range(&mut self, low: u16, high: u16) -> io::Result<()> {
+        if low == 0 || low > high {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        self.range = (low, high);
+        Ok(())
+    }
+
+    pub fn ephemeral_range(&self) -> (u16, u16) {
+        self.range
+    }
+
+    pub fn set_reuse_addr(&mut self, enabled: bool) {
+        self.reuse_addr = enabled;
+    }
+
+    /// Whether something listens on `local`, or on the port for any address.
+    pub fn is_listening(&self, local: (u32, u16)) -> bool {
+        self.listeners
+            .get(&local.1)
+            .map(|ls| ls.iter().any(|l| overlaps(l.addr, local.0)))
+            .unwrap_or(false)
+    }
+
+    /// Starts listening on `local`. Two listeners never share an address and port.
+    /// Connections on the port get in the way too, unless `reuse_addr` is set.
+    pub fn listen(
+        &mut self,
+        local: (u32, u16),
+        endpoints: impl IntoIterator<Item = Endpoint>,
+    ) -> io::Result<()> {
+        if local.1 == 0 || self.is_listening(local) {
+            return Err(in_use());
+        }
+        let busy = endpoints
+            .into_iter()
+            .any(|e| e.local.1 == local.1 && overlaps(e.local.0, local.0));
+        if busy && !self.reuse_addr {
+            return Err(in_use());
+        }
+        self.listeners
+            .entry(local.1)
+            .or_default()
+            .push(Listener { addr: local.0 });
+        Ok(())
+    }
+
+    /// Stops listening on `local`, returning whether anything was.
+    pub fn unlisten(&mut self, local: (u32, u16)) -> bool {
+        let listeners = match self.listeners.get_mut(&local.1) {
+            Some(listeners) => listeners,
+            None => return false,
+        };
+        let before = listeners.len();
+        listeners.retain(|l| l.addr != local.0);
+        let removed = listeners.len() != before;
+        if listeners.is_empty() {
+            self.listeners.remove(&local.1);
+        }
+        removed
+    }
+
+    /// Checks a connection from an explicitly chosen local port. A listener on the
+    /// port refuses it, as does the same four-tuple already in use. A four-tuple in
+    /// TIME-WAIT may be taken over with `reuse_addr`, and is returned so the
+    /// transport can drop it.
+    pub fn bind(
+        &self,
+        local: (u32, u16),
+        remote: (u32, u16),
+        endpoints: impl IntoIterator<Item = Endpoint>,
+    ) -> io::Result<Option<Endpoint>> {
+        if self.is_listening(local) {
+            return Err(in_use());
+        }
+        match endpoints
+            .into_iter()
+            .find(|e| e.local == local && e.remote == remote)
+        {
+            Some(e) if e.time_wait && self.reuse_addr => Ok(Some(e)),
+            Some(_) => Err(in_use()),
+            None => Ok(None),
+        }
+    }
+
+    /// Picks an ephemeral port for a connection from `local_addr` to `remote`, one
+    /// no listener holds and that makes a four-tuple nothing else uses, TIME-WAIT
+    /// included. `AddrNotAvailable` once the range is exhausted.
+    pub fn allocate(
+        &mut self,
+        local_addr: u32,
+        remote: (u32, u16),
+        endpoints: impl IntoIterator<Item = Endpoint>,
+    ) -> io::Result<u16> {
+        let taken: Vec<u16> = endpoints
+            .into_iter()
+            .filter(|e| e.local.0 == local_addr && e.remote == remote)
+            .map(|e| e.local.1)
+            .collect();
+        let (low, high) = self.range;
+        let count = (high - low) as u32 + 1;
+        let offset = self.secret.hash_one((local_addr, remote)) as u32;
+
+        for i in 0..count {
+            let step = offset
+                .wrapping_add(self.next_ephemeral as u32)
+                .wrapping_add(i);
+            let port = low + (step % count) as u16;
+            if !taken.contains(&port) && !self.is_listening((local_addr, port)) {
+                self.next_ephemeral = self.next_ephemeral.wrapping_add(1);
+                return Ok(port);
+            }
+        }
+        Err(io::Error::from(io::ErrorKind::AddrNotAvailable))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCAL: u32 = 0x0a000002;
+    const REMOTE: (u32, u16) = (0x0a000001, 80);
+
+    #[test]
+    fn test_allocate() {
+        let mut ports = Ports::new();
+        ports.set_ephemeral_range(40000, 40003).unwrap();
+        ports.listen((ANY_ADDR, 40001), vec![]).unwrap();
+
+        let mut endpoints = vec![];
+        for _ in 0..3 {
+            let port = ports
+                .allocate(LOCAL, REMOTE, endpoints.iter().copied())
+                .unwrap();
+            assert!((40000..=40003).contains(&port) && port != 40001);
+            endpoints.push(Endpoint {
+                local: (LOCAL, port),
+                remote: REMOTE,
+                time_wait: true,
+            });
+        }
+        let exhausted = ports.allocate(LOCAL, REMOTE, endpoints.iter().copied());
+        assert_eq!(
+            exhausted.unwrap_err().kind(),
+            io::ErrorKind::AddrNotAvailable
+        );
+        // Another peer can still use every port.
+        assert!(ports
+            .allocate(LOCAL, (REMOTE.0, 443), endpoints.iter().copied())
+            .is_ok());
+    }
+
+    #[test]
+    fn test_reuse_addr() {
+        let mut ports = Ports::new();
+        let tw = Endpoint {
+            local: (LOCAL, 8080),
+            remote: REMOTE,
+            time_wait: true,
+        };
+        let err = ports.listen((ANY_ADDR, 8080), vec![tw]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert!(ports.bind(tw.local, tw.remote, vec![tw]).is_err());
+
+        ports.set_reuse_addr(true);
+        assert_eq!(ports.bind(tw.local, tw.remote, vec![tw]).unwrap(), Some(tw));
+        ports.listen((ANY_ADDR, 8080), vec![tw]).unwrap();
+        // Never two listeners, and nothing binds under one.
+        assert!(ports.listen((LOCAL, 8080), vec![]).is_err());
+        assert!(ports.bind((LOCAL, 8080), REMOTE, vec![]).is_err());
+        assert!(ports.unlisten((ANY_ADDR, 8080)));
+        ports.listen((LOCAL, 8080), vec![]).unwrap();
+        ports.listen((0x0a000003, 8080), vec![]).unwrap();
+    }
+}
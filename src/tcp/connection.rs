@@ -0,0 +1,1431 @@
+// The Transmission Control Block of a single connection, and the segment
+// processing that drives it, following RFC 793 section 3.9 ("Event Processing").
+// Retransmission timing follows RFC 6298 and congestion control RFC 5681.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::time::{Duration, Instant};
+
+use super::fast_open::FastOpenSyn;
+use super::info::{Stats, TcpInfo};
+use super::mptcp::{Subflow, Verdict, DSS_SPACE};
+use super::options::TcpOptions;
+use super::repair::{invalid, Reader, Writer};
+use super::{build_segment, seq_le, seq_lt, Quad, Segment, State, TcpHeader, TcpHeaderFlags};
+
+/// The MSS we advertise, an ethernet MTU less the IPv4 and TCP headers.
+pub const LOCAL_MSS: u16 = 1460;
+
+/// MSS assumed when the peer does not send the option, RFC 1122 section 4.2.2.6.
+const DEFAULT_MSS: u16 = 536;
+
+/// The smallest MSS we take from a peer, lower ones are raised to it. Below this the
+/// headers dwarf the data, and 0 would leave us a window of nothing.
+const MIN_MSS: u16 = 64;
+
+/// Size each connection's receive buffer starts at, before autotuning grows it.
+pub const RECV_BUFFER_SIZE: usize = 65535;
+
+/// The largest receive buffer autotuning grows to.
+const MAX_RECV_BUFFER_SIZE: usize = 8 << 20;
+
+/// The window scale we ask for, RFC 7323, enough to advertise `MAX_RECV_BUFFER_SIZE`.
+const WINDOW_SCALE: u8 = 7;
+
+/// The largest shift RFC 7323 section 2.3 allows.
+const MAX_WINDOW_SCALE: u8 = 14;
+
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_secs(1);
+const MAX_RTO: Duration = Duration::from_secs(60);
+
+/// Clock granularity used in the RTO calculation.
+const CLOCK_GRANULARITY: Duration = Duration::from_millis(1);
+
+/// Retransmission timeouts in a row before the connection is given up on.
+const MAX_RETRANSMITS: u32 = 8;
+
+/// Maximum segment lifetime, connections sit in TIME-WAIT for twice this.
+const MSL: Duration = Duration::from_secs(30);
+
+const DUP_ACK_THRESHOLD: u32 = 3;
+
+/// Send Sequence Variables, RFC 793 section 3.2.
+#[derive(Clone, Debug)]
+struct SendSequence {
+    /// oldest unacknowledged sequence number
+    una: u32,
+    /// next sequence number to be sent
+    nxt: u32,
+    /// window advertised by the peer
+    wnd: u32,
+    /// segment sequence number used for last window update
+    wl1: u32,
+    /// segment acknowledgment number used for last window update
+    wl2: u32,
+    /// initial send sequence number
+    iss: u32,
+}
+
+/// Receive Sequence Variables, RFC 793 section 3.2.
+#[derive(Clone, Debug)]
+struct RecvSequence {
+    /// next sequence number expected
+    nxt: u32,
+    /// initial receive sequence number
+    irs: u32,
+}
+
+/// Window scaling, RFC 7323. Both shifts stay 0 unless both SYNs carried the option.
+#[derive(Clone, Debug, Default)]
+struct WindowScale {
+    /// The peer's shift, applied to the windows it advertises.
+    snd: u8,
+    /// Our shift, applied to the windows we advertise.
+    rcv: u8,
+}
+
+pub struct Connection {
+    quad: Quad,
+    state: State,
+    send: SendSequence,
+    recv: RecvSequence,
+    window_scale: WindowScale,
+
+    /// Largest segment we send, from the peer's MSS option.
+    mss: u16,
+
+    cwnd: u32,
+    ssthresh: u32,
+
+    /// The sequence number the current fast recovery ends at, if in one.
+    recovery: Option<u32>,
+    dup_acks: u32,
+
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+
+    /// The end of the segment being timed for an RTT sample, and when it was sent.
+    rtt_sample: Option<(u32, Instant)>,
+
+    /// When the retransmission timer fires, if it is running.
+    retransmit_at: Option<Instant>,
+
+    /// Retransmission timeouts since the last forward progress.
+    retransmits: u32,
+
+    /// Opened by the peer's SYN rather than `connect`.
+    passive: bool,
+
+    /// Data is riding on the SYN, RFC 7413. For a server this means the SYN's
+    /// cookie was valid and its data accepted.
+    fast_open: bool,
+
+    /// The Fast Open option our SYN or SYN-ACK carries, a cookie or a cookie request.
+    syn_cookie: Option<Vec<u8>>,
+
+    /// TCP MD5 signature key shared with the peer.
+    md5_key: Option<Vec<u8>>,
+
+    /// MPTCP state, if this connection is a subflow of an MPTCP connection.
+    mptcp: Option<Subflow>,
+
+    /// Our SYN has been acknowledged, so data may flow.
+    syn_acked: bool,
+
+    /// When a connection in TIME-WAIT may be forgotten.
+    time_wait_until: Option<Instant>,
+
+    /// Data written by the application and not yet acknowledged, starting at `send.una`.
+    unacked: VecDeque<u8>,
+
+    /// The application has closed its side, a FIN follows the data in `unacked`.
+    fin_queued: bool,
+
+    /// In order data received and not yet read by the application.
+    incoming: VecDeque<u8>,
+
+    /// How much `incoming` may hold, grown by autotuning.
+    recv_buffer: usize,
+
+    /// Start of the current autotuning measurement, when it began and `recv.nxt` then.
+    recv_measure: Option<(Instant, u32)>,
+
+    /// A bigger receive buffer autotuning would like, see `take_recv_buffer_wanted`.
+    recv_buffer_wanted: Option<usize>,
+
+    /// Sequence number following the last urgent byte we were asked to send.
+    /// Like most stacks this follows RFC 6093 rather than RFC 793's pointer.
+    send_up: Option<u32>,
+
+    /// Sequence number following the last urgent byte the peer signalled, until it arrives.
+    recv_up: Option<u32>,
+
+    /// Leave urgent bytes in the normal stream rather than delivering them out of band.
+    urgent_inline: bool,
+
+    /// The urgent byte taken out of the stream when delivering out of band.
+    urgent_data: Option<u8>,
+
+    /// How many bytes of `incoming` come before the urgent mark, if it is still to be read.
+    urgent_mark: Option<usize>,
+
+    /// Data received ahead of `recv.nxt`, keyed by sequence number.
+    out_of_order: BTreeMap<u32, Vec<u8>>,
+
+    stats: Stats,
+}
+
+impl Connection {
+    fn new(quad: Quad, state: State, iss: u32) -> Self {
+        let mut c = Connection {
+            quad,
+            state,
+            passive: state == State::SynRcvd,
+            send: SendSequence {
+                una: iss,
+                nxt: iss,
+                wnd: 0,
+                wl1: 0,
+                wl2: 0,
+                iss,
+            },
+            recv: RecvSequence { nxt: 0, irs: 0 },
+            window_scale: WindowScale::default(),
+            mss: DEFAULT_MSS,
+            cwnd: 0,
+            ssthresh: u32::MAX,
+            recovery: None,
+            dup_acks: 0,
+            srtt: None,
+            rttvar: Duration::from_secs(0),
+            rto: INITIAL_RTO,
+            rtt_sample: None,
+            retransmit_at: None,
+            retransmits: 0,
+            fast_open: false,
+            syn_cookie: None,
+            md5_key: None,
+            mptcp: None,
+            syn_acked: false,
+            time_wait_until: None,
+            unacked: VecDeque::new(),
+            fin_queued: false,
+            incoming: VecDeque::new(),
+            recv_buffer: RECV_BUFFER_SIZE,
+            recv_measure: None,
+            recv_buffer_wanted: None,
+            send_up: None,
+            recv_up: None,
+            urgent_inline: false,
+            urgent_data: None,
+            urgent_mark: None,
+            out_of_order: BTreeMap::new(),
+            stats: Stats::default(),
+        };
+        c.cwnd = c.initial_window();
+        c
+    }
+
+    /// Passive open, for a SYN received on a port we accept connections on.
+    /// `open` then sends the SYN-ACK.
+    pub fn accept(
+        quad: Quad,
+        iss: u32,
+        header: &TcpHeader,
+        options: &TcpOptions,
+        fast_open: FastOpenSyn,
+    ) -> Self {
+        let mut c = Connection::new(quad, State::SynRcvd, iss);
+        c.stats.segs_in += 1;
+        c.on_peer_syn(header, options);
+        c.syn_cookie = fast_open.cookie;
+        if let Some(data) = fast_open.data {
+            c.fast_open = true;
+            c.receive(c.recv.nxt, data);
+        }
+        c
+    }
+
+    /// Active open, `open` then sends the SYN.
+    pub fn connect(quad: Quad, iss: u32) -> Self {
+        Connection::new(quad, State::SynSent, iss)
+    }
+
+    /// Active open with Fast Open. With a cookie the first of `data` goes in the
+    /// SYN, without one the SYN asks for a cookie and `data` follows the handshake.
+    pub fn connect_fast_open(quad: Quad, iss: u32, cookie: Option<&[u8]>, data: &[u8]) -> Self {
+        let mut c = Connection::new(quad, State::SynSent, iss);
+        c.unacked.extend(data);
+        c.fast_open = cookie.is_some() && !data.is_empty();
+        c.syn_cookie = Some(cookie.unwrap_or_default().to_vec());
+        c
+    }
+
+    /// Sends our SYN, or SYN-ACK for a passive open.
+    pub fn open(&mut self, now: Instant, out: &mut VecDeque<Segment>) {
+        self.send_syn(now, out);
+    }
+
+    /// Signs every segment we send with `key` and requires the peer to do the same, RFC 2385.
+    pub fn set_md5_key(&mut self, key: Option<Vec<u8>>) {
+        self.md5_key = key;
+    }
+
+    pub fn md5_key(&self) -> Option<&[u8]> {
+        self.md5_key.as_deref()
+    }
+
+    /// Makes the connection a subflow of an MPTCP connection, before `open`.
+    pub fn set_mptcp(&mut self, subflow: Subflow) {
+        self.mptcp = Some(subflow);
+    }
+
+    pub fn mptcp(&self) -> Option<&Subflow> {
+        self.mptcp.as_ref()
+    }
+
+    pub fn mptcp_mut(&mut self) -> Option<&mut Subflow> {
+        self.mptcp.as_mut()
+    }
+
+    /// Whether the MPTCP scheduler may give this subflow data.
+    pub fn is_mptcp_usable(&self) -> bool {
+        self.syn_acked
+            && matches!(self.state, State::Established | State::CloseWait)
+            && self
+                .mptcp
+                .as_ref()
+                .is_some_and(|subflow| subflow.is_usable())
+    }
+
+    /// How much more data the send and congestion windows have room for.
+    pub fn send_space(&self) -> usize {
+        (self.send.wnd.min(self.cwnd) as usize).saturating_sub(self.unacked.len())
+    }
+
+    /// Queues data for an MPTCP subflow, mapped to data sequence numbers from `dsn`.
+    pub fn write_mapped(
+        &mut self,
+        dsn: u64,
+        data: &[u8],
+        now: Instant,
+        out: &mut VecDeque<Segment>,
+    ) -> usize {
+        if self.fin_queued {
+            return 0;
+        }
+        let end = self.data_start().wrapping_add(self.unacked.len() as u32);
+        if let Some(subflow) = &mut self.mptcp {
+            subflow.on_write(dsn, end.wrapping_sub(self.send.iss), data.len());
+        }
+        self.write(data, now, out)
+    }
+
+    /// Reads up to `limit` bytes received on an MPTCP subflow, as runs of bytes
+    /// with their data sequence numbers.
+    pub fn read_mapped(
+        &mut self,
+        limit: usize,
+        out: &mut VecDeque<Segment>,
+    ) -> Vec<(u64, Vec<u8>)> {
+        let mut buf = vec![0u8; limit.min(self.incoming.len())];
+        let n = self.read(&mut buf, out);
+        match &mut self.mptcp {
+            Some(subflow) => subflow.map_received(&buf[..n]),
+            None => Vec::new(),
+        }
+    }
+
+    /// Sends a pure ACK, for when something the segment carries other than the TCP
+    /// acknowledgment needs to reach the peer.
+    pub fn ack_now(&mut self, out: &mut VecDeque<Segment>) {
+        self.send_ack(out);
+    }
+
+    pub fn recv_buffer(&self) -> usize {
+        self.recv_buffer
+    }
+
+    /// Takes the receive buffer size autotuning last asked for, for `Connections` to
+    /// grant as far as its memory limit allows.
+    pub fn take_recv_buffer_wanted(&mut self) -> Option<usize> {
+        self.recv_buffer_wanted.take()
+    }
+
+    /// Grows the receive buffer, and so the window we advertise. It never shrinks,
+    /// that would take back window the peer may already be using.
+    pub fn set_recv_buffer(&mut self, size: usize) {
+        self.recv_buffer = self.recv_buffer.max(size);
+    }
+
+    pub fn quad(&self) -> Quad {
+        self.quad
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Whether data rode on the SYN, see `connect_fast_open` and `FastOpen::on_syn`.
+    pub fn is_fast_open(&self) -> bool {
+        self.fast_open
+    }
+
+    /// Whether the connection was opened by a SYN from the peer, rather than by us.
+    pub fn is_passive(&self) -> bool {
+        self.passive
+    }
+
+    /// A snapshot of the connection's state and counters.
+    pub fn info(&self) -> TcpInfo {
+        TcpInfo {
+            state: self.state,
+            mss: self.mss,
+            cwnd: self.cwnd,
+            ssthresh: self.ssthresh,
+            srtt: self.srtt,
+            rttvar: self.rttvar,
+            rto: self.rto,
+            snd_wnd: self.send.wnd,
+            rcv_wnd: self.recv_window() as u32,
+            rcv_buf: self.recv_buffer as u32,
+            wscale: match self.window_scale {
+                WindowScale { snd: 0, rcv: 0 } => None,
+                WindowScale { snd, rcv } => Some((snd, rcv)),
+            },
+            unacked: self.send.nxt.wrapping_sub(self.send.una),
+            segs_in: self.stats.segs_in,
+            segs_out: self.stats.segs_out,
+            bytes_sent: self.stats.bytes_sent,
+            bytes_retrans: self.stats.bytes_retrans,
+            bytes_acked: self.stats.bytes_acked,
+            bytes_received: self.stats.bytes_received,
+            retransmits: self.retransmits,
+            total_retrans: self.stats.total_retrans,
+            dup_acks_in: self.stats.dup_acks_in,
+            sack_blocks_in: self.stats.sack_blocks_in,
+            reordering: self.stats.reordering,
+        }
+    }
+
+    /// Writes out the TCB for `Connections::checkpoint`. The RTT sample in flight
+    /// and the autotuning measurement are left out, both just start over.
+    pub fn checkpoint(&self, now: Instant, w: &mut Writer) {
+        let time_left = |at: Instant| at.saturating_duration_since(now);
+        w.quad(&self.quad);
+        w.state(self.state);
+        for value in [self.send.una, self.send.nxt, self.send.wnd, self.send.wl1] {
+            w.u32(value);
+        }
+        for value in [self.send.wl2, self.send.iss, self.recv.nxt, self.recv.irs] {
+            w.u32(value);
+        }
+        w.u8(self.window_scale.snd);
+        w.u8(self.window_scale.rcv);
+        w.u16(self.mss);
+        w.u32(self.cwnd);
+        w.u32(self.ssthresh);
+        w.option(self.recovery, Writer::u32);
+        w.u32(self.dup_acks);
+        w.option(self.srtt, Writer::duration);
+        w.duration(self.rttvar);
+        w.duration(self.rto);
+        w.option(self.retransmit_at.map(time_left), Writer::duration);
+        w.u32(self.retransmits);
+        w.bool(self.passive);
+        w.bool(self.fast_open);
+        w.option(self.syn_cookie.as_ref(), |w, cookie| w.bytes(cookie.iter()));
+        w.option(self.md5_key.as_ref(), |w, key| w.bytes(key.iter()));
+        w.bool(self.syn_acked);
+        w.option(self.time_wait_until.map(time_left), Writer::duration);
+        w.bytes(self.unacked.iter());
+        w.bool(self.fin_queued);
+        w.bytes(self.incoming.iter());
+        w.u32(self.recv_buffer as u32);
+        w.option(self.send_up, Writer::u32);
+        w.option(self.recv_up, Writer::u32);
+        w.bool(self.urgent_inline);
+        w.option(self.urgent_data, Writer::u8);
+        w.option(self.urgent_mark.map(|mark| mark as u32), Writer::u32);
+        w.u32(self.out_of_order.len() as u32);
+        for (seq, data) in &self.out_of_order {
+            w.u32(*seq);
+            w.bytes(data.iter());
+        }
+        for value in [
+            self.stats.segs_in,
+            self.stats.segs_out,
+            self.stats.bytes_sent,
+            self.stats.bytes_retrans,
+            self.stats.bytes_acked,
+            self.stats.bytes_received,
+        ] {
+            w.u64(value);
+        }
+        for value in [
+            self.stats.total_retrans,
+            self.stats.dup_acks_in,
+            self.stats.sack_blocks_in,
+            self.stats.reordering,
+        ] {
+            w.u32(value);
+        }
+    }
+
+    /// Reads back a TCB written by `checkpoint`, restarting its timers from `now`.
+    pub fn restore(r: &mut Reader, now: Instant) -> io::Result<Self> {
+        let quad = r.quad()?;
+        let state = r.state()?;
+        let mut c = Connection::new(quad, state, 0);
+        c.send = SendSequence {
+            una: r.u32()?,
+            nxt: r.u32()?,
+            wnd: r.u32()?,
+            wl1: r.u32()?,
+            wl2: r.u32()?,
+            iss: r.u32()?,
+        };
+        c.recv = RecvSequence {
+            nxt: r.u32()?,
+            irs: r.u32()?,
+        };
+        c.window_scale = WindowScale {
+            snd: r.u8()?,
+            rcv: r.u8()?,
+        };
+        c.mss = r.u16()?;
+        if c.window_scale.snd > MAX_WINDOW_SCALE
+            || c.window_scale.rcv > MAX_WINDOW_SCALE
+            || c.mss < MIN_MSS
+        {
+            return Err(invalid("window scale or MSS"));
+        }
+        c.cwnd = r.u32()?;
+        c.ssthresh = r.u32()?;
+        c.recovery = r.option(Reader::u32)?;
+        c.dup_acks = r.u32()?;
+        c.srtt = r.option(Reader::duration)?;
+        c.rttvar = r.duration()?;
+        c.rto = r.duration()?;
+        c.retransmit_at = r.option(Reader::duration)?.map(|left| now + left);
+        c.retransmits = r.u32()?;
+        c.passive = r.bool()?;
+        c.fast_open = r.bool()?;
+        c.syn_cookie = r.option(|r| r.bytes().map(|b| b.to_vec()))?;
+        c.md5_key = r.option(|r| r.bytes().map(|b| b.to_vec()))?;
+        c.syn_acked = r.bool()?;
+        c.time_wait_until = r.option(Reader::duration)?.map(|left| now + left);
+        c.unacked = r.bytes()?.iter().copied().collect();
+        c.fin_queued = r.bool()?;
+        c.incoming = r.bytes()?.iter().copied().collect();
+        c.recv_buffer = r.u32()? as usize;
+        c.send_up = r.option(Reader::u32)?;
+        c.recv_up = r.option(Reader::u32)?;
+        c.urgent_inline = r.bool()?;
+        c.urgent_data = r.option(Reader::u8)?;
+        c.urgent_mark = r.option(Reader::u32)?.map(|mark| mark as usize);
+        for _ in 0..r.u32()? {
+            let seq = r.u32()?;
+            c.out_of_order.insert(seq, r.bytes()?.to_vec());
+        }
+        c.stats = Stats {
+            segs_in: r.u64()?,
+            segs_out: r.u64()?,
+            bytes_sent: r.u64()?,
+            bytes_retrans: r.u64()?,
+            bytes_acked: r.u64()?,
+            bytes_received: r.u64()?,
+            total_retrans: r.u32()?,
+            dup_acks_in: r.u32()?,
+            sack_blocks_in: r.u32()?,
+            reordering: r.u32()?,
+        };
+        c.check_sequence_space().map_err(invalid)?;
+        Ok(c)
+    }
+
+    /// Whether a restored TCB's sequence numbers agree with each other and with
+    /// what is buffered, so a corrupted checkpoint can't have us send or accept
+    /// anything outside the windows.
+    fn check_sequence_space(&self) -> Result<(), &'static str> {
+        let flight = self.send.nxt.wrapping_sub(self.send.una) as usize;
+        let (syn, fin) = (!self.syn_acked as usize, self.fin_queued as usize);
+        if flight > self.unacked.len() + syn + fin {
+            return Err("more in flight than unacknowledged");
+        }
+
+        // Until the handshake is done the window hasn't been updated from an ACK.
+        let recv_end = self.recv.nxt.wrapping_add(self.recv_buffer as u32);
+        let updated = !matches!(
+            self.state,
+            State::Listen | State::SynSent | State::SynRcvd | State::Closed
+        );
+        if updated
+            && !(seq_le(self.recv.irs, self.send.wl1)
+                && seq_le(self.send.wl1, recv_end)
+                && seq_le(self.send.iss, self.send.wl2)
+                && seq_le(self.send.wl2, self.send.nxt))
+        {
+            return Err("window update out of range");
+        }
+
+        let send_end = self.data_start().wrapping_add(self.unacked.len() as u32);
+        if let Some(up) = self.send_up {
+            if !(seq_lt(self.send.una, up) && seq_le(up, send_end)) {
+                return Err("send urgent pointer out of range");
+            }
+        }
+        if let Some(up) = self.recv_up {
+            let furthest = recv_end.wrapping_add(u16::MAX as u32);
+            if !(seq_lt(self.recv.nxt, up) && seq_le(up, furthest)) {
+                return Err("receive urgent pointer out of range");
+            }
+        }
+
+        let window_end = self.recv.nxt.wrapping_add(self.recv_window() as u32);
+        let in_window = |seq: u32| seq_lt(self.recv.nxt, seq) && seq_lt(seq, window_end);
+        if !self.out_of_order.keys().all(|&seq| in_window(seq)) {
+            return Err("out of order data outside the receive window");
+        }
+        Ok(())
+    }
+
+    /// Queues application data for sending, returning how much was taken.
+    pub fn write(&mut self, data: &[u8], now: Instant, out: &mut VecDeque<Segment>) -> usize {
+        if self.fin_queued {
+            return 0;
+        }
+        self.unacked.extend(data);
+        self.flush(now, out);
+        data.len()
+    }
+
+    /// Queues data to be sent as urgent, the urgent pointer marking its last byte.
+    pub fn write_urgent(
+        &mut self,
+        data: &[u8],
+        now: Instant,
+        out: &mut VecDeque<Segment>,
+    ) -> usize {
+        if self.fin_queued || data.is_empty() {
+            return 0;
+        }
+        self.unacked.extend(data);
+        self.send_up = Some(self.data_start().wrapping_add(self.unacked.len() as u32));
+        self.flush(now, out);
+        data.len()
+    }
+
+    /// Takes the urgent byte held out of band, if one has arrived.
+    pub fn read_urgent(&mut self) -> Option<u8> {
+        self.urgent_data.take()
+    }
+
+    /// The peer has signalled urgent data that has not arrived yet.
+    pub fn is_urgent_pending(&self) -> bool {
+        self.recv_up.is_some()
+    }
+
+    /// The next byte `read` returns is the urgent mark, like `sockatmark`.
+    pub fn is_at_urgent_mark(&self) -> bool {
+        self.urgent_mark == Some(0)
+    }
+
+    /// Chooses between leaving urgent bytes in the stream and holding them out of band.
+    pub fn set_urgent_inline(&mut self, inline: bool) {
+        self.urgent_inline = inline;
+    }
+
+    /// Reads received data into `buf`, returning how much was read.
+    /// A read stops short of the urgent mark, so the mark can be noticed.
+    pub fn read(&mut self, buf: &mut [u8], out: &mut VecDeque<Segment>) -> usize {
+        let was_closed = self.recv_window() < self.mss as usize;
+        let mut n = buf.len().min(self.incoming.len());
+        match self.urgent_mark {
+            Some(0) => self.urgent_mark = None,
+            Some(mark) => {
+                n = n.min(mark);
+                self.urgent_mark = Some(mark - n);
+            }
+            None => {}
+        }
+        for (b, byte) in buf.iter_mut().zip(self.incoming.drain(..n)) {
+            *b = byte;
+        }
+        // Let the peer know if reading reopened a window it saw as (nearly) closed.
+        if n > 0 && was_closed && self.recv_window() >= self.mss as usize {
+            self.send_ack(out);
+        }
+        n
+    }
+
+    /// The peer has closed its side and all its data has been read.
+    pub fn is_read_closed(&self) -> bool {
+        self.incoming.is_empty()
+            && matches!(
+                self.state,
+                State::CloseWait
+                    | State::LastAck
+                    | State::Closing
+                    | State::TimeWait
+                    | State::Closed
+            )
+    }
+
+    /// Closes our side of the connection, a FIN is sent after any queued data.
+    pub fn close(&mut self, now: Instant, out: &mut VecDeque<Segment>) {
+        match self.state {
+            State::SynSent => {
+                self.state = State::Closed;
+                return;
+            }
+            State::SynRcvd | State::Established => self.state = State::FinWait1,
+            State::CloseWait => self.state = State::LastAck,
+            _ => return,
+        }
+        self.fin_queued = true;
+        self.flush(now, out);
+    }
+
+    /// Processes an incoming segment, RFC 793 "SEGMENT ARRIVES".
+    pub fn on_segment(
+        &mut self,
+        header: &TcpHeader,
+        options: &TcpOptions,
+        payload: &[u8],
+        now: Instant,
+        out: &mut VecDeque<Segment>,
+    ) {
+        self.stats.segs_in += 1;
+        if self.state == State::SynSent {
+            return self.on_syn_sent_segment(header, options, now, out);
+        }
+
+        let flags = &header.flags;
+        let mut seq = header.seq_number;
+        let mut syn = flags.syn;
+
+        // A SYN we have already seen, the peer is missing our answer to it.
+        if syn && seq == self.recv.irs {
+            match self.state {
+                State::SynRcvd if !flags.ack => {
+                    // Our SYN-ACK was lost, send it again.
+                    self.retransmit(now, out);
+                    return;
+                }
+                State::SynRcvd => {
+                    // In a simultaneous open this SYN-ACK answers our SYN, the
+                    // SYN part we already have so only its ACK is new.
+                    seq = seq.wrapping_add(1);
+                    syn = false;
+                }
+                _ => {
+                    self.send_ack(out);
+                    return;
+                }
+            }
+        }
+        let seg_len = payload.len() as u32 + syn as u32 + flags.fin as u32;
+
+        // first check sequence number
+        if !self.is_acceptable(seq, seg_len) {
+            if !flags.rst {
+                self.send_ack(out);
+            }
+            return;
+        }
+
+        // second check the RST bit
+        if flags.rst {
+            self.state = State::Closed;
+            return;
+        }
+
+        // fourth, check the SYN bit. Rather than resetting on a SYN in the window
+        // send a challenge ACK, RFC 5961 section 4, in case the SYN was spoofed.
+        if syn {
+            self.send_ack(out);
+            return;
+        }
+
+        // fifth check the ACK field
+        if !flags.ack {
+            return;
+        }
+        if !self.on_ack(header, options, payload.is_empty(), now, out) {
+            return;
+        }
+        if let Some(subflow) = &mut self.mptcp {
+            match subflow.on_segment(options) {
+                Verdict::Accept => {}
+                Verdict::AckNow => self.send_ack(out),
+                Verdict::Reset => {
+                    self.send_reset(out);
+                    self.state = State::Closed;
+                    return;
+                }
+            }
+        }
+
+        // sixth, check the URG bit
+        if flags.urg
+            && matches!(
+                self.state,
+                State::Established | State::FinWait1 | State::FinWait2
+            )
+        {
+            let up = seq.wrapping_add(header.urgent_pointer as u32);
+            if seq_lt(self.recv.nxt, up) && self.recv_up.is_none_or(|old| seq_lt(old, up)) {
+                self.recv_up = Some(up);
+            }
+        }
+
+        // seventh, process the segment text
+        if !payload.is_empty()
+            && matches!(
+                self.state,
+                State::Established | State::FinWait1 | State::FinWait2
+            )
+        {
+            self.receive(seq, payload);
+            self.tune_recv_buffer(now);
+            if let Some(subflow) = &mut self.mptcp {
+                subflow.on_in_order(self.recv.nxt.wrapping_sub(self.recv.irs));
+            }
+        }
+
+        // eighth, check the FIN bit, only acting on it once everything before it arrived.
+        if flags.fin && seq.wrapping_add(payload.len() as u32) == self.recv.nxt {
+            let consumed = match self.state {
+                State::SynRcvd | State::Established => {
+                    self.state = State::CloseWait;
+                    true
+                }
+                State::FinWait1 => {
+                    // Simultaneous close, both FINs are in flight and ours is
+                    // not yet acknowledged (if it were we'd be in FIN-WAIT-2 by now).
+                    self.state = State::Closing;
+                    true
+                }
+                State::FinWait2 | State::TimeWait => {
+                    self.enter_time_wait(now);
+                    true
+                }
+                _ => false,
+            };
+            if consumed {
+                self.recv.nxt = self.recv.nxt.wrapping_add(1);
+            }
+        }
+
+        if seg_len > 0 {
+            // Piggyback the ACK on data if there is any to go out.
+            let before = out.len();
+            self.flush(now, out);
+            if out.len() == before {
+                self.send_ack(out);
+            }
+        } else {
+            self.flush(now, out);
+        }
+    }
+
+    /// RFC 793 "SEGMENT ARRIVES" in the SYN-SENT state.
+    fn on_syn_sent_segment(
+        &mut self,
+        header: &TcpHeader,
+        options: &TcpOptions,
+        now: Instant,
+        out: &mut VecDeque<Segment>,
+    ) {
+        let flags = &header.flags;
+        let ack = header.ack_number;
+
+        // first check the ACK bit
+        let ack_acceptable = flags.ack && seq_lt(self.send.iss, ack) && seq_le(ack, self.send.nxt);
+        if flags.ack && !ack_acceptable {
+            if !flags.rst {
+                self.send_reset_for(ack, out);
+            }
+            return;
+        }
+
+        // second check the RST bit
+        if flags.rst {
+            if ack_acceptable {
+                self.state = State::Closed;
+            }
+            return;
+        }
+
+        // fourth check the SYN bit
+        if !flags.syn {
+            return;
+        }
+        self.on_peer_syn(header, options);
+
+        if ack_acceptable {
+            if self
+                .mptcp
+                .as_mut()
+                .is_some_and(|subflow| !subflow.on_syn_ack(options))
+            {
+                self.send_reset_for(ack, out);
+                self.state = State::Closed;
+                return;
+            }
+            self.state = State::Established;
+            self.on_new_ack(ack, now, out);
+            self.send.wl1 = header.seq_number;
+            self.send.wl2 = ack;
+            if self.send.una != self.send.nxt {
+                // The server did not take the data in our SYN, send it again
+                // now rather than waiting for the retransmission timer.
+                self.send.nxt = self.send.una;
+            }
+            self.send_ack(out);
+            self.flush(now, out);
+        } else {
+            // Simultaneous open, the peer's SYN crossed ours. Answer with a
+            // SYN-ACK reusing our ISN and wait in SYN-RECEIVED for its ACK.
+            self.state = State::SynRcvd;
+            self.send_syn(now, out);
+        }
+    }
+
+    /// Takes the peer's ISN, window and MSS from its SYN.
+    fn on_peer_syn(&mut self, header: &TcpHeader, options: &TcpOptions) {
+        self.recv.irs = header.seq_number;
+        self.recv.nxt = header.seq_number.wrapping_add(1);
+        self.send.wnd = header.window_size as u32;
+        self.send.wl1 = header.seq_number;
+        self.mss = options.mss.unwrap_or(DEFAULT_MSS).clamp(MIN_MSS, LOCAL_MSS);
+        self.cwnd = self.initial_window();
+
+        // An active open asked for scaling in its SYN already, a passive one only
+        // answers with it, either way it's on only if the peer asked for it too.
+        self.window_scale = match options.window_scale {
+            Some(shift) => WindowScale {
+                snd: shift.min(MAX_WINDOW_SCALE),
+                rcv: WINDOW_SCALE,
+            },
+            None => WindowScale::default(),
+        };
+    }
+
+    /// The window a segment advertises, scaled unless it is a SYN, RFC 7323 section 2.2.
+    fn peer_window(&self, header: &TcpHeader) -> u32 {
+        match header.flags.syn {
+            true => header.window_size as u32,
+            false => (header.window_size as u32) << self.window_scale.snd,
+        }
+    }
+
+    /// The peer's host is unreachable, a connection still opening gives up. See
+    /// `Connections::on_host_unreachable`.
+    pub fn on_host_unreachable(&mut self) {
+        println!(
+            "[TCP] {:?} host unreachable in {:?}",
+            self.quad.remote, self.state
+        );
+        if matches!(self.state, State::SynSent | State::SynRcvd) {
+            self.state = State::Closed;
+        }
+    }
+
+    /// Runs the retransmission and TIME-WAIT timers.
+    pub fn on_tick(&mut self, now: Instant, out: &mut VecDeque<Segment>) {
+        if let Some(until) = self.time_wait_until {
+            if until <= now {
+                self.state = State::Closed;
+            }
+            return;
+        }
+
+        match self.retransmit_at {
+            Some(at) if at <= now => {}
+            _ => return,
+        }
+
+        self.retransmits += 1;
+        if self.retransmits > MAX_RETRANSMITS {
+            self.send_reset(out);
+            self.state = State::Closed;
+            return;
+        }
+
+        // RFC 5681 section 3.1, after a timeout fall back to one segment.
+        self.ssthresh = (self.flight_size() / 2).max(2 * self.mss as u32);
+        self.cwnd = self.mss as u32;
+        self.recovery = None;
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        self.retransmit(now, out);
+    }
+
+    /// Whether the segment overlaps our receive window, RFC 793 page 69.
+    fn is_acceptable(&self, seq: u32, seg_len: u32) -> bool {
+        let wnd = self.recv_window() as u32;
+        let nxt = self.recv.nxt;
+        let end = nxt.wrapping_add(wnd);
+        let in_window = |s: u32| seq_le(nxt, s) && seq_lt(s, end);
+
+        match (seg_len, wnd) {
+            (0, 0) => seq == nxt,
+            (0, _) => in_window(seq),
+            (_, 0) => false,
+            (_, _) => in_window(seq) || in_window(seq.wrapping_add(seg_len - 1)),
+        }
+    }
+
+    /// Handles the acknowledgment field, returning false if the segment should be dropped.
+    fn on_ack(
+        &mut self,
+        header: &TcpHeader,
+        options: &TcpOptions,
+        is_pure_ack: bool,
+        now: Instant,
+        out: &mut VecDeque<Segment>,
+    ) -> bool {
+        let ack = header.ack_number;
+        self.stats.sack_blocks_in += options.sack_blocks.len() as u32;
+
+        if self.state == State::SynRcvd {
+            if seq_lt(self.send.una, ack) && seq_le(ack, self.send.nxt) {
+                self.state = State::Established;
+            } else {
+                self.send_reset_for(ack, out);
+                return false;
+            }
+        }
+
+        let fin_outstanding = self.fin_sent();
+        if seq_lt(self.send.una, ack) && seq_le(ack, self.send.nxt) {
+            self.on_new_ack(ack, now, out);
+        } else if seq_lt(self.send.nxt, ack) {
+            // Acknowledges something not yet sent.
+            self.send_ack(out);
+            return false;
+        } else if ack == self.send.una
+            && is_pure_ack
+            && self.send.una != self.send.nxt
+            && self.peer_window(header) == self.send.wnd
+        {
+            self.on_dup_ack(now, out);
+        }
+
+        // Update the send window if this segment is newer than the one that last set it.
+        let seq = header.seq_number;
+        if seq_lt(self.send.wl1, seq) || (self.send.wl1 == seq && seq_le(self.send.wl2, ack)) {
+            self.send.wnd = self.peer_window(header);
+            self.send.wl1 = seq;
+            self.send.wl2 = ack;
+        }
+
+        if fin_outstanding && self.send.una == self.send.nxt {
+            // Our FIN has been acknowledged.
+            match self.state {
+                State::FinWait1 => self.state = State::FinWait2,
+                State::Closing => self.enter_time_wait(now),
+                State::LastAck => {
+                    self.state = State::Closed;
+                    return false;
+                }
+                _ => {}
+            }
+        }
+        true
+    }
+
+    fn on_new_ack(&mut self, ack: u32, now: Instant, out: &mut VecDeque<Segment>) {
+        let mut acked = ack.wrapping_sub(self.send.una);
+        if !self.syn_acked {
+            // Our SYN takes up a sequence number but is not data.
+            self.syn_acked = true;
+            acked -= 1;
+        }
+        let data_acked = (acked as usize).min(self.unacked.len());
+        self.unacked.drain(..data_acked);
+        self.stats.bytes_acked += data_acked as u64;
+        self.send.una = ack;
+        if let Some(subflow) = &mut self.mptcp {
+            subflow.on_acked(ack.wrapping_sub(self.send.iss));
+        }
+        self.retransmits = 0;
+        if self.send_up.is_some_and(|up| seq_le(up, ack)) {
+            self.send_up = None;
+        }
+        self.dup_acks = 0;
+
+        if let Some((end, sent_at)) = self.rtt_sample {
+            if seq_le(end, ack) {
+                self.update_rtt(now - sent_at);
+                self.rtt_sample = None;
+            }
+        }
+
+        match self.recovery {
+            Some(recover) if seq_lt(ack, recover) => {
+                // NewReno partial ACK, the next hole was lost too.
+                self.retransmit(now, out);
+            }
+            Some(_) => {
+                self.cwnd = self.ssthresh;
+                self.recovery = None;
+            }
+            None if self.cwnd < self.ssthresh => {
+                // slow start
+                self.cwnd += (data_acked as u32).min(self.mss as u32);
+            }
+            None => {
+                // congestion avoidance
+                let mss = self.mss as u32;
+                self.cwnd += (mss * mss / self.cwnd.max(1)).max(1);
+            }
+        }
+
+        self.retransmit_at = if self.send.una == self.send.nxt {
+            None
+        } else {
+            Some(now + self.rto)
+        };
+    }
+
+    fn on_dup_ack(&mut self, now: Instant, out: &mut VecDeque<Segment>) {
+        self.stats.dup_acks_in += 1;
+        self.dup_acks += 1;
+
+        if self.recovery.is_some() {
+            // Each further duplicate means a segment has left the network.
+            self.cwnd += self.mss as u32;
+        } else if self.dup_acks == DUP_ACK_THRESHOLD {
+            // fast retransmit, RFC 5681 section 3.2
+            self.ssthresh = (self.flight_size() / 2).max(2 * self.mss as u32);
+            self.cwnd = self.ssthresh + DUP_ACK_THRESHOLD * self.mss as u32;
+            self.recovery = Some(self.send.nxt);
+            self.retransmit(now, out);
+        }
+    }
+
+    /// RFC 6298 section 2.
+    fn update_rtt(&mut self, r: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(r);
+                self.rttvar = r / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(r);
+                self.rttvar = self.rttvar * 3 / 4 + delta / 4;
+                self.srtt = Some(srtt * 7 / 8 + r / 8);
+            }
+        }
+        let srtt = self.srtt.unwrap_or(r);
+        self.rto = (srtt + CLOCK_GRANULARITY.max(self.rttvar * 4)).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// Accepts segment text into the receive buffer, holding on to anything that arrived early.
+    fn receive(&mut self, seq: u32, payload: &[u8]) {
+        // Trim anything we already have off the front.
+        let skip = self.recv.nxt.wrapping_sub(seq) as usize;
+        if seq_lt(seq, self.recv.nxt) {
+            if skip >= payload.len() {
+                return;
+            }
+            return self.receive(self.recv.nxt, &payload[skip..]);
+        }
+
+        if seq != self.recv.nxt {
+            self.stats.reordering += 1;
+            self.out_of_order.insert(seq, payload.to_vec());
+            return;
+        }
+
+        let len = payload.len().min(self.recv_window());
+        self.incoming.extend(&payload[..len]);
+        self.stats.bytes_received += len as u64;
+        self.recv.nxt = self.recv.nxt.wrapping_add(len as u32);
+
+        if let Some(up) = self.recv_up {
+            let last_urgent = up.wrapping_sub(1);
+            if seq_le(seq, last_urgent) && seq_lt(last_urgent, self.recv.nxt) {
+                let at = self.incoming.len() - len + last_urgent.wrapping_sub(seq) as usize;
+                if !self.urgent_inline {
+                    self.urgent_data = self.incoming.remove(at);
+                }
+                self.urgent_mark = Some(at);
+                self.recv_up = None;
+            }
+        }
+
+        // Anything held back may now follow on.
+        while let Some((&early, _)) = self.out_of_order.iter().next() {
+            if seq_lt(self.recv.nxt, early) {
+                break;
+            }
+            let data = self.out_of_order.remove(&early).unwrap_or_default();
+            if seq_lt(self.recv.nxt, early.wrapping_add(data.len() as u32)) {
+                self.receive(early, &data);
+            }
+        }
+    }
+
+    fn enter_time_wait(&mut self, now: Instant) {
+        self.state = State::TimeWait;
+        self.retransmit_at = None;
+        self.time_wait_until = Some(now + 2 * MSL);
+    }
+
+    /// Sends as much queued data as the send and congestion windows allow, then the FIN.
+    fn flush(&mut self, now: Instant, out: &mut VecDeque<Segment>) {
+        let may_send = match self.state {
+            State::Established | State::CloseWait | State::FinWait1 | State::LastAck => {
+                self.syn_acked
+            }
+            // A Fast Open server may answer before the handshake completes.
+            State::SynRcvd => self.fast_open,
+            _ => false,
+        };
+        if !may_send {
+            return;
+        }
+
+        loop {
+            if self.fin_sent() {
+                return;
+            }
+            let sent = self.send.nxt.wrapping_sub(self.data_start()) as usize;
+            let window = self.send.wnd.min(self.cwnd) as usize;
+            let in_flight = self.flight_size() as usize;
+            let len = (self.unacked.len() - sent)
+                .min(window.saturating_sub(in_flight))
+                .min(self.segment_size());
+            let fin = self.fin_queued && sent + len == self.unacked.len();
+            if len == 0 && !fin {
+                return;
+            }
+
+            let payload: Vec<u8> = self.unacked.range(sent..sent + len).copied().collect();
+            let mut flags = TcpHeaderFlags::new();
+            flags.ack = true;
+            flags.psh = len > 0 && sent + len == self.unacked.len();
+            flags.fin = fin;
+            let seq = self.send.nxt;
+            self.send.nxt = seq.wrapping_add(len as u32 + fin as u32);
+            self.stats.bytes_sent += len as u64;
+
+            if self.rtt_sample.is_none() {
+                self.rtt_sample = Some((self.send.nxt, now));
+            }
+            if self.retransmit_at.is_none() {
+                self.retransmit_at = Some(now + self.rto);
+            }
+            out.push_back(self.segment(seq, flags, &payload));
+
+            if fin {
+                return;
+            }
+        }
+    }
+
+    /// Resends the oldest unacknowledged segment.
+    fn retransmit(&mut self, now: Instant, out: &mut VecDeque<Segment>) {
+        // Karn's algorithm, retransmitted segments give ambiguous samples.
+        self.rtt_sample = None;
+        self.stats.total_retrans += 1;
+        self.retransmit_at = Some(now + self.rto);
+
+        if !self.syn_acked {
+            if self.state == State::SynSent {
+                // Maybe the SYN was dropped for its data or Fast Open option,
+                // retry with a plain one and send the data after the handshake.
+                self.fast_open = false;
+                self.syn_cookie = None;
+            }
+            self.send_syn(now, out);
+            return;
+        }
+
+        let len = self.unacked.len().min(self.segment_size());
+        let fin = self.fin_sent() && len == self.unacked.len();
+        let payload: Vec<u8> = self.unacked.range(..len).copied().collect();
+        let mut flags = TcpHeaderFlags::new();
+        flags.ack = true;
+        flags.fin = fin;
+        self.stats.bytes_sent += len as u64;
+        self.stats.bytes_retrans += len as u64;
+        out.push_back(self.segment(self.send.una, flags, &payload));
+    }
+
+    fn send_syn(&mut self, now: Instant, out: &mut VecDeque<Segment>) {
+        let mut flags = TcpHeaderFlags::new();
+        flags.syn = true;
+        flags.ack = self.state != State::SynSent;
+        let asks_scaling = self.state == State::SynSent || self.window_scale.rcv != 0;
+        let options = TcpOptions {
+            mss: Some(LOCAL_MSS),
+            window_scale: Some(WINDOW_SCALE).filter(|_| asks_scaling),
+            fast_open: self.syn_cookie.clone(),
+            ..TcpOptions::default()
+        };
+
+        // A Fast Open client's data rides on the SYN, up to the MSS every host accepts.
+        let len = if self.fast_open && self.state == State::SynSent {
+            self.unacked.len().min(DEFAULT_MSS as usize)
+        } else {
+            0
+        };
+        let payload: Vec<u8> = self.unacked.range(..len).copied().collect();
+        self.stats.bytes_sent += len as u64;
+
+        self.send.nxt = self.send.iss.wrapping_add(1 + len as u32);
+        if self.rtt_sample.is_none() && self.stats.total_retrans == 0 {
+            self.rtt_sample = Some((self.send.nxt, now));
+        }
+        if self.retransmit_at.is_none() {
+            self.retransmit_at = Some(now + self.rto);
+        }
+        let segment = self.segment_with(self.send.iss, flags, &options, &payload);
+        out.push_back(segment);
+    }
+
+    fn send_ack(&mut self, out: &mut VecDeque<Segment>) {
+        let mut flags = TcpHeaderFlags::new();
+        flags.ack = true;
+        out.push_back(self.segment(self.send.nxt, flags, &[]));
+    }
+
+    fn send_reset(&mut self, out: &mut VecDeque<Segment>) {
+        let mut flags = TcpHeaderFlags::new();
+        flags.rst = true;
+        out.push_back(self.segment(self.send.nxt, flags, &[]));
+    }
+
+    /// A reset for an unacceptable ACK, taking its sequence number from that ACK.
+    fn send_reset_for(&mut self, ack: u32, out: &mut VecDeque<Segment>) {
+        let mut flags = TcpHeaderFlags::new();
+        flags.rst = true;
+        out.push_back(self.segment(ack, flags, &[]));
+    }
+
+    fn segment(&mut self, seq: u32, flags: TcpHeaderFlags, payload: &[u8]) -> Segment {
+        self.segment_with(seq, flags, &TcpOptions::default(), payload)
+    }
+
+    fn segment_with(
+        &mut self,
+        seq: u32,
+        flags: TcpHeaderFlags,
+        options: &TcpOptions,
+        payload: &[u8],
+    ) -> Segment {
+        self.stats.segs_out += 1;
+        let rel_seq = seq.wrapping_sub(self.send.iss);
+        let mptcp = match &mut self.mptcp {
+            Some(subflow) if !flags.rst => {
+                subflow.option(flags.syn, flags.ack, rel_seq, payload.len())
+            }
+            _ => None,
+        };
+        let ack = if flags.ack { self.recv.nxt } else { 0 };
+        let window = match flags.syn {
+            true => self.recv_window(),
+            false => self.recv_window() >> self.window_scale.rcv,
+        };
+        let window = window.min(u16::MAX as usize) as u16;
+        let mut header = TcpHeader::new(seq, ack, flags, window);
+        if let Some(up) = self.send_up {
+            // Every segment carries the urgent pointer until the urgent data is acknowledged.
+            if seq_lt(seq, up) {
+                header.flags.urg = true;
+                header.urgent_pointer = up.wrapping_sub(seq).min(u16::MAX as u32) as u16;
+            }
+        }
+        if mptcp.is_some() {
+            let options = TcpOptions {
+                mptcp,
+                ..options.clone()
+            };
+            return build_segment(
+                self.quad,
+                header,
+                &options,
+                payload,
+                self.md5_key.as_deref(),
+            );
+        }
+        build_segment(self.quad, header, options, payload, self.md5_key.as_deref())
+    }
+
+    /// The most data one segment carries, the MSS less room for the options that
+    /// unlike the others are on every segment, an MD5 signature or MPTCP's DSS.
+    /// Never 0, so sending always gets somewhere.
+    fn segment_size(&self) -> usize {
+        let every_segment = if self.md5_key.is_some() {
+            20
+        } else if self
+            .mptcp
+            .as_ref()
+            .is_some_and(|subflow| !subflow.is_fallback())
+        {
+            DSS_SPACE
+        } else {
+            0
+        };
+        (self.mss as usize).saturating_sub(every_segment).max(1)
+    }
+
+    /// Free space in the receive buffer.
+    fn recv_window(&self) -> usize {
+        self.recv_buffer.saturating_sub(self.incoming.len())
+    }
+
+    /// Receive buffer autotuning, after Linux's dynamic right-sizing. Once a round
+    /// trip, compare what arrived during it with the buffer and ask for twice that,
+    /// so the window keeps ahead of the bandwidth-delay product and the sender is
+    /// never held back by it. Without window scaling there is nothing to gain.
+    fn tune_recv_buffer(&mut self, now: Instant) {
+        let rtt = match self.srtt {
+            Some(rtt) => rtt,
+            None => return,
+        };
+        let (started, seq) = *self.recv_measure.get_or_insert((now, self.recv.nxt));
+        if now.duration_since(started) < rtt {
+            return;
+        }
+        self.recv_measure = Some((now, self.recv.nxt));
+
+        let received = self.recv.nxt.wrapping_sub(seq) as usize;
+        let largest = (u16::MAX as usize) << self.window_scale.rcv;
+        let wanted = (2 * received).min(MAX_RECV_BUFFER_SIZE).min(largest);
+        if wanted > self.recv_buffer {
+            self.recv_buffer_wanted = Some(wanted);
+        }
+    }
+
+    /// Our FIN has gone out, whether or not it has been acknowledged.
+    fn fin_sent(&self) -> bool {
+        self.fin_queued
+            && self.send.nxt.wrapping_sub(self.data_start()) as usize > self.unacked.len()
+    }
+
+    /// The sequence number of the first byte in `unacked`, after our SYN.
+    fn data_start(&self) -> u32 {
+        if self.syn_acked {
+            self.send.una
+        } else {
+            self.send.iss.wrapping_add(1)
+        }
+    }
+
+    fn flight_size(&self) -> u32 {
+        self.send.nxt.wrapping_sub(self.send.una)
+    }
+
+    /// RFC 5681 section 3.1.
+    fn initial_window(&self) -> u32 {
+        let mss = self.mss as u32;
+        (4 * mss).min((2 * mss).max(4380))
+    }
+}
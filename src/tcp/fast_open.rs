@@ -0,0 +1,98 @@
+// TCP Fast Open, RFC 7413.
+//
+// A client that holds a cookie from an earlier connection to a server may send
+// data in its SYN, which the server hands to the application straight away
+// if the cookie checks out. Clients without a cookie ask for one with an empty
+// cookie option and send their data after the handshake as usual.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+
+use super::options::TcpOptions;
+
+/// Fast Open state shared by every connection, the server's cookie secret and
+/// the client's cookie cache.
+pub struct FastOpen {
+    /// Whether we accept data in SYNs and hand out cookies.
+    server_enabled: bool,
+
+    /// Keys the hash cookies are generated with.
+    secret: RandomState,
+
+    /// Cookies servers have given us, by server address.
+    cookies: HashMap<u32, Vec<u8>>,
+}
+
+/// What to do with a SYN received on a server, see `FastOpen::on_syn`.
+pub struct FastOpenSyn<'a> {
+    /// The SYN's data, if its cookie was valid.
+    pub data: Option<&'a [u8]>,
+
+    /// A cookie to send back in the SYN-ACK.
+    pub cookie: Option<Vec<u8>>,
+}
+
+impl Default for FastOpen {
+    fn default() -> Self {
+        FastOpen::new()
+    }
+}
+
+impl FastOpen {
+    pub fn new() -> Self {
+        FastOpen {
+            server_enabled: false,
+            secret: RandomState::new(),
+            cookies: HashMap::new(),
+        }
+    }
+
+    pub fn set_server_enabled(&mut self, enabled: bool) {
+        self.server_enabled = enabled;
+    }
+
+    /// The cookie for a client address, 8 bytes of keyed hash.
+    pub fn cookie_for(&self, client_ip: u32) -> Vec<u8> {
+        self.secret.hash_one(client_ip).to_be_bytes().to_vec()
+    }
+
+    /// Decides what a server does with a SYN. Data is only accepted with a valid
+    /// cookie; a cookie request, or an invalid cookie, is answered with a fresh one.
+    pub fn on_syn<'a>(
+        &self,
+        client_ip: u32,
+        options: &TcpOptions,
+        payload: &'a [u8],
+    ) -> FastOpenSyn<'a> {
+        let mut syn = FastOpenSyn {
+            data: None,
+            cookie: None,
+        };
+        let presented = match (&options.fast_open, self.server_enabled) {
+            (Some(cookie), true) => cookie,
+            _ => return syn,
+        };
+
+        let cookie = self.cookie_for(client_ip);
+        if *presented == cookie {
+            syn.data = Some(payload).filter(|data| !data.is_empty());
+        } else {
+            syn.cookie = Some(cookie);
+        }
+        syn
+    }
+
+    /// The cookie cached for a server, if we have one.
+    pub fn cached_cookie(&self, server_ip: u32) -> Option<&[u8]> {
+        self.cookies.get(&server_ip).map(|c| c.as_slice())
+    }
+
+    /// Remembers a cookie a server sent in its SYN-ACK. RFC 7413 allows 4 to 16
+    /// bytes, anything else is ignored.
+    pub fn cache_cookie(&mut self, server_ip: u32, cookie: &[u8]) {
+        if (4..=16).contains(&cookie.len()) && cookie.len().is_multiple_of(2) {
+            self.cookies.insert(server_ip, cookie.to_vec());
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use std::fmt;
+use std::time::Duration;
+
+use super::State;
+
+/// Counters kept for the lifetime of a connection.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(super) struct Stats {
+    pub segs_in: u64,
+    pub segs_out: u64,
+    pub bytes_sent: u64,
+    pub bytes_retrans: u64,
+    pub bytes_acked: u64,
+    pub bytes_received: u64,
+    pub total_retrans: u32,
+    pub dup_acks_in: u32,
+    pub sack_blocks_in: u32,
+    pub reordering: u32,
+}
+
+/// A snapshot of a connection, holding roughly what `ss -ti` shows for a socket.
+/// Taken with `Connections::info`, so it does not change as the connection does.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TcpInfo {
+    pub state: State,
+
+    /// The MSS we send with, ie. the smaller of the peer's advertised MSS and ours.
+    pub mss: u16,
+
+    /// Congestion window and slow start threshold, in bytes.
+    pub cwnd: u32,
+    pub ssthresh: u32,
+
+    /// Smoothed round trip time and its variance, None until the first sample.
+    pub srtt: Option<Duration>,
+    pub rttvar: Duration,
+
+    /// The current retransmission timeout, including any backoff.
+    pub rto: Duration,
+
+    /// The peer's advertised window and the window we last advertised.
+    pub snd_wnd: u32,
+    pub rcv_wnd: u32,
+
+    /// Receive buffer size, as autotuning has grown it.
+    pub rcv_buf: u32,
+
+    /// Window scale shifts as (the peer's, ours), if in use.
+    pub wscale: Option<(u8, u8)>,
+
+    /// Bytes sent and not yet acknowledged.
+    pub unacked: u32,
+
+    pub segs_in: u64,
+    pub segs_out: u64,
+
+    /// Payload bytes sent, including retransmissions.
+    pub bytes_sent: u64,
+    pub bytes_retrans: u64,
+    pub bytes_acked: u64,
+    pub bytes_received: u64,
+
+    /// Retransmission timeouts since the last forward progress.
+    pub retransmits: u32,
+
+    /// Segments retransmitted over the lifetime of the connection, by timeout or fast retransmit.
+    pub total_retrans: u32,
+
+    /// Duplicate ACKs received.
+    pub dup_acks_in: u32,
+
+    /// SACK blocks received from the peer.
+    pub sack_blocks_in: u32,
+
+    /// Segments received ahead of the next expected sequence number.
+    pub reordering: u32,
+}
+
+impl fmt::Display for TcpInfo {
+    /// Formats the snapshot in the style of `ss -ti`, times in milliseconds.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+
+        write!(f, "{:?}", self.state)?;
+        if let Some((snd, rcv)) = self.wscale {
+            write!(f, " wscale:{},{}", snd, rcv)?;
+        }
+        write!(f, " mss:{} cwnd:{}", self.mss, self.cwnd)?;
+        if self.ssthresh != u32::MAX {
+            write!(f, " ssthresh:{}", self.ssthresh)?;
+        }
+        if let Some(srtt) = self.srtt {
+            write!(f, " rtt:{:.3}/{:.3}", ms(srtt), ms(self.rttvar))?;
+        }
+        write!(
+            f,
+            " rto:{:.0} snd_wnd:{} rcv_wnd:{} rcv_buf:{} unacked:{} segs_in:{} segs_out:{} \
+             bytes_sent:{} bytes_retrans:{} bytes_acked:{} bytes_received:{} \
+             retrans:{}/{} dup_acks:{} sacks:{} reordering:{}",
+            ms(self.rto),
+            self.snd_wnd,
+            self.rcv_wnd,
+            self.rcv_buf,
+            self.unacked,
+            self.segs_in,
+            self.segs_out,
+            self.bytes_sent,
+            self.bytes_retrans,
+            self.bytes_acked,
+            self.bytes_received,
+            self.retransmits,
+            self.total_retrans,
+            self.dup_acks_in,
+            self.sack_blocks_in,
+            self.reordering,
+        )
+    }
+}
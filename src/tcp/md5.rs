@@ -0,0 +1,104 @@
+// TCP MD5 signatures, RFC 2385, along with the MD5 they need (RFC 1321).
+// BGP peers still insist on these, so we can't get away without them.
+
+/// Per-round shift amounts.
+const S: [u32; 64] = [
+    7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9,
+    14, 20, 5, 9, 14, 20, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10, 15,
+    21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+];
+
+/// floor(abs(sin(i + 1)) * 2^32)
+const K: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+/// MD5 of the concatenation of `parts`.
+pub fn digest(parts: &[&[u8]]) -> [u8; 16] {
+    let mut message: Vec<u8> = parts.concat();
+    let bit_len = (message.len() as u64).wrapping_mul(8);
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&bit_len.to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for chunk in message.chunks(64) {
+        let mut m = [0u32; 16];
+        for (word, bytes) in m.iter_mut().zip(chunk.chunks(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let f = f.wrapping_add(a).wrapping_add(K[i]).wrapping_add(m[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(S[i]));
+        }
+
+        state[0] = state[0].wrapping_add(a);
+        state[1] = state[1].wrapping_add(b);
+        state[2] = state[2].wrapping_add(c);
+        state[3] = state[3].wrapping_add(d);
+    }
+
+    let mut ret = [0u8; 16];
+    for (out, word) in ret.chunks_mut(4).zip(state.iter()) {
+        out.clone_from_slice(&word.to_le_bytes());
+    }
+    ret
+}
+
+/// The RFC 2385 signature of a segment: MD5 over the pseudo-header, the fixed
+/// 20 bytes of the TCP header with a zero checksum, the segment data and the key.
+/// Options, including the signature option itself, are left out.
+pub fn segment_signature(source_ip: u32, dest_ip: u32, segment: &[u8], key: &[u8]) -> [u8; 16] {
+    let mut pseudo_header = [0u8; 12];
+    pseudo_header[0..4].clone_from_slice(&source_ip.to_be_bytes());
+    pseudo_header[4..8].clone_from_slice(&dest_ip.to_be_bytes());
+    pseudo_header[9] = crate::ipv4::ProtoType::to_u8(&Some(crate::ipv4::ProtoType::TCP));
+    pseudo_header[10..12].clone_from_slice(&(segment.len() as u16).to_be_bytes());
+
+    let mut header = [0u8; 20];
+    header.clone_from_slice(&segment[..20]);
+    header[16] = 0;
+    header[17] = 0;
+
+    let data_offset = (segment[12] >> 4) as usize * 4;
+    digest(&[&pseudo_header, &header, &segment[data_offset..], key])
+}
+
+#[cfg(test)]
+#[test]
+fn test_digest() {
+    assert_eq!(
+        digest(&[b"The quick brown fox ", b"jumps over the lazy dog"]),
+        [
+            0x9e, 0x10, 0x7d, 0x9d, 0x37, 0x2b, 0xb6, 0x82, 0x6b, 0xd8, 0x1d, 0x35, 0x42, 0xa4,
+            0x19, 0xd6
+        ]
+    );
+    assert_eq!(
+        digest(&[]),
+        [
+            0xd4, 0x1d, 0x8c, 0xd9, 0x8f, 0x00, 0xb2, 0x04, 0xe9, 0x80, 0x09, 0x98, 0xec, 0xf8,
+            0x42, 0x7e
+        ]
+    );
+}
@@ -1,6 +1,27 @@
-use std::convert::TryInto;
+mod connection;
+mod fast_open;
+mod info;
+mod md5;
+mod mptcp;
+mod options;
+mod repair;
+mod sha256;
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::BuildHasher;
+use std::io;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::ports::{Endpoint, Ports};
+
+use self::connection::Connection;
+use self::fast_open::FastOpen;
+pub use self::info::TcpInfo;
+use self::mptcp::Mptcp;
+pub use self::options::TcpOptions;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
 pub struct TcpHeaderFlags {
     /// robustness protection, not used much afaik, see RFC https://tools.ietf.org/html/rfc3540
     ns: bool,
@@ -125,6 +146,23 @@ pub struct TcpHeader {
 }
 
 impl TcpHeader {
+    /// A header with no options or urgent data, for `build_segment` to fill in the rest of.
+    fn new(seq_number: u32, ack_number: u32, flags: TcpHeaderFlags, window_size: u16) -> Self {
+        TcpHeader {
+            src_port: 0,
+            dst_port: 0,
+            seq_number,
+            ack_number,
+            data_offset: 20,
+            reserved: 0,
+            flags,
+            window_size,
+            checksum: 0,
+            urgent_pointer: 0,
+            options: [0u8; 40],
+        }
+    }
+
     // TODO parse packet!!
     pub fn from_slice(slice: &TcpPacketSlice) -> Self {
         TcpHeader {
@@ -148,12 +186,7 @@ impl TcpHeader {
         ret[4..8].clone_from_slice(&u32::to_be_bytes(self.seq_number));
         ret[8..12].clone_from_slice(&u32::to_be_bytes(self.ack_number));
         let flags_u8 = self.flags.to_u8();
-        println!(
-            "data offset pre: {} post: {}",
-            self.data_offset,
-            self.data_offset << 4
-        );
-        ret[12] = (0b10100000) | flags_u8[0];
+        ret[12] = ((self.data_offset / 4) << 4) | flags_u8[0];
         ret[13] = flags_u8[1];
         ret[14..16].clone_from_slice(&u16::to_be_bytes(self.window_size));
         // dumb implementatio, assume caller handles recalcuation, awkard here.
@@ -162,12 +195,6 @@ impl TcpHeader {
         ret[20..60].clone_from_slice(&self.options);
         ret
     }
-    fn flip_sd(&mut self) {
-        let new_dst = self.src_port;
-        let new_src = self.dst_port;
-        self.dst_port = new_dst;
-        self.src_port = new_src;
-    }
 }
 
 /// A slice containing an TCP Packet.
@@ -198,7 +225,7 @@ impl<'a> TcpPacketSlice<'a> {
         let mut ret = self.slice[12] & 0xf0;
         ret >>= 4;
         ret *= 4;
-        assert!(ret >= 20 && ret <= 60);
+        assert!((20..=60).contains(&ret));
         ret
     }
 
@@ -238,7 +265,6 @@ impl<'a> TcpPacketSlice<'a> {
             flags.fin = true;
         }
 
-        println!("Flags Found: {:?}", flags);
         flags
     }
 
@@ -258,15 +284,8 @@ impl<'a> TcpPacketSlice<'a> {
     /// We can len check the actual data via the data offset field
     pub fn options(&self) -> [u8; 40] {
         let data_max = self.data_offset() as usize - 20;
-        println!("data max: {}", data_max);
         let mut ret = [0u8; 40];
-        println!(
-            "selfslicelen: {} - ret len: {}",
-            &self.slice[20..data_max + 20].len(),
-            ret[..data_max].len(),
-        );
         ret[..data_max].clone_from_slice(&self.slice[20..data_max + 20]);
-        println!("Options extracted: {:X?}", ret);
         ret
     }
 
@@ -275,65 +294,1211 @@ impl<'a> TcpPacketSlice<'a> {
     }
 }
 
-fn tcp_checksum(tcp_packet: &TcpPacketSlice, ipv4_packet: &crate::ipv4::IPv4Packet) -> u16 {
-    let data = tcp_packet.slice;
-    // Create new data slice with psuedo ip header attached.
-    let mut psuedo_header = [0u8; 12];
-    psuedo_header[0..4].clone_from_slice(&u32::to_be_bytes(ipv4_packet.source_ip));
-    psuedo_header[4..8].clone_from_slice(&u32::to_be_bytes(ipv4_packet.dest_ip));
-    psuedo_header[8..10].clone_from_slice(&u16::to_be_bytes(ipv4_packet.total_len));
-    psuedo_header[10] = crate::ipv4::ProtoType::to_u8(&ipv4_packet.protocol);
-    crate::ipv4::checksum(&[&psuedo_header, data].concat())
+/// Connection states, RFC 793 section 3.2.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum State {
+    Listen,
+    SynSent,
+    SynRcvd,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+/// Identifies a connection by its (address, port) pairs, from our point of view.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct Quad {
+    pub local: (u32, u16),
+    pub remote: (u32, u16),
+}
+
+/// A TCP segment ready to be put in an IPv4 packet, checksum included.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Segment {
+    pub quad: Quad,
+    pub data: Vec<u8>,
+}
+
+/// Sequence number comparisons modulo 2^32, RFC 793 section 3.3.
+fn seq_lt(lhs: u32, rhs: u32) -> bool {
+    (lhs.wrapping_sub(rhs) as i32) < 0
+}
+
+fn seq_le(lhs: u32, rhs: u32) -> bool {
+    lhs == rhs || seq_lt(lhs, rhs)
+}
+
+/// The checksum of a segment over the IPv4 pseudo-header, RFC 793 section 3.1.
+/// Checking a received segment (checksum field included) gives 0.
+fn segment_checksum(source_ip: u32, dest_ip: u32, segment: &[u8]) -> u16 {
+    crate::ipv4::pseudo_header_checksum(source_ip, dest_ip, crate::ipv4::ProtoType::TCP, segment)
+}
+
+/// Builds a segment from `quad.local` to `quad.remote`, filling in the header's
+/// ports, options and checksum. With an MD5 key the segment is signed too.
+fn build_segment(
+    quad: Quad,
+    mut header: TcpHeader,
+    options: &TcpOptions,
+    payload: &[u8],
+    md5_key: Option<&[u8]>,
+) -> Segment {
+    let options_len = match md5_key {
+        // Room for the signature, which goes first so it is always at offset 20.
+        Some(_) => {
+            let mut options = options.clone();
+            options.md5 = Some([0; 16]);
+            options.write(&mut header.options)
+        }
+        None => options.write(&mut header.options),
+    };
+    header.src_port = quad.local.1;
+    header.dst_port = quad.remote.1;
+    header.data_offset = 20 + options_len as u8;
+    header.checksum = 0;
+
+    let mut data = header.to_slice()[..header.data_offset as usize].to_vec();
+    data.extend_from_slice(payload);
+    if let Some(key) = md5_key {
+        let signature = md5::segment_signature(quad.local.0, quad.remote.0, &data, key);
+        data[24..40].clone_from_slice(&signature);
+    }
+    let csum = segment_checksum(quad.local.0, quad.remote.0, &data);
+    data[16..18].clone_from_slice(&csum.to_be_bytes());
+    Segment { quad, data }
+}
+
+/// The longest MD5 key we take, as Linux's TCP_MD5SIG.
+pub const MD5_MAX_KEY_LEN: usize = 80;
+
+/// Default for the memory all receive buffers together may grow to, see
+/// `Connections::set_recv_memory_limit`.
+pub const RECV_MEMORY_LIMIT: usize = 64 << 20;
+
+/// Every connection the stack knows about.
+/// For now a SYN to any port is accepted.
+pub struct Connections {
+    connections: HashMap<Quad, Connection>,
+
+    /// Connections that finished the handshake and have not been picked up by `accept`.
+    established: VecDeque<Quad>,
+
+    /// Segments waiting to be sent, see `poll_transmit`.
+    outbound: VecDeque<Segment>,
+
+    /// Keys the hash used for initial sequence numbers.
+    isn_secret: RandomState,
+
+    fast_open: FastOpen,
+
+    /// MD5 keys for passive opens, by local port and peer address.
+    md5_keys: HashMap<(u16, u32), Vec<u8>>,
+
+    mptcp: Mptcp,
+
+    /// Receive buffers only grow while their sizes add up to less than this.
+    recv_memory_limit: usize,
+
+    /// Listening ports, and ephemeral ports for connections that don't pick one.
+    ports: Ports,
+}
+
+impl Default for Connections {
+    fn default() -> Self {
+        Connections::new()
+    }
 }
 
-/// returns a TCP Packet depending on what was contained in recv'd TCP packet.
+impl Connections {
+    pub fn new() -> Self {
+        Connections {
+            connections: HashMap::new(),
+            established: VecDeque::new(),
+            outbound: VecDeque::new(),
+            isn_secret: RandomState::new(),
+            fast_open: FastOpen::new(),
+            md5_keys: HashMap::new(),
+            mptcp: Mptcp::new(),
+            recv_memory_limit: RECV_MEMORY_LIMIT,
+            ports: Ports::new(),
+        }
+    }
+
+    /// A snapshot of a connection's state and counters.
+    pub fn info(&self, quad: &Quad) -> Option<TcpInfo> {
+        self.connections.get(quad).map(|c| c.info())
+    }
+
+    /// Snapshots of every connection.
+    pub fn infos(&self) -> Vec<(Quad, TcpInfo)> {
+        self.connections
+            .iter()
+            .map(|(quad, c)| (*quad, c.info()))
+            .collect()
+    }
+
+    /// Opens a connection from `local` to `remote` by sending a SYN. Data may be
+    /// queued with `send` straight away, it goes out once the handshake completes.
+    /// A local port of 0 picks an ephemeral one.
+    pub fn connect(&mut self, local: (u32, u16), remote: (u32, u16)) -> io::Result<Quad> {
+        let quad = self.bind(local, remote)?;
+        let iss = self.initial_sequence_number(&quad);
+        self.open(quad, Connection::connect(quad, iss));
+        Ok(quad)
+    }
+
+    /// Like `connect`, but every segment of the connection is signed with `key`
+    /// and the peer's must be too, RFC 2385.
+    pub fn connect_md5(
+        &mut self,
+        local: (u32, u16),
+        remote: (u32, u16),
+        key: &[u8],
+    ) -> io::Result<Quad> {
+        if key.is_empty() || key.len() > MD5_MAX_KEY_LEN {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let quad = self.bind(local, remote)?;
+        let iss = self.initial_sequence_number(&quad);
+        let mut c = Connection::connect(quad, iss);
+        c.set_md5_key(Some(key.to_vec()));
+        self.open(quad, c);
+        Ok(quad)
+    }
+
+    /// Opens a connection with TCP Fast Open. If we hold a cookie for `remote` the
+    /// start of `data` is sent in the SYN, otherwise the SYN asks for a cookie for
+    /// next time and `data` is sent once the handshake completes.
+    pub fn connect_fast_open(
+        &mut self,
+        local: (u32, u16),
+        remote: (u32, u16),
+        data: &[u8],
+    ) -> io::Result<Quad> {
+        let quad = self.bind(local, remote)?;
+        let iss = self.initial_sequence_number(&quad);
+        let cookie = self.fast_open.cached_cookie(remote.0);
+        let c = Connection::connect_fast_open(quad, iss, cookie, data);
+        self.open(quad, c);
+        Ok(quad)
+    }
+
+    /// Accepts connections on `local`, with `ports::ANY_ADDR` for every address.
+    /// For now a SYN to any port is still accepted, listening keeps the port from
+    /// being bound or handed out as an ephemeral port.
+    pub fn listen(&mut self, local: (u32, u16)) -> io::Result<()> {
+        let endpoints = self.endpoints();
+        self.ports.listen(local, endpoints)
+    }
+
+    pub fn unlisten(&mut self, local: (u32, u16)) -> bool {
+        self.ports.unlisten(local)
+    }
+
+    /// Sets the range ephemeral ports are picked from, both ends included.
+    pub fn set_ephemeral_range(&mut self, low: u16, high: u16) -> io::Result<()> {
+        self.ports.set_ephemeral_range(low, high)
+    }
+
+    /// Like SO_REUSEADDR for every later `connect` and `listen`, see `Ports::listen`
+    /// and `Ports::bind`.
+    pub fn set_reuse_addr(&mut self, enabled: bool) {
+        self.ports.set_reuse_addr(enabled);
+    }
+
+    /// The quad for a new connection from `local` to `remote`, picking an ephemeral
+    /// port when `local`'s is 0. A connection in TIME-WAIT that `reuse_addr` lets
+    /// us take over is dropped.
+    fn bind(&mut self, local: (u32, u16), remote: (u32, u16)) -> io::Result<Quad> {
+        let endpoints = self.endpoints();
+        if local.1 == 0 {
+            let port = self.ports.allocate(local.0, remote, endpoints)?;
+            return Ok(Quad {
+                local: (local.0, port),
+                remote,
+            });
+        }
+
+        let quad = Quad { local, remote };
+        if self.ports.bind(local, remote, endpoints)?.is_some() {
+            println!("[TCP] reusing {:?} from TIME-WAIT", quad);
+            let token = self
+                .connections
+                .remove(&quad)
+                .and_then(|c| c.mptcp().map(|s| s.token()));
+            if let Some(token) = token {
+                self.mptcp.on_subflow_closed(token, &quad);
+            }
+        }
+        Ok(quad)
+    }
+
+    /// Every connection as `ports` sees them.
+    fn endpoints(&self) -> Vec<Endpoint> {
+        self.connections
+            .values()
+            .map(|c| Endpoint {
+                local: c.quad().local,
+                remote: c.quad().remote,
+                time_wait: c.state() == State::TimeWait,
+            })
+            .collect()
+    }
+
+    fn open(&mut self, quad: Quad, mut c: Connection) {
+        c.open(Instant::now(), &mut self.outbound);
+        self.connections.insert(quad, c);
+    }
+
+    /// Lets clients send data in their SYN, RFC 7413. Such connections are ready to
+    /// `accept` as soon as the SYN arrives, with its data waiting to be read.
+    pub fn set_fast_open(&mut self, enabled: bool) {
+        self.fast_open.set_server_enabled(enabled);
+    }
+
+    /// Requires connections between our `port` and `peer_ip` to sign their segments
+    /// with `key`, RFC 2385, or with `None` stops requiring it. This applies to
+    /// connections accepted afterwards, use `connect_md5` for active opens.
+    pub fn set_md5_key(&mut self, port: u16, peer_ip: u32, key: Option<&[u8]>) -> io::Result<()> {
+        match key {
+            Some(key) if key.is_empty() || key.len() > MD5_MAX_KEY_LEN => {
+                return Err(io::Error::from(io::ErrorKind::InvalidInput));
+            }
+            Some(key) => self.md5_keys.insert((port, peer_ip), key.to_vec()),
+            None => self.md5_keys.remove(&(port, peer_ip)),
+        };
+        Ok(())
+    }
+
+    /// Bounds the memory receive buffer autotuning may take across every connection.
+    /// Buffers already grown past the limit are left as they are.
+    pub fn set_recv_memory_limit(&mut self, bytes: usize) {
+        self.recv_memory_limit = bytes;
+    }
+
+    /// Takes the next connection that has completed its handshake, or had data
+    /// accepted from its SYN.
+    pub fn accept(&mut self) -> Option<Quad> {
+        self.established.pop_front()
+    }
+
+    /// Queues data to be sent on a connection.
+    pub fn send(&mut self, quad: &Quad, data: &[u8]) -> io::Result<usize> {
+        let c = lookup(&mut self.connections, quad)?;
+        match c.state() {
+            State::SynSent | State::SynRcvd | State::Established | State::CloseWait => {
+                Ok(c.write(data, Instant::now(), &mut self.outbound))
+            }
+            _ => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+        }
+    }
+
+    /// Queues data to be sent as urgent data, the urgent pointer marking its last byte.
+    pub fn send_urgent(&mut self, quad: &Quad, data: &[u8]) -> io::Result<usize> {
+        let c = lookup(&mut self.connections, quad)?;
+        match c.state() {
+            State::SynSent | State::SynRcvd | State::Established | State::CloseWait => {
+                Ok(c.write_urgent(data, Instant::now(), &mut self.outbound))
+            }
+            _ => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+        }
+    }
+
+    /// Reads data received on a connection. Returns 0 once the peer has closed and
+    /// everything it sent has been read, and `WouldBlock` if there is nothing yet.
+    /// Reads stop at the urgent mark, see `at_urgent_mark`.
+    pub fn recv(&mut self, quad: &Quad, buf: &mut [u8]) -> io::Result<usize> {
+        let c = lookup(&mut self.connections, quad)?;
+        match c.read(buf, &mut self.outbound) {
+            0 if !c.is_read_closed() => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+            n => Ok(n),
+        }
+    }
+
+    /// Takes the urgent byte received out of band, `WouldBlock` if the peer signalled
+    /// urgent data that has not arrived yet and `InvalidInput` if there is none.
+    pub fn recv_urgent(&mut self, quad: &Quad) -> io::Result<u8> {
+        let c = lookup(&mut self.connections, quad)?;
+        match c.read_urgent() {
+            Some(byte) => Ok(byte),
+            None if c.is_urgent_pending() => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+            None => Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        }
+    }
+
+    /// Whether the next byte `recv` returns is where the urgent data was, like `sockatmark`.
+    /// Inline this is the urgent byte itself, out of band the byte that followed it.
+    pub fn at_urgent_mark(&mut self, quad: &Quad) -> io::Result<bool> {
+        Ok(lookup(&mut self.connections, quad)?.is_at_urgent_mark())
+    }
+
+    /// Chooses whether urgent data is left in the stream (inline) or the last urgent
+    /// byte is held back for `recv_urgent` (out of band, the default).
+    pub fn set_urgent_inline(&mut self, quad: &Quad, inline: bool) -> io::Result<()> {
+        lookup(&mut self.connections, quad)?.set_urgent_inline(inline);
+        Ok(())
+    }
+
+    /// Closes our side of a connection once queued data has been sent.
+    pub fn close(&mut self, quad: &Quad) -> io::Result<()> {
+        let c = lookup(&mut self.connections, quad)?;
+        c.close(Instant::now(), &mut self.outbound);
+        Ok(())
+    }
+
+    /// A packet of the connection couldn't be delivered as its host is unreachable.
+    /// A connection still opening gives up, like Linux, an open one carries on
+    /// as RFC 1122 section 4.2.3.9 asks.
+    pub fn on_host_unreachable(&mut self, quad: &Quad) {
+        if let Some(c) = self.connections.get_mut(quad) {
+            c.on_host_unreachable();
+        }
+        self.remove_closed();
+    }
+
+    /// Takes the next segment that should be sent.
+    pub fn poll_transmit(&mut self) -> Option<Segment> {
+        self.outbound.pop_front()
+    }
+
+    /// Runs every connection's timers, should be called regularly.
+    pub fn on_tick(&mut self, now: Instant) {
+        for c in self.connections.values_mut() {
+            c.on_tick(now, &mut self.outbound);
+        }
+        let tokens: Vec<u32> = self.mptcp.tokens().collect();
+        for token in tokens {
+            self.mptcp_update(token, now);
+        }
+        self.remove_closed();
+    }
+
+    /// Hands a received segment to its connection, opening one for a SYN.
+    fn on_segment(&mut self, quad: Quad, segment: &[u8], now: Instant) {
+        let header = &TcpHeader::from_slice(&TcpPacketSlice { slice: segment });
+        let payload = &segment[header.data_offset as usize..];
+        let options = TcpOptions::parse(&header.options[..header.data_offset as usize - 20]);
+
+        if !self.verify_md5(&quad, segment, &options) {
+            println!("[TCP] bad MD5 signature from {:?}, dropping", quad.remote);
+            return;
+        }
+
+        let mut subflow_of = None;
+        match self.connections.get_mut(&quad) {
+            Some(c) => {
+                let before = c.state();
+                c.on_segment(header, &options, payload, now, &mut self.outbound);
+                subflow_of = c.mptcp().map(|subflow| subflow.token());
+                if subflow_of.is_none()
+                    && c.is_passive()
+                    && !c.is_fast_open()
+                    && before == State::SynRcvd
+                    && c.state() == State::Established
+                {
+                    self.established.push_back(quad);
+                }
+                if before == State::SynSent && c.state() != State::SynSent {
+                    if let Some(cookie) = &options.fast_open {
+                        self.fast_open.cache_cookie(quad.remote.0, cookie);
+                    }
+                }
+            }
+            None if header.flags.syn && !header.flags.ack && !header.flags.rst => {
+                let iss = self.initial_sequence_number(&quad);
+                let fast_open = self.fast_open.on_syn(quad.remote.0, &options, payload);
+                let mut c = Connection::accept(quad, iss, header, &options, fast_open);
+                c.set_md5_key(self.md5_keys.get(&(quad.local.1, quad.remote.0)).cloned());
+                if !self.mptcp.on_syn(quad, &options, &mut c) {
+                    return self.reset_closed(quad, header, payload);
+                }
+                c.open(now, &mut self.outbound);
+                if c.is_fast_open() {
+                    self.established.push_back(quad);
+                }
+                self.connections.insert(quad, c);
+            }
+            None => self.reset_closed(quad, header, payload),
+        }
+        if let Some(token) = subflow_of {
+            self.mptcp_update(token, now);
+        }
+        self.grow_recv_buffer(&quad);
+        self.remove_closed();
+    }
+
+    /// Gives a connection the bigger receive buffer autotuning asked for, as far as
+    /// the memory limit allows.
+    fn grow_recv_buffer(&mut self, quad: &Quad) {
+        let wanted = match self.connections.get_mut(quad) {
+            Some(c) => match c.take_recv_buffer_wanted() {
+                Some(wanted) => wanted,
+                None => return,
+            },
+            None => return,
+        };
+        let used: usize = self.connections.values().map(|c| c.recv_buffer()).sum();
+        let available = self.recv_memory_limit.saturating_sub(used);
+        if let Some(c) = self.connections.get_mut(quad) {
+            c.set_recv_buffer(wanted.min(c.recv_buffer() + available));
+        }
+    }
+
+    /// The MD5 key a segment for `quad` must be signed with, if any.
+    fn md5_key(&self, quad: &Quad) -> Option<&[u8]> {
+        match self.connections.get(quad) {
+            Some(c) => c.md5_key(),
+            None => self
+                .md5_keys
+                .get(&(quad.local.1, quad.remote.0))
+                .map(|key| key.as_slice()),
+        }
+    }
+
+    /// RFC 2385, a segment must carry a valid signature when we hold a key for its
+    /// connection, and must not carry one when we do not.
+    fn verify_md5(&self, quad: &Quad, segment: &[u8], options: &TcpOptions) -> bool {
+        match (self.md5_key(quad), options.md5) {
+            (None, None) => true,
+            (Some(key), Some(signature)) => {
+                md5::segment_signature(quad.remote.0, quad.local.0, segment, key) == signature
+            }
+            _ => false,
+        }
+    }
+
+    /// Answers a segment for a connection that does not exist, RFC 793 page 36.
+    fn reset_closed(&mut self, quad: Quad, header: &TcpHeader, payload: &[u8]) {
+        if header.flags.rst {
+            return;
+        }
+        let mut flags = TcpHeaderFlags::new();
+        flags.rst = true;
+        let header = if header.flags.ack {
+            TcpHeader::new(header.ack_number, 0, flags, 0)
+        } else {
+            flags.ack = true;
+            let seg_len = payload.len() as u32 + header.flags.syn as u32 + header.flags.fin as u32;
+            let ack = header.seq_number.wrapping_add(seg_len);
+            TcpHeader::new(0, ack, flags, 0)
+        };
+        let key = self.md5_key(&quad);
+        let segment = build_segment(quad, header, &TcpOptions::default(), &[], key);
+        self.outbound.push_back(segment);
+    }
+
+    fn remove_closed(&mut self) {
+        let mptcp = &mut self.mptcp;
+        self.connections.retain(|quad, c| {
+            if c.state() == State::Closed {
+                println!("[TCP] closed {:?}: {}", quad, c.info());
+                if let Some(subflow) = c.mptcp() {
+                    mptcp.on_subflow_closed(subflow.token(), quad);
+                }
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// RFC 6528, a 4 microsecond clock plus a keyed hash of the four-tuple.
+    fn initial_sequence_number(&self, quad: &Quad) -> u32 {
+        let clock = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| (d.as_micros() / 4) as u32)
+            .unwrap_or(0);
+        clock.wrapping_add(self.isn_secret.hash_one(quad) as u32)
+    }
+}
+
+fn lookup<'a>(
+    connections: &'a mut HashMap<Quad, Connection>,
+    quad: &Quad,
+) -> io::Result<&'a mut Connection> {
+    connections
+        .get_mut(quad)
+        .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))
+}
+
+/// Processes a received TCP segment, returning the segment to send back if there is one.
 /// We use a 1500 size max array size as this is the MTU for ethernet.
+/// Anything else the segment causes to be sent is left in `connections` for `poll_transmit`.
 pub fn read_packet(
     data: &[u8],
     ipv4_packet: &crate::ipv4::IPv4Packet,
+    connections: &mut Connections,
 ) -> Option<([u8; 1500], usize)> {
-    // assuming that data means TCP and above layer.
-    let tcp_slice = TcpPacketSlice { slice: data };
+    // assuming that data means TCP and above layer, trimmed to what the IP header says.
+    let segment_len = (ipv4_packet.total_len as usize).checked_sub(ipv4_packet.ihl as usize * 4)?;
+    let data = data.get(..segment_len)?;
+    if segment_len < 20 {
+        return None;
+    }
+    // A header is 5 to 15 words, and has to fit in the segment.
+    let data_offset = (data[12] >> 4) as usize * 4;
+    if data_offset < 20 || segment_len < data_offset {
+        return None;
+    }
+    if segment_checksum(ipv4_packet.source_ip, ipv4_packet.dest_ip, data) != 0 {
+        println!("[TCP] bad checksum, dropping");
+        return None;
+    }
+
+    let quad = Quad {
+        local: (ipv4_packet.dest_ip, u16::from_be_bytes([data[2], data[3]])),
+        remote: (
+            ipv4_packet.source_ip,
+            u16::from_be_bytes([data[0], data[1]]),
+        ),
+    };
 
-    let mut tcp_packet = TcpHeader::from_slice(&tcp_slice);
+    connections.on_segment(quad, data, Instant::now());
 
-    // check the checksum
-    //let original_csum_res = tcp_checksum(&tcp_slice, ipv4_packet);
-    println!("Recvd TCP Packet: {:?}", tcp_packet);
+    // The first segment for this connection goes back as the reply.
+    let i = connections.outbound.iter().position(|s| s.quad == quad)?;
+    let reply = connections.outbound.remove(i)?;
     let mut buf = [0u8; 1500];
+    buf[..reply.data.len()].clone_from_slice(&reply.data);
+    Some((buf, reply.data.len()))
+}
 
-    if tcp_packet.flags.syn {
-        tcp_packet.flags.ack = true;
-        tcp_packet.flip_sd();
-        tcp_packet.data_offset = 40;
-
-        let old_ack = tcp_packet.ack_number;
-        let old_seq = tcp_packet.seq_number;
-        tcp_packet.ack_number = tcp_packet.seq_number + 1;
-        println!("FOUND ACK: {} : FOUND SEQ: {}", old_ack, old_seq);
-        if old_ack == 0 {
-            // if ack is zero, set an ISN.
-            tcp_packet.seq_number = 300;
-        } else {
-            tcp_packet.seq_number = old_ack + 1;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub const PEER: (u32, u16) = (0x0a000001, 5000);
+    pub const LOCAL: (u32, u16) = (0x0a000002, 80);
+
+    pub fn quad() -> Quad {
+        Quad {
+            local: LOCAL,
+            remote: PEER,
         }
-        tcp_packet.checksum = 0;
-        let tcp_outbuf = tcp_packet.to_slice();
-        let csum = tcp_checksum(
-            &TcpPacketSlice {
-                slice: &tcp_outbuf[..(tcp_packet.data_offset as usize)],
-            },
-            ipv4_packet,
+    }
+
+    /// Feeds `connections` a segment from the peer.
+    pub fn deliver(
+        connections: &mut Connections,
+        seq: u32,
+        ack: u32,
+        flags: TcpHeaderFlags,
+        options: &TcpOptions,
+        payload: &[u8],
+    ) {
+        let header = TcpHeader::new(seq, ack, flags, 65535);
+        deliver_header(connections, header, options, payload);
+    }
+
+    pub fn deliver_header(
+        connections: &mut Connections,
+        header: TcpHeader,
+        options: &TcpOptions,
+        payload: &[u8],
+    ) {
+        let from_peer = Quad {
+            local: PEER,
+            remote: LOCAL,
+        };
+        let segment = build_segment(from_peer, header, options, payload, None);
+        connections.on_segment(quad(), &segment.data, Instant::now());
+    }
+
+    pub fn flags(f: &str) -> TcpHeaderFlags {
+        let mut flags = TcpHeaderFlags::new();
+        flags.syn = f.contains('S');
+        flags.ack = f.contains('A');
+        flags.fin = f.contains('F');
+        flags.rst = f.contains('R');
+        flags
+    }
+
+    /// Takes the next outbound segment, checking its checksum on the way.
+    pub fn sent(connections: &mut Connections) -> (TcpHeader, Vec<u8>) {
+        let segment = connections.poll_transmit().expect("nothing was sent");
+        assert_eq!(segment_checksum(LOCAL.0, PEER.0, &segment.data), 0);
+        let header = TcpHeader::from_slice(&TcpPacketSlice {
+            slice: &segment.data,
+        });
+        let payload = segment.data[header.data_offset as usize..].to_vec();
+        (header, payload)
+    }
+
+    /// Runs a passive open, returning our ISN.
+    pub fn handshake(connections: &mut Connections, peer_isn: u32) -> u32 {
+        let syn_options = TcpOptions {
+            mss: Some(1460),
+            ..TcpOptions::default()
+        };
+        deliver(connections, peer_isn, 0, flags("S"), &syn_options, &[]);
+        let (syn_ack, _) = sent(connections);
+        assert!(syn_ack.flags.syn && syn_ack.flags.ack);
+        assert_eq!(syn_ack.ack_number, peer_isn + 1);
+
+        let iss = syn_ack.seq_number;
+        let none = TcpOptions::default();
+        deliver(
+            connections,
+            peer_isn + 1,
+            iss.wrapping_add(1),
+            flags("A"),
+            &none,
+            &[],
+        );
+        iss
+    }
+
+    #[test]
+    fn test_connection_info() {
+        let mut connections = Connections::new();
+        let none = TcpOptions::default();
+        let iss = handshake(&mut connections, 1000);
+        assert_eq!(connections.accept(), Some(quad()));
+
+        deliver(
+            &mut connections,
+            1001,
+            iss.wrapping_add(1),
+            flags("A"),
+            &none,
+            b"hello",
+        );
+        let (ack, _) = sent(&mut connections);
+        assert_eq!(ack.ack_number, 1006);
+
+        // A segment from further on is held back and counted.
+        deliver(
+            &mut connections,
+            1010,
+            iss.wrapping_add(1),
+            flags("A"),
+            &none,
+            b"late",
+        );
+        let (dup, _) = sent(&mut connections);
+        assert_eq!(dup.ack_number, 1006);
+
+        connections.send(&quad(), b"world").unwrap();
+        let (data, payload) = sent(&mut connections);
+        assert_eq!(payload, b"world");
+        deliver(
+            &mut connections,
+            1006,
+            data.seq_number.wrapping_add(5),
+            flags("A"),
+            &none,
+            &[],
+        );
+
+        let info = connections.info(&quad()).unwrap();
+        assert_eq!(info.state, State::Established);
+        assert_eq!(info.mss, 1460);
+        assert_eq!(info.bytes_received, 5);
+        assert_eq!(info.bytes_sent, 5);
+        assert_eq!(info.bytes_acked, 5);
+        assert_eq!(info.unacked, 0);
+        assert_eq!(info.reordering, 1);
+        assert!(info.srtt.is_some());
+
+        let mut buf = [0u8; 16];
+        assert_eq!(connections.recv(&quad(), &mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+    }
+
+    #[test]
+    fn test_bad_data_offset() {
+        let mut connections = Connections::new();
+        let from_peer = Quad {
+            local: PEER,
+            remote: LOCAL,
+        };
+        let header = TcpHeader::new(1000, 0, flags("S"), 65535);
+        let none = TcpOptions::default();
+        let mut segment = build_segment(from_peer, header, &none, &[], None).data;
+
+        // A data offset of 4 words, shorter than any header, with a good checksum.
+        segment[12] = 0x40 | (segment[12] & 0x0f);
+        segment[16..18].clone_from_slice(&[0, 0]);
+        let checksum = segment_checksum(PEER.0, LOCAL.0, &segment);
+        segment[16..18].clone_from_slice(&checksum.to_be_bytes());
+        let ip_header =
+            crate::pkt::build_ipv4_header(PEER.0, LOCAL.0, crate::ipv4::ProtoType::TCP, 20);
+        let ip_packet =
+            crate::ipv4::IPv4Packet::from_slice(crate::ipv4::Ipv4PacketSlice { slice: &ip_header });
+        assert!(read_packet(&segment, &ip_packet, &mut connections).is_none());
+        assert!(connections.poll_transmit().is_none());
+    }
+
+    #[test]
+    fn test_tiny_mss() {
+        let mut connections = Connections::new();
+        let syn_options = TcpOptions {
+            mss: Some(0),
+            ..TcpOptions::default()
+        };
+        deliver(&mut connections, 1000, 0, flags("S"), &syn_options, &[]);
+        let (syn_ack, _) = sent(&mut connections);
+        let iss = syn_ack.seq_number;
+        let none = TcpOptions::default();
+        deliver(
+            &mut connections,
+            1001,
+            iss.wrapping_add(1),
+            flags("A"),
+            &none,
+            &[],
+        );
+
+        // An MSS of 0 is raised to the floor, data still goes out.
+        assert_eq!(connections.info(&quad()).unwrap().mss, 64);
+        connections.send(&quad(), &[0xAB; 100]).unwrap();
+        let (data, payload) = sent(&mut connections);
+        assert_eq!(payload.len(), 64);
+        deliver(
+            &mut connections,
+            1001,
+            data.seq_number.wrapping_add(64),
+            flags("A"),
+            &none,
+            &[],
+        );
+        let (_, payload) = sent(&mut connections);
+        assert_eq!(payload.len(), 36);
+    }
+
+    #[test]
+    fn test_simultaneous_open() {
+        let mut connections = Connections::new();
+        let none = TcpOptions::default();
+        connections.connect(LOCAL, PEER).unwrap();
+        let (syn, _) = sent(&mut connections);
+        assert!(syn.flags.syn && !syn.flags.ack);
+        let iss = syn.seq_number;
+
+        // The peer's SYN crosses ours.
+        deliver(&mut connections, 5000, 0, flags("S"), &none, &[]);
+        let (syn_ack, _) = sent(&mut connections);
+        assert!(syn_ack.flags.syn && syn_ack.flags.ack);
+        assert_eq!((syn_ack.seq_number, syn_ack.ack_number), (iss, 5001));
+        assert_eq!(connections.info(&quad()).unwrap().state, State::SynRcvd);
+
+        deliver(
+            &mut connections,
+            5000,
+            iss.wrapping_add(1),
+            flags("SA"),
+            &none,
+            &[],
         );
-        println!("CHECKSUM: {:X?}", csum);
-        tcp_packet.checksum = csum;
-        let tcp_outbuf = tcp_packet.to_slice();
-        println!(
-            "TCP PACKET STRUCT: {:?}\nTCP BUF: {:X?}",
-            tcp_packet, tcp_outbuf
+        assert_eq!(connections.info(&quad()).unwrap().state, State::Established);
+        // We opened it, so it is not waiting to be accepted.
+        assert_eq!(connections.accept(), None);
+    }
+
+    #[test]
+    fn test_simultaneous_close() {
+        let mut connections = Connections::new();
+        let none = TcpOptions::default();
+        let iss = handshake(&mut connections, 1000);
+
+        connections.close(&quad()).unwrap();
+        let (fin, _) = sent(&mut connections);
+        assert!(fin.flags.fin);
+        assert_eq!(connections.info(&quad()).unwrap().state, State::FinWait1);
+
+        // The peer's FIN crosses ours.
+        deliver(
+            &mut connections,
+            1001,
+            iss.wrapping_add(1),
+            flags("FA"),
+            &none,
+            &[],
+        );
+        let (ack, _) = sent(&mut connections);
+        assert_eq!(ack.ack_number, 1002);
+        assert_eq!(connections.info(&quad()).unwrap().state, State::Closing);
+
+        deliver(
+            &mut connections,
+            1002,
+            iss.wrapping_add(2),
+            flags("A"),
+            &none,
+            &[],
+        );
+        assert_eq!(connections.info(&quad()).unwrap().state, State::TimeWait);
+    }
+
+    #[test]
+    fn test_urgent_data() {
+        let mut connections = Connections::new();
+        let none = TcpOptions::default();
+        let iss = handshake(&mut connections, 1000);
+
+        connections.send_urgent(&quad(), b"ab!").unwrap();
+        let (data, _) = sent(&mut connections);
+        assert!(data.flags.urg);
+        assert_eq!(data.urgent_pointer, 3);
+
+        let receive_urgent = |connections: &mut Connections, seq: u32| {
+            let mut header = TcpHeader::new(seq, iss.wrapping_add(4), flags("A"), 65535);
+            header.flags.urg = true;
+            header.urgent_pointer = 3;
+            deliver_header(connections, header, &none, b"xyZw");
+        };
+
+        // Out of band, the urgent byte is taken out of the stream.
+        receive_urgent(&mut connections, 1001);
+        let mut buf = [0u8; 16];
+        assert_eq!(connections.recv(&quad(), &mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"xy");
+        assert!(connections.at_urgent_mark(&quad()).unwrap());
+        assert_eq!(connections.recv_urgent(&quad()).unwrap(), b'Z');
+        assert_eq!(connections.recv(&quad(), &mut buf).unwrap(), 1);
+        assert_eq!(buf[0], b'w');
+
+        // Inline, it stays in the stream with the mark in front of it.
+        connections.set_urgent_inline(&quad(), true).unwrap();
+        receive_urgent(&mut connections, 1005);
+        assert_eq!(connections.recv(&quad(), &mut buf).unwrap(), 2);
+        assert!(connections.at_urgent_mark(&quad()).unwrap());
+        assert_eq!(connections.recv(&quad(), &mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"Zw");
+        assert!(connections.recv_urgent(&quad()).is_err());
+    }
+
+    #[test]
+    fn test_fast_open() {
+        let options_of = |header: &TcpHeader| {
+            TcpOptions::parse(&header.options[..header.data_offset as usize - 20])
+        };
+        let request = TcpOptions {
+            fast_open: Some(Vec::new()),
+            ..TcpOptions::default()
+        };
+
+        // Server, a cookie request gets a cookie and SYN data is only taken with one.
+        let mut server = Connections::new();
+        server.set_fast_open(true);
+        deliver(&mut server, 1000, 0, flags("S"), &request, b"early");
+        let (syn_ack, _) = sent(&mut server);
+        assert_eq!(syn_ack.ack_number, 1001);
+        let cookie = options_of(&syn_ack).fast_open.unwrap();
+        assert_eq!(cookie.len(), 8);
+        deliver(
+            &mut server,
+            1001,
+            0,
+            flags("R"),
+            &TcpOptions::default(),
+            &[],
         );
-        buf[..tcp_packet.data_offset as usize]
-            .clone_from_slice(&tcp_outbuf[..tcp_packet.data_offset as usize]);
+        assert!(server.info(&quad()).is_none());
+
+        let with_cookie = TcpOptions {
+            fast_open: Some(cookie.clone()),
+            ..TcpOptions::default()
+        };
+        deliver(&mut server, 2000, 0, flags("S"), &with_cookie, b"GET");
+        let (syn_ack, _) = sent(&mut server);
+        assert_eq!(syn_ack.ack_number, 2004);
+        assert_eq!(server.accept(), Some(quad()));
+        let mut buf = [0u8; 8];
+        assert_eq!(server.recv(&quad(), &mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"GET");
+
+        // Client, the first connection asks for a cookie and the next uses it.
+        let mut client = Connections::new();
+        client.connect_fast_open(LOCAL, PEER, b"hello").unwrap();
+        let (syn, payload) = sent(&mut client);
+        assert_eq!(options_of(&syn).fast_open, Some(Vec::new()));
+        assert!(payload.is_empty());
+        let iss = syn.seq_number;
+        deliver(
+            &mut client,
+            7000,
+            iss.wrapping_add(1),
+            flags("SA"),
+            &with_cookie,
+            &[],
+        );
+        let (_, payload) = sent(&mut client);
+        assert!(payload.is_empty());
+        let (_, payload) = sent(&mut client);
+        assert_eq!(payload, b"hello");
+        deliver(
+            &mut client,
+            7001,
+            0,
+            flags("R"),
+            &TcpOptions::default(),
+            &[],
+        );
+
+        client.connect_fast_open(LOCAL, PEER, b"hello").unwrap();
+        let (syn, payload) = sent(&mut client);
+        assert_eq!(options_of(&syn).fast_open, Some(cookie));
+        assert_eq!(payload, b"hello");
+    }
+
+    #[test]
+    fn test_md5_signature() {
+        let key = b"bgp peering secret";
+        let from_peer = Quad {
+            local: PEER,
+            remote: LOCAL,
+        };
+        let syn = |key: Option<&[u8]>| {
+            let header = TcpHeader::new(1000, 0, flags("S"), 65535);
+            build_segment(from_peer, header, &TcpOptions::default(), &[], key).data
+        };
+
+        let mut connections = Connections::new();
+        connections.set_md5_key(LOCAL.1, PEER.0, Some(key)).unwrap();
+
+        // Unsigned and wrongly signed SYNs are dropped without an answer.
+        connections.on_segment(quad(), &syn(None), Instant::now());
+        connections.on_segment(quad(), &syn(Some(b"wrong")), Instant::now());
+        assert!(connections.poll_transmit().is_none());
+        assert!(connections.info(&quad()).is_none());
+
+        connections.on_segment(quad(), &syn(Some(key)), Instant::now());
+        let syn_ack = connections.poll_transmit().unwrap();
+        let signature = TcpOptions::parse(&syn_ack.data[20..40]).md5.unwrap();
+        assert_eq!(
+            md5::segment_signature(LOCAL.0, PEER.0, &syn_ack.data, key),
+            signature
+        );
+        assert_eq!(segment_checksum(LOCAL.0, PEER.0, &syn_ack.data), 0);
+        assert_eq!(connections.info(&quad()).unwrap().state, State::SynRcvd);
+
+        // Nor is an unsigned segment for the connection now that it exists.
+        connections.on_segment(quad(), &syn(None), Instant::now());
+        assert!(connections.poll_transmit().is_none());
+    }
+
+    /// Passes segments between two stacks until neither has anything more to say.
+    pub fn exchange(a: &mut Connections, b: &mut Connections) {
+        while transmit(a, b) | transmit(b, a) {}
+    }
+
+    /// Delivers everything `from` has queued to `to`, returning whether there was anything.
+    fn transmit(from: &mut Connections, to: &mut Connections) -> bool {
+        let mut any = false;
+        while let Some(segment) = from.poll_transmit() {
+            any = true;
+            let quad = Quad {
+                local: segment.quad.remote,
+                remote: segment.quad.local,
+            };
+            to.on_segment(quad, &segment.data, Instant::now());
+        }
+        any
+    }
+
+    #[test]
+    fn test_mptcp() {
+        let server_addr = (0x0a000001, 80);
+        let mut client = Connections::new();
+        let mut server = Connections::new();
+        server.set_mptcp(true);
+
+        let token = client
+            .mptcp_connect((0x0a000002, 40000), server_addr)
+            .unwrap();
+        exchange(&mut client, &mut server);
+        let server_token = server.mptcp_accept().unwrap();
+        assert!(server.accept().is_none());
+
+        let second = client.mptcp_join(token, 0x0a000004).unwrap();
+        exchange(&mut client, &mut server);
+        assert_eq!(server.mptcp_subflows(server_token).len(), 2);
+
+        // More than the first subflow's initial window, so both carry some of it.
+        let data: Vec<u8> = (0..20000u32).map(|i| (i % 251) as u8).collect();
+        client.mptcp_send(token, &data).unwrap();
+        for quad in client.mptcp_subflows(token) {
+            assert!(client.info(&quad).unwrap().bytes_sent > 0);
+        }
+        exchange(&mut client, &mut server);
+
+        let mut received = Vec::new();
+        let mut buf = [0u8; 4096];
+        while let Ok(n) = server.mptcp_recv(server_token, &mut buf) {
+            received.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(received, data);
+
+        server.mptcp_send(server_token, b"bye").unwrap();
+        server.mptcp_close(server_token).unwrap();
+        exchange(&mut client, &mut server);
+        assert_eq!(client.mptcp_recv(token, &mut buf).unwrap(), 3);
+        assert_eq!(client.mptcp_recv(token, &mut buf).unwrap(), 0);
+
+        // Both DATA_FINs through, the subflows close.
+        client.mptcp_close(token).unwrap();
+        exchange(&mut client, &mut server);
+        assert_eq!(server.mptcp_recv(server_token, &mut buf).unwrap(), 0);
+        assert_eq!(client.info(&second).unwrap().state, State::TimeWait);
+
+        // A join for a connection the server doesn't have is refused.
+        let mut stranger = Connections::new();
+        let token = stranger
+            .mptcp_connect((0x0a000004, 40001), server_addr)
+            .unwrap();
+        let mut plain = Connections::new();
+        exchange(&mut stranger, &mut plain);
+        assert!(stranger.mptcp_join(token, 0x0a000002).is_err());
+    }
+
+    #[test]
+    fn test_recv_autotuning() {
+        let from_peer = Quad {
+            local: PEER,
+            remote: LOCAL,
+        };
+        let start = Instant::now();
+        let at = |ms: u64| start + std::time::Duration::from_millis(ms);
+        let segment = |seq: u32, ack: u32, flags: TcpHeaderFlags, options: &TcpOptions| {
+            let header = TcpHeader::new(seq, ack, flags, 65535);
+            build_segment(from_peer, header, options, &[0; 1460], None).data
+        };
+
+        // A 100ms handshake, then a window's worth of data inside one round trip
+        // and another segment once it is over.
+        let transfer = |connections: &mut Connections| {
+            let syn_options = TcpOptions {
+                mss: Some(1460),
+                window_scale: Some(7),
+                ..TcpOptions::default()
+            };
+            let syn = segment(1000, 0, flags("S"), &syn_options);
+            connections.on_segment(quad(), &syn[..syn.len() - 1460], at(0));
+            let (syn_ack, _) = sent(connections);
+            let options = TcpOptions::parse(&syn_ack.options[..syn_ack.data_offset as usize - 20]);
+            assert_eq!(options.window_scale, Some(7));
+
+            let ack = syn_ack.seq_number.wrapping_add(1);
+            let none = TcpOptions::default();
+            let mut seq = 1001;
+            let mut buf = [0u8; 1460];
+            let mut times = vec![100];
+            times.extend([150; 40].iter());
+            times.extend([260, 270].iter());
+            for ms in times {
+                let data = segment(seq, ack, flags("A"), &none);
+                let data = if ms == 100 { &data[..20] } else { &data[..] };
+                connections.on_segment(quad(), data, at(ms));
+                if ms != 100 {
+                    seq += 1460;
+                    assert_eq!(connections.recv(&quad(), &mut buf).unwrap(), 1460);
+                }
+            }
+            let mut window = 0;
+            while let Some(segment) = connections.poll_transmit() {
+                let header = TcpHeader::from_slice(&TcpPacketSlice {
+                    slice: &segment.data,
+                });
+                window = (header.window_size as u32) << 7;
+            }
+            (connections.info(&quad()).unwrap(), window)
+        };
+
+        let (info, window) = transfer(&mut Connections::new());
+        assert_eq!(info.wscale, Some((7, 7)));
+        assert_eq!(info.srtt, Some(std::time::Duration::from_millis(100)));
+        assert!(info.rcv_buf > 65535);
+        assert!(window > 65535);
+
+        let mut connections = Connections::new();
+        connections.set_recv_memory_limit(70000);
+        let (info, _) = transfer(&mut connections);
+        assert_eq!(info.rcv_buf, 70000);
+    }
+
+    #[test]
+    fn test_checkpoint_restore() {
+        let mut old = Connections::new();
+        let none = TcpOptions::default();
+        let iss = handshake(&mut old, 1000);
+        deliver(
+            &mut old,
+            1001,
+            iss.wrapping_add(1),
+            flags("A"),
+            &none,
+            b"hello",
+        );
+        sent(&mut old);
+        old.send(&quad(), b"world").unwrap();
+        sent(&mut old);
+        let before = old.info(&quad()).unwrap();
+
+        // Taking the checkpoint moves the connection out without a word to the peer.
+        let checkpoint = old.checkpoint(&quad()).unwrap();
+        assert!(old.info(&quad()).is_none());
+        assert!(old.poll_transmit().is_none());
+        assert_eq!(old.accept(), None);
+
+        let mut new = Connections::new();
+        let truncated = new.restore(&checkpoint[..checkpoint.len() - 1]);
+        assert_eq!(truncated.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // SND.NXT, behind the magic, version, quad, state and SND.UNA, moved past
+        // the data that was sent.
+        let mut corrupted = checkpoint.clone();
+        corrupted[22..26].clone_from_slice(&iss.wrapping_add(100).to_be_bytes());
+        let corrupted = new.restore(&corrupted);
+        assert_eq!(corrupted.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(new.restore(&checkpoint).unwrap(), quad());
+        let again = new.restore(&checkpoint);
+        assert_eq!(again.unwrap_err().kind(), io::ErrorKind::AddrInUse);
+
+        let after = new.info(&quad()).unwrap();
+        assert_eq!(after.state, before.state);
+        assert_eq!(after.unacked, 5);
+        assert_eq!(after.rcv_wnd, before.rcv_wnd);
+        assert_eq!(after.bytes_received, before.bytes_received);
+
+        // Data read out, acknowledged and sent all carry on from where they were.
+        let mut buf = [0u8; 16];
+        assert_eq!(new.recv(&quad(), &mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+        deliver(
+            &mut new,
+            1006,
+            iss.wrapping_add(6),
+            flags("A"),
+            &none,
+            b"more",
+        );
+        let (ack, _) = sent(&mut new);
+        assert_eq!(ack.ack_number, 1010);
+        assert_eq!(new.info(&quad()).unwrap().unacked, 0);
+        new.send(&quad(), b"again").unwrap();
+        let (data, payload) = sent(&mut new);
+        assert_eq!(data.seq_number, iss.wrapping_add(6));
+        assert_eq!(payload, b"again");
+    }
+
+    #[test]
+    fn test_ports() {
+        let mut connections = Connections::new();
+        connections.set_ephemeral_range(50000, 50009).unwrap();
+        let quad = connections.connect((LOCAL.0, 0), PEER).unwrap();
+        assert!((50000..=50009).contains(&quad.local.1));
+        let err = connections.listen((crate::ports::ANY_ADDR, quad.local.1));
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::AddrInUse);
+
+        connections.listen(LOCAL).unwrap();
+        let err = connections.connect(LOCAL, PEER).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert!(connections.unlisten(LOCAL));
+        connections.connect(LOCAL, PEER).unwrap();
     }
-    Some((buf, tcp_packet.data_offset as usize))
 }
@@ -0,0 +1,1453 @@
+// Multipath TCP, RFC 8684 (version 1).
+//
+// An MPTCP connection is a set of subflows, each an ordinary `Connection` whose
+// segments carry MPTCP options. The first subflow exchanges keys with MP_CAPABLE,
+// further ones, typically from other local addresses, are added with MP_JOIN and
+// authenticated with an HMAC of both keys. Data is numbered in a 64 bit data
+// sequence space and every subflow maps the bytes it carries into that space with
+// DSS options, so the receiver can put the stream back together whichever subflows
+// the bytes took. The scheduler hands data to the subflow with the lowest RTT that
+// has room in its windows.
+//
+// Not done: DSS checksums (a peer that requires them gets plain TCP), ADD_ADDR
+// and REMOVE_ADDR, and reinjecting what a failed subflow had in flight elsewhere.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::convert::TryInto;
+use std::hash::BuildHasher;
+use std::io;
+use std::time::{Duration, Instant};
+
+use super::connection::Connection;
+use super::options::TcpOptions;
+use super::{seq_le, seq_lt, sha256, Connections, Quad, Segment};
+
+/// Option subtypes.
+const MP_CAPABLE: u8 = 0;
+const MP_JOIN: u8 = 1;
+const DSS: u8 = 2;
+
+const VERSION: u8 = 1;
+
+/// MP_CAPABLE flags, A (checksums required) and H (HMAC-SHA256).
+const CHECKSUM_REQUIRED: u8 = 0x80;
+const HMAC_SHA256: u8 = 0x01;
+
+/// MP_JOIN flag B, the subflow is only to be used if the others fail.
+const BACKUP: u8 = 0x01;
+
+/// DSS flags.
+const DATA_FIN: u8 = 0x10;
+const DSN_64: u8 = 0x08;
+const DSN_PRESENT: u8 = 0x04;
+const DATA_ACK_64: u8 = 0x02;
+const DATA_ACK_PRESENT: u8 = 0x01;
+
+/// Room a DSS option with a 64 bit Data ACK and a mapping takes, padded.
+pub const DSS_SPACE: usize = 28;
+
+/// How long to wait for our DATA_FIN to be acknowledged before sending it again.
+const DATA_FIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Received data held per connection before we stop taking it off the subflows,
+/// which then close their windows.
+const RECV_BUFFER_SIZE: usize = 4 * 65535;
+
+/// A DSS mapping of `len` bytes from subflow sequence number `ssn`, relative to
+/// the subflow's ISN, to data sequence number `dsn`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Mapping {
+    dsn: u64,
+    ssn: u32,
+    len: u16,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum MptcpOption {
+    Capable {
+        flags: u8,
+        sender_key: Option<u64>,
+        receiver_key: Option<u64>,
+        data_len: Option<u16>,
+    },
+    JoinSyn {
+        backup: bool,
+        address_id: u8,
+        token: u32,
+        nonce: u32,
+    },
+    JoinSynAck {
+        backup: bool,
+        address_id: u8,
+        hmac: u64,
+        nonce: u32,
+    },
+    JoinAck {
+        hmac: [u8; 20],
+    },
+    Dss {
+        data_ack: Option<u64>,
+        data_ack_64: bool,
+        mapping: Option<Mapping>,
+        dsn_64: bool,
+        data_fin: bool,
+    },
+}
+
+impl MptcpOption {
+    /// Parses an option's value, from the subtype byte on.
+    fn parse(value: &[u8]) -> Option<Self> {
+        let u32_at = |i: usize| Some(u32::from_be_bytes(value.get(i..i + 4)?.try_into().ok()?));
+        let u64_at = |i: usize| Some(u64::from_be_bytes(value.get(i..i + 8)?.try_into().ok()?));
+        let subtype = value.first()? >> 4;
+        let low = value[0] & 0x0f;
+
+        match subtype {
+            MP_CAPABLE if low == VERSION => Some(MptcpOption::Capable {
+                flags: *value.get(1)?,
+                sender_key: u64_at(2),
+                receiver_key: u64_at(10),
+                data_len: value.get(18..20).map(|b| u16::from_be_bytes([b[0], b[1]])),
+            }),
+            MP_JOIN => match value.len() {
+                10 => Some(MptcpOption::JoinSyn {
+                    backup: low & BACKUP != 0,
+                    address_id: value[1],
+                    token: u32_at(2)?,
+                    nonce: u32_at(6)?,
+                }),
+                14 => Some(MptcpOption::JoinSynAck {
+                    backup: low & BACKUP != 0,
+                    address_id: value[1],
+                    hmac: u64_at(2)?,
+                    nonce: u32_at(10)?,
+                }),
+                22 => {
+                    let mut hmac = [0u8; 20];
+                    hmac.clone_from_slice(&value[2..22]);
+                    Some(MptcpOption::JoinAck { hmac })
+                }
+                _ => None,
+            },
+            DSS => {
+                let flags = *value.get(1)?;
+                let mut i = 2;
+                let mut field = |wide: bool| {
+                    let v = if wide {
+                        u64_at(i)
+                    } else {
+                        u32_at(i).map(|v| v as u64)
+                    };
+                    i += if wide { 8 } else { 4 };
+                    v
+                };
+
+                let data_ack = match flags & DATA_ACK_PRESENT {
+                    0 => None,
+                    _ => Some(field(flags & DATA_ACK_64 != 0)?),
+                };
+                let mapping = match flags & DSN_PRESENT {
+                    0 => None,
+                    _ => {
+                        let dsn = field(flags & DSN_64 != 0)?;
+                        let ssn = field(false)? as u32;
+                        let len = value.get(i..i + 2)?;
+                        Some(Mapping {
+                            dsn,
+                            ssn,
+                            len: u16::from_be_bytes([len[0], len[1]]),
+                        })
+                    }
+                };
+                Some(MptcpOption::Dss {
+                    data_ack,
+                    data_ack_64: flags & DATA_ACK_64 != 0,
+                    mapping,
+                    dsn_64: flags & DSN_64 != 0,
+                    data_fin: flags & DATA_FIN != 0,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// The option's value, from the subtype byte on.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        match self {
+            MptcpOption::Capable {
+                flags,
+                sender_key,
+                receiver_key,
+                data_len,
+            } => {
+                v.push(MP_CAPABLE << 4 | VERSION);
+                v.push(*flags);
+                for key in sender_key.iter().chain(receiver_key) {
+                    v.extend_from_slice(&key.to_be_bytes());
+                }
+                if let Some(len) = data_len {
+                    v.extend_from_slice(&len.to_be_bytes());
+                }
+            }
+            MptcpOption::JoinSyn {
+                backup,
+                address_id,
+                token,
+                nonce,
+            } => {
+                v.push(MP_JOIN << 4 | *backup as u8);
+                v.push(*address_id);
+                v.extend_from_slice(&token.to_be_bytes());
+                v.extend_from_slice(&nonce.to_be_bytes());
+            }
+            MptcpOption::JoinSynAck {
+                backup,
+                address_id,
+                hmac,
+                nonce,
+            } => {
+                v.push(MP_JOIN << 4 | *backup as u8);
+                v.push(*address_id);
+                v.extend_from_slice(&hmac.to_be_bytes());
+                v.extend_from_slice(&nonce.to_be_bytes());
+            }
+            MptcpOption::JoinAck { hmac } => {
+                v.push(MP_JOIN << 4);
+                v.push(0);
+                v.extend_from_slice(hmac);
+            }
+            MptcpOption::Dss {
+                data_ack,
+                data_ack_64,
+                mapping,
+                dsn_64,
+                data_fin,
+            } => {
+                let mut flags = 0;
+                if data_ack.is_some() {
+                    flags |= DATA_ACK_PRESENT | if *data_ack_64 { DATA_ACK_64 } else { 0 };
+                }
+                if mapping.is_some() {
+                    flags |= DSN_PRESENT | if *dsn_64 { DSN_64 } else { 0 };
+                }
+                if *data_fin {
+                    flags |= DATA_FIN;
+                }
+                v.push(DSS << 4);
+                v.push(flags);
+
+                let mut field = |value: u64, wide: bool| match wide {
+                    true => v.extend_from_slice(&value.to_be_bytes()),
+                    false => v.extend_from_slice(&(value as u32).to_be_bytes()),
+                };
+                if let Some(ack) = data_ack {
+                    field(*ack, *data_ack_64);
+                }
+                if let Some(m) = mapping {
+                    field(m.dsn, *dsn_64);
+                    field(m.ssn as u64, false);
+                    v.extend_from_slice(&m.len.to_be_bytes());
+                }
+            }
+        }
+        v
+    }
+}
+
+/// The SHA-256 of a key, RFC 8684 section 3.1. Its first 32 bits are the token
+/// naming the key holder's connection, its last 64 the data sequence number the
+/// holder's data starts after.
+fn key_hash(key: u64) -> [u8; 32] {
+    sha256::digest(&[&key.to_be_bytes()])
+}
+
+fn token(key: u64) -> u32 {
+    let hash = key_hash(key);
+    u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]])
+}
+
+fn idsn(key: u64) -> u64 {
+    let mut low = [0u8; 8];
+    low.clone_from_slice(&key_hash(key)[24..]);
+    u64::from_be_bytes(low)
+}
+
+/// The MP_JOIN HMAC from A's point of view, RFC 8684 section 3.2.
+fn join_hmac(key_a: u64, key_b: u64, nonce_a: u32, nonce_b: u32) -> [u8; 32] {
+    let key = [key_a.to_be_bytes(), key_b.to_be_bytes()].concat();
+    let message = [nonce_a.to_be_bytes(), nonce_b.to_be_bytes()].concat();
+    sha256::hmac(&key, &message)
+}
+
+/// Data sequence numbers wrap like subflow ones, and compare the same way.
+fn dsn_lt(lhs: u64, rhs: u64) -> bool {
+    (lhs.wrapping_sub(rhs) as i64) < 0
+}
+
+fn dsn_le(lhs: u64, rhs: u64) -> bool {
+    lhs == rhs || dsn_lt(lhs, rhs)
+}
+
+/// Widens a 32 bit data sequence number to the 64 bit one nearest `near`.
+fn widen(low: u32, near: u64) -> u64 {
+    near.wrapping_add_signed(low.wrapping_sub(near as u32) as i32 as i64)
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Handshake {
+    /// MP_CAPABLE sent in our SYN. Our third ACK repeats both keys until a
+    /// DSS from the peer shows it got them.
+    CapableActive,
+    /// MP_CAPABLE answered in our SYN-ACK, waiting for the peer's key in the third ACK.
+    CapablePassive,
+    /// MP_JOIN sent in our SYN. Data waits for the peer to acknowledge our third ACK.
+    JoinActive {
+        address_id: u8,
+        local_nonce: u32,
+        remote_nonce: u32,
+    },
+    /// MP_JOIN answered in our SYN-ACK, waiting for the peer's HMAC in the third ACK.
+    JoinPassive {
+        address_id: u8,
+        local_nonce: u32,
+        remote_nonce: u32,
+    },
+    Established,
+}
+
+/// What a subflow's connection should do with a segment after its MPTCP option.
+pub enum Verdict {
+    Accept,
+    /// Accept it and acknowledge it straight away.
+    AckNow,
+    /// The option failed authentication, reset the subflow.
+    Reset,
+}
+
+/// The MPTCP state of one subflow, kept in its `Connection`.
+pub struct Subflow {
+    /// Our token for the MPTCP connection this subflow belongs to.
+    token: u32,
+    local_key: u64,
+    remote_key: Option<u64>,
+    handshake: Handshake,
+
+    /// The peer did not take up MPTCP, the subflow is plain TCP.
+    fallback: bool,
+
+    /// Mappings for data queued on this subflow, oldest first.
+    send_mappings: VecDeque<Mapping>,
+
+    /// Mappings the peer sent, by subflow sequence number.
+    recv_mappings: VecDeque<Mapping>,
+
+    /// Relative subflow sequence number of the next byte `map_received` takes.
+    read_ssn: u32,
+
+    /// The Data ACK we send, the next data sequence number we expect.
+    data_ack: u64,
+
+    /// The highest Data ACK the peer has sent on this subflow.
+    remote_data_ack: Option<u64>,
+
+    /// Data sequence number of the peer's DATA_FIN, once seen.
+    remote_data_fin: Option<u64>,
+
+    /// Data sequence number of our DATA_FIN, while it is to be sent on this subflow.
+    data_fin: Option<u64>,
+}
+
+impl Subflow {
+    fn new(token: u32, local_key: u64, remote_key: Option<u64>, handshake: Handshake) -> Self {
+        Subflow {
+            token,
+            local_key,
+            remote_key,
+            handshake,
+            fallback: false,
+            send_mappings: VecDeque::new(),
+            recv_mappings: VecDeque::new(),
+            read_ssn: 1,
+            data_ack: remote_key.map_or(0, |key| idsn(key).wrapping_add(1)),
+            remote_data_ack: None,
+            remote_data_fin: None,
+            data_fin: None,
+        }
+    }
+
+    /// The first subflow of a connection, opened with MP_CAPABLE.
+    fn capable(token: u32, local_key: u64, passive: bool) -> Self {
+        let handshake = match passive {
+            true => Handshake::CapablePassive,
+            false => Handshake::CapableActive,
+        };
+        Subflow::new(token, local_key, None, handshake)
+    }
+
+    fn join_active(token: u32, keys: (u64, u64), address_id: u8, nonce: u32) -> Self {
+        let handshake = Handshake::JoinActive {
+            address_id,
+            local_nonce: nonce,
+            remote_nonce: 0,
+        };
+        Subflow::new(token, keys.0, Some(keys.1), handshake)
+    }
+
+    fn join_passive(token: u32, keys: (u64, u64), address_id: u8, nonces: (u32, u32)) -> Self {
+        let handshake = Handshake::JoinPassive {
+            address_id,
+            local_nonce: nonces.0,
+            remote_nonce: nonces.1,
+        };
+        Subflow::new(token, keys.0, Some(keys.1), handshake)
+    }
+
+    pub fn token(&self) -> u32 {
+        self.token
+    }
+
+    pub fn is_fallback(&self) -> bool {
+        self.fallback
+    }
+
+    /// Whether the handshake is far enough along for data to be sent.
+    pub fn is_usable(&self) -> bool {
+        self.fallback
+            || (self.remote_key.is_some()
+                && matches!(
+                    self.handshake,
+                    Handshake::CapableActive | Handshake::Established
+                ))
+    }
+
+    fn fall_back(&mut self) {
+        self.fallback = true;
+        self.handshake = Handshake::Established;
+        self.data_ack = 0;
+    }
+
+    /// The MPTCP option for a segment we are about to send, `rel_seq` being its
+    /// sequence number relative to our ISN.
+    pub fn option(&mut self, syn: bool, ack: bool, rel_seq: u32, len: usize) -> Option<Vec<u8>> {
+        if self.fallback {
+            return None;
+        }
+        let option = match (self.handshake, syn) {
+            (Handshake::CapableActive, true) if !ack => MptcpOption::Capable {
+                flags: HMAC_SHA256,
+                sender_key: None,
+                receiver_key: None,
+                data_len: None,
+            },
+            (Handshake::CapablePassive, true) => MptcpOption::Capable {
+                flags: HMAC_SHA256,
+                sender_key: Some(self.local_key),
+                receiver_key: None,
+                data_len: None,
+            },
+            (
+                Handshake::JoinActive {
+                    address_id,
+                    local_nonce,
+                    ..
+                },
+                true,
+            ) if !ack => MptcpOption::JoinSyn {
+                backup: false,
+                address_id,
+                token: token(self.remote_key?),
+                nonce: local_nonce,
+            },
+            (
+                Handshake::JoinPassive {
+                    address_id,
+                    local_nonce,
+                    remote_nonce,
+                },
+                true,
+            ) => {
+                let hmac = join_hmac(self.local_key, self.remote_key?, local_nonce, remote_nonce);
+                MptcpOption::JoinSynAck {
+                    backup: false,
+                    address_id,
+                    hmac: u64::from_be_bytes(hmac[..8].try_into().ok()?),
+                    nonce: local_nonce,
+                }
+            }
+            (_, true) => return None,
+            (Handshake::CapableActive, false) if len == 0 => MptcpOption::Capable {
+                flags: HMAC_SHA256,
+                sender_key: Some(self.local_key),
+                receiver_key: Some(self.remote_key?),
+                data_len: None,
+            },
+            (
+                Handshake::JoinActive {
+                    local_nonce,
+                    remote_nonce,
+                    ..
+                },
+                false,
+            ) if len == 0 => {
+                let hmac = join_hmac(self.local_key, self.remote_key?, local_nonce, remote_nonce);
+                let mut truncated = [0u8; 20];
+                truncated.clone_from_slice(&hmac[..20]);
+                MptcpOption::JoinAck { hmac: truncated }
+            }
+            _ => {
+                self.remote_key?;
+                let mapping = match len {
+                    0 => self.data_fin.map(|fin| Mapping {
+                        dsn: fin,
+                        ssn: 0,
+                        len: 1,
+                    }),
+                    _ => self
+                        .send_mappings
+                        .iter()
+                        .find(|m| rel_seq.wrapping_sub(m.ssn) < m.len as u32)
+                        .copied(),
+                };
+                MptcpOption::Dss {
+                    data_ack: Some(self.data_ack),
+                    data_ack_64: true,
+                    mapping,
+                    dsn_64: true,
+                    data_fin: len == 0 && self.data_fin.is_some(),
+                }
+            }
+        };
+        Some(option.to_bytes())
+    }
+
+    /// Takes the MPTCP option of the SYN-ACK answering our SYN. Returns false if
+    /// the subflow must be reset.
+    pub fn on_syn_ack(&mut self, options: &TcpOptions) -> bool {
+        let option = options.mptcp.as_deref().and_then(MptcpOption::parse);
+        match (self.handshake, option) {
+            (
+                Handshake::CapableActive,
+                Some(MptcpOption::Capable {
+                    flags,
+                    sender_key: Some(key),
+                    ..
+                }),
+            ) if flags & CHECKSUM_REQUIRED == 0 => {
+                self.remote_key = Some(key);
+                self.data_ack = idsn(key).wrapping_add(1);
+                true
+            }
+            (Handshake::CapableActive, _) => {
+                self.fall_back();
+                true
+            }
+            (
+                Handshake::JoinActive {
+                    address_id,
+                    local_nonce,
+                    ..
+                },
+                Some(MptcpOption::JoinSynAck { hmac, nonce, .. }),
+            ) => {
+                let remote_key = match self.remote_key {
+                    Some(key) => key,
+                    None => return false,
+                };
+                let expected = join_hmac(remote_key, self.local_key, nonce, local_nonce);
+                if expected[..8] != hmac.to_be_bytes() {
+                    println!("[MPTCP] bad MP_JOIN HMAC in SYN-ACK");
+                    return false;
+                }
+                self.handshake = Handshake::JoinActive {
+                    address_id,
+                    local_nonce,
+                    remote_nonce: nonce,
+                };
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Takes the MPTCP option of an acceptable segment past the SYN.
+    pub fn on_segment(&mut self, options: &TcpOptions) -> Verdict {
+        if self.fallback {
+            return Verdict::Accept;
+        }
+        let option = options.mptcp.as_deref().and_then(MptcpOption::parse);
+
+        match (self.handshake, &option) {
+            (
+                Handshake::CapablePassive,
+                Some(MptcpOption::Capable {
+                    sender_key: Some(key),
+                    receiver_key: Some(ours),
+                    data_len,
+                    ..
+                }),
+            ) if *ours == self.local_key => {
+                self.remote_key = Some(*key);
+                self.data_ack = idsn(*key).wrapping_add(1);
+                self.handshake = Handshake::Established;
+                // A third ACK lost on the way turns into MP_CAPABLE on the first data.
+                if let Some(len) = data_len.filter(|len| *len > 0) {
+                    self.recv_mappings.push_back(Mapping {
+                        dsn: self.data_ack,
+                        ssn: 1,
+                        len,
+                    });
+                }
+                return Verdict::Accept;
+            }
+            (Handshake::CapablePassive, _) => {
+                self.fall_back();
+                return Verdict::Accept;
+            }
+            (
+                Handshake::JoinPassive {
+                    local_nonce,
+                    remote_nonce,
+                    ..
+                },
+                Some(MptcpOption::JoinAck { hmac }),
+            ) => {
+                let remote_key = match self.remote_key {
+                    Some(key) => key,
+                    None => return Verdict::Reset,
+                };
+                let expected = join_hmac(remote_key, self.local_key, remote_nonce, local_nonce);
+                if expected[..20] != hmac[..] {
+                    println!("[MPTCP] bad MP_JOIN HMAC in third ACK");
+                    return Verdict::Reset;
+                }
+                // The peer holds back data until it sees this acknowledged.
+                self.handshake = Handshake::Established;
+                return Verdict::AckNow;
+            }
+            (Handshake::JoinPassive { .. }, _) => return Verdict::Reset,
+            (Handshake::JoinActive { .. }, _)
+            | (Handshake::CapableActive, Some(MptcpOption::Dss { .. })) => {
+                self.handshake = Handshake::Established;
+            }
+            _ => {}
+        }
+
+        match option {
+            Some(MptcpOption::Dss {
+                data_ack,
+                data_ack_64,
+                mapping,
+                dsn_64,
+                data_fin,
+            }) => {
+                if let Some(ack) = data_ack {
+                    let near = self
+                        .remote_data_ack
+                        .unwrap_or_else(|| idsn(self.local_key).wrapping_add(1));
+                    self.on_data_ack(if data_ack_64 {
+                        ack
+                    } else {
+                        widen(ack as u32, near)
+                    });
+                }
+                if let Some(mut mapping) = mapping {
+                    if !dsn_64 {
+                        mapping.dsn = widen(mapping.dsn as u32, self.data_ack);
+                    }
+                    return self.on_mapping(mapping, data_fin);
+                }
+                Verdict::Accept
+            }
+            _ => Verdict::Accept,
+        }
+    }
+
+    fn on_data_ack(&mut self, ack: u64) {
+        if self.remote_data_ack.is_none_or(|old| dsn_lt(old, ack)) {
+            self.remote_data_ack = Some(ack);
+        }
+        if self.data_fin.is_some_and(|fin| dsn_lt(fin, ack)) {
+            self.data_fin = None;
+        }
+    }
+
+    fn on_mapping(&mut self, mut mapping: Mapping, data_fin: bool) -> Verdict {
+        if data_fin && mapping.len > 0 {
+            let fin = mapping.dsn.wrapping_add(mapping.len as u64 - 1);
+            self.remote_data_fin = Some(fin);
+            mapping.len -= 1;
+            if dsn_lt(fin, self.data_ack) {
+                // We acknowledged it before, the acknowledgment must have been lost.
+                return Verdict::AckNow;
+            }
+        }
+        let end = mapping.ssn.wrapping_add(mapping.len as u32);
+        if mapping.len == 0
+            || seq_le(end, self.read_ssn)
+            || self.recv_mappings.iter().any(|m| m.ssn == mapping.ssn)
+        {
+            return Verdict::Accept;
+        }
+        let at = self
+            .recv_mappings
+            .iter()
+            .position(|m| seq_lt(mapping.ssn, m.ssn))
+            .unwrap_or(self.recv_mappings.len());
+        self.recv_mappings.insert(at, mapping);
+        Verdict::Accept
+    }
+
+    /// Moves the Data ACK along as the subflow receives in order, `rel_nxt` being
+    /// the next subflow sequence number expected, relative to the peer's ISN.
+    /// What other subflows brought in only counts once the connection has taken
+    /// it, see `set_data_ack`.
+    pub fn on_in_order(&mut self, rel_nxt: u32) {
+        if self.fallback {
+            return;
+        }
+        while let Some(m) = self
+            .recv_mappings
+            .iter()
+            .find(|m| self.data_ack.wrapping_sub(m.dsn) < m.len as u64)
+            .copied()
+        {
+            if seq_le(rel_nxt, m.ssn) {
+                return;
+            }
+            let received = rel_nxt.wrapping_sub(m.ssn).min(m.len as u32);
+            let end = m.dsn.wrapping_add(received as u64);
+            if dsn_le(end, self.data_ack) {
+                return;
+            }
+            self.data_ack = end;
+        }
+    }
+
+    /// Raises the Data ACK to what the connection has received over every subflow.
+    pub fn set_data_ack(&mut self, ack: u64) {
+        if dsn_lt(self.data_ack, ack) {
+            self.data_ack = ack;
+        }
+    }
+
+    /// Records that `len` bytes from relative subflow sequence number `ssn` carry
+    /// data from `dsn` on.
+    pub fn on_write(&mut self, dsn: u64, ssn: u32, len: usize) {
+        self.send_mappings.push_back(Mapping {
+            dsn,
+            ssn,
+            len: len as u16,
+        });
+    }
+
+    /// Forgets mappings the peer has acknowledged on the subflow. A fallen back
+    /// subflow has no Data ACKs, there the subflow acknowledgment stands in for one.
+    pub fn on_acked(&mut self, rel_una: u32) {
+        while let Some(m) = self.send_mappings.front().copied() {
+            if self.fallback && seq_le(m.ssn, rel_una) {
+                let acked = rel_una.wrapping_sub(m.ssn).min(m.len as u32);
+                self.on_data_ack(m.dsn.wrapping_add(acked as u64));
+            }
+            if seq_lt(rel_una, m.ssn.wrapping_add(m.len as u32)) {
+                return;
+            }
+            self.send_mappings.pop_front();
+        }
+    }
+
+    /// Splits data read off the subflow into runs by data sequence number.
+    /// Bytes no mapping covers have no place in the stream and are dropped.
+    pub fn map_received(&mut self, mut data: &[u8]) -> Vec<(u64, Vec<u8>)> {
+        let mut runs = Vec::new();
+        if self.fallback {
+            if !data.is_empty() {
+                runs.push((self.data_ack, data.to_vec()));
+                self.data_ack = self.data_ack.wrapping_add(data.len() as u64);
+            }
+            return runs;
+        }
+
+        while !data.is_empty() {
+            let ssn = self.read_ssn;
+            let n = match self
+                .recv_mappings
+                .iter()
+                .find(|m| ssn.wrapping_sub(m.ssn) < m.len as u32)
+            {
+                Some(m) => {
+                    let offset = ssn.wrapping_sub(m.ssn);
+                    let n = ((m.len as u32 - offset) as usize).min(data.len());
+                    runs.push((m.dsn.wrapping_add(offset as u64), data[..n].to_vec()));
+                    n
+                }
+                None => data.len(),
+            };
+            self.read_ssn = ssn.wrapping_add(n as u32);
+            data = &data[n..];
+        }
+
+        let read_ssn = self.read_ssn;
+        self.recv_mappings
+            .retain(|m| seq_lt(read_ssn, m.ssn.wrapping_add(m.len as u32)));
+        runs
+    }
+
+    pub fn remote_data_ack(&self) -> Option<u64> {
+        self.remote_data_ack
+    }
+
+    pub fn remote_data_fin(&self) -> Option<u64> {
+        self.remote_data_fin
+    }
+
+    /// Sends our DATA_FIN, at `dsn`, on this subflow's pure ACKs until acknowledged.
+    pub fn set_data_fin(&mut self, dsn: u64) {
+        self.data_fin = Some(dsn);
+    }
+}
+
+/// Connection level state of an MPTCP connection.
+struct MptcpConnection {
+    local_key: u64,
+    remote_key: Option<u64>,
+    passive: bool,
+
+    /// The peer did not take up MPTCP, the one subflow is plain TCP.
+    fallback: bool,
+
+    /// A passive connection has been queued for `mptcp_accept`.
+    announced: bool,
+
+    /// The subflows, the first being the one opened with MP_CAPABLE.
+    subflows: Vec<Quad>,
+
+    next_address_id: u8,
+
+    /// Data sequence number of the first byte in `unacked`.
+    snd_una: u64,
+
+    /// The next data sequence number to hand to a subflow.
+    snd_nxt: u64,
+
+    /// Data written by the application and not yet covered by a Data ACK.
+    unacked: VecDeque<u8>,
+
+    /// The application has closed its side, a DATA_FIN follows the data.
+    fin_queued: bool,
+
+    /// When our DATA_FIN was last sent.
+    fin_sent_at: Option<Instant>,
+    fin_acked: bool,
+
+    /// The next data sequence number expected.
+    rcv_nxt: u64,
+
+    /// Data that arrived ahead of `rcv_nxt`, over another subflow usually.
+    out_of_order: BTreeMap<u64, Vec<u8>>,
+
+    /// In order data not yet read by the application.
+    incoming: VecDeque<u8>,
+
+    /// The peer's DATA_FIN has been reached.
+    read_closed: bool,
+
+    /// The subflows have been closed after both DATA_FINs.
+    subflows_closed: bool,
+}
+
+impl MptcpConnection {
+    fn new(local_key: u64, passive: bool, first: Quad) -> Self {
+        let isn = idsn(local_key).wrapping_add(1);
+        MptcpConnection {
+            local_key,
+            remote_key: None,
+            passive,
+            fallback: false,
+            announced: false,
+            subflows: vec![first],
+            next_address_id: 1,
+            snd_una: isn,
+            snd_nxt: isn,
+            unacked: VecDeque::new(),
+            fin_queued: false,
+            fin_sent_at: None,
+            fin_acked: false,
+            rcv_nxt: 0,
+            out_of_order: BTreeMap::new(),
+            incoming: VecDeque::new(),
+            read_closed: false,
+            subflows_closed: false,
+        }
+    }
+
+    /// The data sequence number following the last byte written.
+    fn data_end(&self) -> u64 {
+        self.snd_una.wrapping_add(self.unacked.len() as u64)
+    }
+
+    fn on_data_ack(&mut self, ack: u64) {
+        if dsn_le(ack, self.snd_una) {
+            return;
+        }
+        let acked = (ack.wrapping_sub(self.snd_una) as usize).min(self.unacked.len());
+        self.unacked.drain(..acked);
+        self.snd_una = self.snd_una.wrapping_add(acked as u64);
+        if dsn_lt(self.snd_nxt, self.snd_una) {
+            self.snd_nxt = self.snd_una;
+        }
+        if self.fin_sent_at.is_some() && dsn_lt(self.data_end(), ack) {
+            self.fin_acked = true;
+        }
+    }
+
+    /// Accepts data into the connection's stream, holding on to anything early.
+    /// Anything past the receive buffer is dropped, the peer sends it again.
+    fn receive(&mut self, dsn: u64, mut data: Vec<u8>) {
+        let limit = self.rcv_nxt.wrapping_add(RECV_BUFFER_SIZE as u64);
+        if !dsn_lt(dsn, limit) {
+            return;
+        }
+        if dsn_lt(limit, dsn.wrapping_add(data.len() as u64)) {
+            data.truncate(limit.wrapping_sub(dsn) as usize);
+        }
+        let end = dsn.wrapping_add(data.len() as u64);
+        if dsn_le(end, self.rcv_nxt) {
+            return;
+        }
+        if dsn_lt(self.rcv_nxt, dsn) {
+            self.out_of_order.insert(dsn, data);
+            return;
+        }
+        let skip = self.rcv_nxt.wrapping_sub(dsn) as usize;
+        self.incoming.extend(&data[skip..]);
+        self.rcv_nxt = end;
+
+        // Keys are in numeric order, which past the wrap isn't stream order.
+        while let Some(early) = self.next_early() {
+            let data = self.out_of_order.remove(&early).unwrap_or_default();
+            self.receive(early, data);
+        }
+    }
+
+    /// Received data held, in order or not, counted against `RECV_BUFFER_SIZE`.
+    fn buffered(&self) -> usize {
+        let early: usize = self.out_of_order.values().map(Vec::len).sum();
+        self.incoming.len() + early
+    }
+
+    /// Data held back that now follows on, or that turned out to be old.
+    fn next_early(&self) -> Option<u64> {
+        let rcv_nxt = self.rcv_nxt;
+        self.out_of_order
+            .keys()
+            .copied()
+            .find(|&early| dsn_le(early, rcv_nxt))
+    }
+
+    /// Hands unsent data to subflows, each time to the usable one with the lowest
+    /// smoothed RTT that has room in its windows.
+    fn schedule(
+        &mut self,
+        connections: &mut HashMap<Quad, Connection>,
+        now: Instant,
+        out: &mut VecDeque<Segment>,
+    ) {
+        while dsn_lt(self.snd_nxt, self.data_end()) {
+            let best = self
+                .subflows
+                .iter()
+                .filter_map(|quad| connections.get(quad).map(|c| (quad, c)))
+                .filter(|(_, c)| c.is_mptcp_usable() && c.send_space() > 0)
+                .min_by_key(|(_, c)| {
+                    let info = c.info();
+                    info.srtt.unwrap_or(info.rto)
+                })
+                .map(|(quad, _)| *quad);
+            let c = match best.and_then(|quad| connections.get_mut(&quad)) {
+                Some(c) => c,
+                None => return,
+            };
+
+            let offset = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            let len = (self.unacked.len() - offset)
+                .min(c.send_space())
+                .min(u16::MAX as usize);
+            let data: Vec<u8> = self.unacked.range(offset..offset + len).copied().collect();
+            c.write_mapped(self.snd_nxt, &data, now, out);
+            self.snd_nxt = self.snd_nxt.wrapping_add(len as u64);
+        }
+    }
+}
+
+/// MPTCP state shared by every connection.
+pub struct Mptcp {
+    /// Whether SYNs with MP_CAPABLE are answered in kind.
+    enabled: bool,
+
+    /// Keys the hash keys and nonces are drawn from.
+    secret: RandomState,
+    draws: u64,
+
+    /// MPTCP connections by our token.
+    connections: HashMap<u32, MptcpConnection>,
+
+    /// Connections the peer opened that have not been picked up by `mptcp_accept`.
+    established: VecDeque<u32>,
+}
+
+impl Default for Mptcp {
+    fn default() -> Self {
+        Mptcp::new()
+    }
+}
+
+impl Mptcp {
+    pub fn new() -> Self {
+        Mptcp {
+            enabled: false,
+            secret: RandomState::new(),
+            draws: 0,
+            connections: HashMap::new(),
+            established: VecDeque::new(),
+        }
+    }
+
+    fn random(&mut self) -> u64 {
+        self.draws += 1;
+        self.secret.hash_one(self.draws)
+    }
+
+    /// A fresh key whose token no other connection of ours has.
+    fn new_key(&mut self) -> (u64, u32) {
+        loop {
+            let key = self.random();
+            if !self.connections.contains_key(&token(key)) {
+                return (key, token(key));
+            }
+        }
+    }
+
+    /// Sets up MPTCP on a connection a SYN opened, if the SYN asks for it. Returns
+    /// false if the SYN must be refused, an MP_JOIN for a connection we don't have
+    /// or on an MD5 signed one, whose options wouldn't fit alongside the signature.
+    pub fn on_syn(&mut self, quad: Quad, options: &TcpOptions, c: &mut Connection) -> bool {
+        let option = options.mptcp.as_deref().and_then(MptcpOption::parse);
+        match option {
+            Some(MptcpOption::Capable {
+                flags,
+                sender_key: None,
+                ..
+            }) if self.enabled && flags & CHECKSUM_REQUIRED == 0 && c.md5_key().is_none() => {
+                let (key, token) = self.new_key();
+                c.set_mptcp(Subflow::capable(token, key, true));
+                self.connections
+                    .insert(token, MptcpConnection::new(key, true, quad));
+                true
+            }
+            Some(MptcpOption::JoinSyn { token, nonce, .. }) if c.md5_key().is_none() => {
+                let local_nonce = self.random() as u32;
+                let m = match self.connections.get_mut(&token) {
+                    Some(m) if !m.fallback && !m.subflows_closed => m,
+                    _ => return false,
+                };
+                let remote_key = match m.remote_key {
+                    Some(key) => key,
+                    None => return false,
+                };
+                let keys = (m.local_key, remote_key);
+                let mut subflow =
+                    Subflow::join_passive(token, keys, m.next_address_id, (local_nonce, nonce));
+                subflow.set_data_ack(m.rcv_nxt);
+                m.next_address_id = m.next_address_id.wrapping_add(1);
+                m.subflows.push(quad);
+                c.set_mptcp(subflow);
+                true
+            }
+            Some(MptcpOption::JoinSyn { .. }) => false,
+            _ => true,
+        }
+    }
+
+    pub fn tokens(&self) -> impl Iterator<Item = u32> + '_ {
+        self.connections.keys().copied()
+    }
+
+    /// Drops a closed subflow from its connection, and the connection along with
+    /// its last subflow once everything received has been read.
+    pub fn on_subflow_closed(&mut self, token: u32, quad: &Quad) {
+        if let Some(m) = self.connections.get_mut(&token) {
+            m.subflows.retain(|q| q != quad);
+            if m.subflows.is_empty() {
+                m.read_closed = true;
+                if m.incoming.is_empty() {
+                    self.connections.remove(&token);
+                }
+            }
+        }
+    }
+}
+
+impl Connections {
+    /// Answers MP_CAPABLE in SYNs, so peers that ask get an MPTCP connection,
+    /// picked up with `mptcp_accept`.
+    pub fn set_mptcp(&mut self, enabled: bool) {
+        self.mptcp.enabled = enabled;
+    }
+
+    /// Opens an MPTCP connection from `local` to `remote`, returning its token,
+    /// which names it to the other `mptcp_` calls. If the peer does not answer
+    /// with MP_CAPABLE the connection carries on as plain TCP.
+    pub fn mptcp_connect(&mut self, local: (u32, u16), remote: (u32, u16)) -> io::Result<u32> {
+        let quad = self.bind(local, remote)?;
+        let (key, token) = self.mptcp.new_key();
+        let iss = self.initial_sequence_number(&quad);
+        let mut c = Connection::connect(quad, iss);
+        c.set_mptcp(Subflow::capable(token, key, false));
+        self.mptcp
+            .connections
+            .insert(token, MptcpConnection::new(key, false, quad));
+        self.open(quad, c);
+        Ok(token)
+    }
+
+    /// Adds a subflow to an MPTCP connection from another local address, with
+    /// MP_JOIN, to the address and port the connection was opened to.
+    /// `WouldBlock` until the first subflow's handshake is done.
+    pub fn mptcp_join(&mut self, token: u32, local_ip: u32) -> io::Result<Quad> {
+        let nonce = self.mptcp.random() as u32;
+        let m = mptcp_lookup(&mut self.mptcp.connections, token)?;
+        let remote_key = match (m.remote_key, m.fallback) {
+            (_, true) => return Err(io::Error::from(io::ErrorKind::Unsupported)),
+            (None, _) => return Err(io::Error::from(io::ErrorKind::WouldBlock)),
+            (Some(key), _) => key,
+        };
+        let first = *m
+            .subflows
+            .first()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        let quad = Quad {
+            local: (local_ip, first.local.1),
+            remote: first.remote,
+        };
+        if self.connections.contains_key(&quad) {
+            return Err(io::Error::from(io::ErrorKind::AddrInUse));
+        }
+
+        let keys = (m.local_key, remote_key);
+        let mut subflow = Subflow::join_active(token, keys, m.next_address_id, nonce);
+        subflow.set_data_ack(m.rcv_nxt);
+        m.next_address_id = m.next_address_id.wrapping_add(1);
+        m.subflows.push(quad);
+
+        let iss = self.initial_sequence_number(&quad);
+        let mut c = Connection::connect(quad, iss);
+        c.set_mptcp(subflow);
+        self.open(quad, c);
+        Ok(quad)
+    }
+
+    /// Takes the next MPTCP connection a peer opened.
+    pub fn mptcp_accept(&mut self) -> Option<u32> {
+        self.mptcp.established.pop_front()
+    }
+
+    /// An MPTCP connection's subflows, the first being the one it was opened with.
+    pub fn mptcp_subflows(&self, token: u32) -> Vec<Quad> {
+        self.mptcp
+            .connections
+            .get(&token)
+            .map(|m| m.subflows.clone())
+            .unwrap_or_default()
+    }
+
+    /// Queues data on an MPTCP connection for the scheduler to spread over its subflows.
+    pub fn mptcp_send(&mut self, token: u32, data: &[u8]) -> io::Result<usize> {
+        let m = mptcp_lookup(&mut self.mptcp.connections, token)?;
+        if m.fin_queued {
+            return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+        }
+        m.unacked.extend(data);
+        self.mptcp_update(token, Instant::now());
+        Ok(data.len())
+    }
+
+    /// Reads data received on an MPTCP connection, in order whichever subflows it
+    /// came over. Returns 0 once the peer's DATA_FIN is reached and `WouldBlock`
+    /// if there is nothing yet.
+    pub fn mptcp_recv(&mut self, token: u32, buf: &mut [u8]) -> io::Result<usize> {
+        let m = mptcp_lookup(&mut self.mptcp.connections, token)?;
+        let n = buf.len().min(m.incoming.len());
+        for (b, byte) in buf.iter_mut().zip(m.incoming.drain(..n)) {
+            *b = byte;
+        }
+        let read_closed = m.read_closed;
+        if m.subflows.is_empty() && m.incoming.is_empty() {
+            self.mptcp.connections.remove(&token);
+        } else {
+            // The room made may let more be taken off the subflows.
+            self.mptcp_update(token, Instant::now());
+        }
+        match n {
+            0 if !read_closed => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+            n => Ok(n),
+        }
+    }
+
+    /// Closes our side of an MPTCP connection, with a DATA_FIN once queued data has
+    /// been handed to the subflows. They are closed once both DATA_FINs are through.
+    pub fn mptcp_close(&mut self, token: u32) -> io::Result<()> {
+        mptcp_lookup(&mut self.mptcp.connections, token)?.fin_queued = true;
+        self.mptcp_update(token, Instant::now());
+        Ok(())
+    }
+
+    /// Brings an MPTCP connection up to date with its subflows, after segments
+    /// arrive on them or the application has been at it: picks up keys and Data
+    /// ACKs, moves received data into the connection, schedules data to send and
+    /// works through the DATA_FIN exchange.
+    pub(super) fn mptcp_update(&mut self, token: u32, now: Instant) {
+        let Connections {
+            connections,
+            mptcp,
+            outbound,
+            ..
+        } = self;
+        let m = match mptcp.connections.get_mut(&token) {
+            Some(m) => m,
+            None => return,
+        };
+
+        for quad in m.subflows.clone() {
+            let c = match connections.get_mut(&quad) {
+                Some(c) => c,
+                None => continue,
+            };
+            let subflow = match c.mptcp() {
+                Some(subflow) => subflow,
+                None => continue,
+            };
+            if m.remote_key.is_none() {
+                m.remote_key = subflow.remote_key;
+                if let Some(key) = m.remote_key {
+                    m.rcv_nxt = idsn(key).wrapping_add(1);
+                }
+            }
+            m.fallback |= subflow.is_fallback();
+            if let Some(ack) = subflow.remote_data_ack() {
+                m.on_data_ack(ack);
+            }
+            let remote_fin = subflow.remote_data_fin();
+
+            let room = RECV_BUFFER_SIZE.saturating_sub(m.buffered());
+            for (dsn, data) in c.read_mapped(room, outbound) {
+                m.receive(dsn, data);
+            }
+            if m.fallback && c.is_read_closed() {
+                m.read_closed = true;
+            }
+            if !m.read_closed && remote_fin == Some(m.rcv_nxt) {
+                m.rcv_nxt = m.rcv_nxt.wrapping_add(1);
+                m.read_closed = true;
+                c.ack_now(outbound);
+            }
+        }
+
+        for quad in &m.subflows {
+            if let Some(subflow) = connections.get_mut(quad).and_then(|c| c.mptcp_mut()) {
+                subflow.set_data_ack(m.rcv_nxt);
+            }
+        }
+
+        if m.passive && !m.announced && (m.remote_key.is_some() || m.fallback) {
+            m.announced = true;
+            mptcp.established.push_back(token);
+        }
+
+        m.schedule(connections, now, outbound);
+
+        let all_sent = m.fin_queued && m.snd_nxt == m.data_end();
+        if all_sent
+            && !m.fallback
+            && !m.fin_acked
+            && m.fin_sent_at.is_none_or(|at| at + DATA_FIN_TIMEOUT <= now)
+        {
+            let usable = m
+                .subflows
+                .iter()
+                .find(|quad| connections.get(quad).is_some_and(|c| c.is_mptcp_usable()));
+            if let Some(c) = usable.and_then(|quad| connections.get_mut(quad)) {
+                if let Some(subflow) = c.mptcp_mut() {
+                    subflow.set_data_fin(m.data_end());
+                }
+                c.ack_now(outbound);
+                m.fin_sent_at = Some(now);
+            }
+        }
+
+        let done = match m.fallback {
+            true => all_sent,
+            false => m.fin_acked && m.read_closed,
+        };
+        if done && !m.subflows_closed {
+            m.subflows_closed = true;
+            for quad in &m.subflows {
+                if let Some(c) = connections.get_mut(quad) {
+                    c.close(now, outbound);
+                }
+            }
+        }
+    }
+}
+
+fn mptcp_lookup(
+    connections: &mut HashMap<u32, MptcpConnection>,
+    token: u32,
+) -> io::Result<&mut MptcpConnection> {
+    connections
+        .get_mut(&token)
+        .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))
+}
+
+#[cfg(test)]
+#[test]
+fn test_option_round_trip() {
+    let options = [
+        MptcpOption::Capable {
+            flags: HMAC_SHA256,
+            sender_key: Some(1),
+            receiver_key: Some(2),
+            data_len: None,
+        },
+        MptcpOption::JoinSyn {
+            backup: true,
+            address_id: 3,
+            token: 4,
+            nonce: 5,
+        },
+        MptcpOption::JoinSynAck {
+            backup: false,
+            address_id: 3,
+            hmac: 6,
+            nonce: 7,
+        },
+        MptcpOption::JoinAck { hmac: [8; 20] },
+        MptcpOption::Dss {
+            data_ack: Some(9),
+            data_ack_64: true,
+            mapping: Some(Mapping {
+                dsn: 10,
+                ssn: 11,
+                len: 12,
+            }),
+            dsn_64: false,
+            data_fin: true,
+        },
+    ];
+    for option in options {
+        assert_eq!(MptcpOption::parse(&option.to_bytes()), Some(option));
+    }
+    assert_eq!(widen(5, 0x1_ffff_fff0), 0x2_0000_0005);
+    assert_eq!(widen(0xffff_fff0, 0x2_0000_0005), 0x1_ffff_fff0);
+}
+
+#[cfg(test)]
+#[test]
+fn test_dsn_wrap() {
+    let quad = Quad {
+        local: (0x0a000002, 40000),
+        remote: (0x0a000001, 80),
+    };
+    let start = u64::MAX - 1;
+
+    // A mapping across the wrap is acknowledged and read as one run.
+    let mut subflow = Subflow::new(1, 2, Some(3), Handshake::Established);
+    subflow.data_ack = start;
+    let mapping = Mapping {
+        dsn: start,
+        ssn: 1,
+        len: 4,
+    };
+    subflow.on_mapping(mapping, false);
+    subflow.on_in_order(5);
+    assert_eq!(subflow.data_ack, 2);
+    assert_eq!(
+        subflow.map_received(b"abcd"),
+        vec![(start, b"abcd".to_vec())]
+    );
+
+    // Data past the wrap waits for what comes before it.
+    let mut m = MptcpConnection::new(2, false, quad);
+    m.rcv_nxt = start;
+    m.receive(1, b"de".to_vec());
+    m.receive(start, b"abc".to_vec());
+    assert_eq!(m.rcv_nxt, 3);
+    assert_eq!(m.incoming, b"abcde");
+    assert!(m.out_of_order.is_empty());
+
+    // Nothing past the receive buffer is held, early data counts against it.
+    let limit = 3 + RECV_BUFFER_SIZE as u64;
+    m.receive(limit, b"f".to_vec());
+    m.receive(limit - 1, b"gh".to_vec());
+    assert_eq!(m.out_of_order[&(limit - 1)], b"g");
+    assert_eq!(m.buffered(), 6);
+
+    // Data ACKs past the wrap acknowledge what is before it, older ones nothing.
+    m.snd_una = start;
+    m.snd_nxt = start;
+    m.unacked.extend(b"hello");
+    m.on_data_ack(2);
+    assert_eq!((m.snd_una, m.snd_nxt, m.unacked.len()), (2, 2, 1));
+    m.on_data_ack(u64::MAX);
+    assert_eq!(m.snd_una, 2);
+}
+
+#[test]
+fn test_join_md5() {
+    use super::tests::exchange;
+    use super::{build_segment, TcpHeader, TcpHeaderFlags, TcpPacketSlice};
+
+    let server_addr = (0x0a000001, 80);
+    let mut client = Connections::new();
+    let mut server = Connections::new();
+    server.set_mptcp(true);
+    client
+        .mptcp_connect((0x0a000002, 40000), server_addr)
+        .unwrap();
+    exchange(&mut client, &mut server);
+    let token = server.mptcp_accept().unwrap();
+
+    // A signed MP_JOIN is refused, its SYN-ACK has no room for the join.
+    let key = b"bgp peering secret";
+    server.set_md5_key(80, 0x0a000004, Some(key)).unwrap();
+    let join = MptcpOption::JoinSyn {
+        backup: false,
+        address_id: 1,
+        token,
+        nonce: 1,
+    };
+    let options = TcpOptions {
+        mss: Some(1460),
+        window_scale: Some(7),
+        mptcp: Some(join.to_bytes()),
+        ..TcpOptions::default()
+    };
+    let mut flags = TcpHeaderFlags::new();
+    flags.syn = true;
+    let from = Quad {
+        local: (0x0a000004, 40000),
+        remote: server_addr,
+    };
+    let header = TcpHeader::new(1000, 0, flags, 65535);
+    let syn = build_segment(from, header, &options, &[], Some(key));
+    let quad = Quad {
+        local: server_addr,
+        remote: from.local,
+    };
+    server.on_segment(quad, &syn.data, Instant::now());
+    let reply = server.poll_transmit().unwrap();
+    let header = TcpHeader::from_slice(&TcpPacketSlice { slice: &reply.data });
+    assert!(header.flags.rst);
+    assert_eq!(server.mptcp_subflows(token).len(), 1);
+}
@@ -0,0 +1,196 @@
+// Parsing and writing of the TCP options we understand.
+// Anything else is skipped over using its length byte.
+
+/// Option kinds, see https://www.iana.org/assignments/tcp-parameters
+const END_OF_LIST: u8 = 0;
+const NO_OPERATION: u8 = 1;
+const MAXIMUM_SEGMENT_SIZE: u8 = 2;
+const WINDOW_SCALE: u8 = 3;
+const SACK_PERMITTED: u8 = 4;
+const SACK: u8 = 5;
+const TIMESTAMPS: u8 = 8;
+const MD5_SIGNATURE: u8 = 19;
+const MULTIPATH_TCP: u8 = 30;
+const FAST_OPEN: u8 = 34;
+
+/// The most option space a header has, a data offset of 15 words less the fixed 20 bytes.
+pub const MAX_OPTIONS_LEN: usize = 40;
+
+/// The space a variable length option takes, padded with NOPs to 4 bytes.
+fn padded(len: usize) -> usize {
+    len.div_ceil(4) * 4
+}
+
+/// The options carried in a TCP header.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TcpOptions {
+    /// Maximum Segment Size, only valid on SYN segments.
+    pub mss: Option<u16>,
+
+    /// Window scale shift count, only valid on SYN segments.
+    pub window_scale: Option<u8>,
+
+    /// SACK-Permitted, only valid on SYN segments.
+    pub sack_permitted: bool,
+
+    /// Selective acknowledgment blocks, as (left edge, right edge) pairs.
+    pub sack_blocks: Vec<(u32, u32)>,
+
+    /// Timestamps as (TSval, TSecr).
+    pub timestamps: Option<(u32, u32)>,
+
+    /// MD5 signature, RFC 2385. Always written first, see `build_segment`.
+    pub md5: Option<[u8; 16]>,
+
+    /// TCP Fast Open cookie, RFC 7413. Empty is a cookie request.
+    pub fast_open: Option<Vec<u8>>,
+
+    /// A Multipath TCP option, RFC 8684, from its subtype byte on. Only the first
+    /// one in a header is kept.
+    pub mptcp: Option<Vec<u8>>,
+}
+
+impl TcpOptions {
+    /// Parses the options area of a header, ie. the bytes between the fixed 20 byte
+    /// header and the data offset. A malformed option ends parsing.
+    pub fn parse(raw: &[u8]) -> Self {
+        let mut options = TcpOptions::default();
+        let mut i = 0;
+
+        while i < raw.len() {
+            let kind = raw[i];
+            if kind == END_OF_LIST {
+                break;
+            }
+            if kind == NO_OPERATION {
+                i += 1;
+                continue;
+            }
+
+            let len = match raw.get(i + 1) {
+                Some(&len) if len >= 2 && i + len as usize <= raw.len() => len as usize,
+                _ => break,
+            };
+            let value = &raw[i + 2..i + len];
+
+            match (kind, value.len()) {
+                (MAXIMUM_SEGMENT_SIZE, 2) => {
+                    options.mss = Some(u16::from_be_bytes([value[0], value[1]]));
+                }
+                (WINDOW_SCALE, 1) => options.window_scale = Some(value[0]),
+                (SACK_PERMITTED, 0) => options.sack_permitted = true,
+                (SACK, n) if n % 8 == 0 => {
+                    for block in value.chunks(8) {
+                        options.sack_blocks.push((
+                            u32::from_be_bytes([block[0], block[1], block[2], block[3]]),
+                            u32::from_be_bytes([block[4], block[5], block[6], block[7]]),
+                        ));
+                    }
+                }
+                (MD5_SIGNATURE, 16) => {
+                    let mut signature = [0u8; 16];
+                    signature.clone_from_slice(value);
+                    options.md5 = Some(signature);
+                }
+                (FAST_OPEN, n) if n <= 16 => options.fast_open = Some(value.to_vec()),
+                (MULTIPATH_TCP, n) if n > 0 && options.mptcp.is_none() => {
+                    options.mptcp = Some(value.to_vec());
+                }
+                (TIMESTAMPS, 8) => {
+                    options.timestamps = Some((
+                        u32::from_be_bytes([value[0], value[1], value[2], value[3]]),
+                        u32::from_be_bytes([value[4], value[5], value[6], value[7]]),
+                    ));
+                }
+                _ => {}
+            }
+            i += len;
+        }
+        options
+    }
+
+    /// Writes the options into a header's options area, padding with NOPs to a 4 byte
+    /// boundary. Returns the number of bytes used. Options that don't fit in what is
+    /// left are left out, SACK blocks from the last one back.
+    pub fn write(&self, out: &mut [u8; MAX_OPTIONS_LEN]) -> usize {
+        let fits = |i: usize, len: usize| i + len <= MAX_OPTIONS_LEN;
+        let mut i = 0;
+
+        if let Some(signature) = self.md5 {
+            out[i] = NO_OPERATION;
+            out[i + 1] = NO_OPERATION;
+            out[i + 2] = MD5_SIGNATURE;
+            out[i + 3] = 18;
+            out[i + 4..i + 20].clone_from_slice(&signature);
+            i += 20;
+        }
+        if let Some(mss) = self.mss.filter(|_| fits(i, 4)) {
+            out[i] = MAXIMUM_SEGMENT_SIZE;
+            out[i + 1] = 4;
+            out[i + 2..i + 4].clone_from_slice(&mss.to_be_bytes());
+            i += 4;
+        }
+        if let Some(shift) = self.window_scale.filter(|_| fits(i, 4)) {
+            out[i] = NO_OPERATION;
+            out[i + 1] = WINDOW_SCALE;
+            out[i + 2] = 3;
+            out[i + 3] = shift;
+            i += 4;
+        }
+        if self.sack_permitted && fits(i, 4) {
+            out[i] = NO_OPERATION;
+            out[i + 1] = NO_OPERATION;
+            out[i + 2] = SACK_PERMITTED;
+            out[i + 3] = 2;
+            i += 4;
+        }
+        if let Some((tsval, tsecr)) = self.timestamps.filter(|_| fits(i, 12)) {
+            out[i] = NO_OPERATION;
+            out[i + 1] = NO_OPERATION;
+            out[i + 2] = TIMESTAMPS;
+            out[i + 3] = 10;
+            out[i + 4..i + 8].clone_from_slice(&tsval.to_be_bytes());
+            out[i + 8..i + 12].clone_from_slice(&tsecr.to_be_bytes());
+            i += 12;
+        }
+        let fast_open = self.fast_open.as_ref();
+        if let Some(cookie) = fast_open.filter(|cookie| fits(i, padded(2 + cookie.len()))) {
+            let len = 2 + cookie.len();
+            out[i] = FAST_OPEN;
+            out[i + 1] = len as u8;
+            out[i + 2..i + len].clone_from_slice(cookie);
+            i += len;
+            while i % 4 != 0 {
+                out[i] = NO_OPERATION;
+                i += 1;
+            }
+        }
+        let mptcp = self.mptcp.as_ref();
+        if let Some(value) = mptcp.filter(|value| fits(i, padded(2 + value.len()))) {
+            let len = 2 + value.len();
+            out[i] = MULTIPATH_TCP;
+            out[i + 1] = len as u8;
+            out[i + 2..i + len].clone_from_slice(value);
+            i += len;
+            while i % 4 != 0 {
+                out[i] = NO_OPERATION;
+                i += 1;
+            }
+        }
+        if !self.sack_blocks.is_empty() && fits(i, 4 + 8) {
+            // Only as many blocks as fit in what is left.
+            let blocks = self.sack_blocks.len().min((MAX_OPTIONS_LEN - i - 4) / 8);
+            out[i] = NO_OPERATION;
+            out[i + 1] = NO_OPERATION;
+            out[i + 2] = SACK;
+            out[i + 3] = 2 + 8 * blocks as u8;
+            i += 4;
+            for (left, right) in self.sack_blocks.iter().take(blocks) {
+                out[i..i + 4].clone_from_slice(&left.to_be_bytes());
+                out[i + 4..i + 8].clone_from_slice(&right.to_be_bytes());
+                i += 8;
+            }
+        }
+        i
+    }
+}
@@ -0,0 +1,226 @@
+// Checkpoint and restore of connections, along the lines of Linux's TCP_REPAIR.
+//
+// A checkpoint is a connection's whole TCB written out as bytes: sequence space,
+// windows, negotiated options, timers and any data still buffered in either
+// direction. Restoring it in another pct process carries the connection on from
+// where it was taken, without the peer seeing anything but a pause, so a service
+// can be moved between instances as long as its address moves with it.
+//
+// Timers are written as the time left on them, as `Instant`s mean nothing in
+// another process. MPTCP subflows belong to a connection spread over several
+// TCBs and can't be checkpointed on their own.
+
+use std::convert::TryInto;
+use std::io;
+use std::time::{Duration, Instant};
+
+use super::connection::Connection;
+use super::{Connections, Quad, State};
+
+/// Leads every checkpoint, followed by a format version.
+const MAGIC: &[u8; 4] = b"PCTR";
+const VERSION: u8 = 1;
+
+/// Every state, in the order they are numbered in a checkpoint.
+const STATES: [State; 11] = [
+    State::Listen,
+    State::SynSent,
+    State::SynRcvd,
+    State::Established,
+    State::FinWait1,
+    State::FinWait2,
+    State::CloseWait,
+    State::Closing,
+    State::LastAck,
+    State::TimeWait,
+    State::Closed,
+];
+
+/// Appends values to a checkpoint, all integers big endian.
+#[derive(Default)]
+pub(super) struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    /// Durations are kept to the microsecond.
+    pub fn duration(&mut self, value: Duration) {
+        self.u64(value.as_micros() as u64);
+    }
+
+    pub fn state(&mut self, state: State) {
+        let index = STATES.iter().position(|s| *s == state).unwrap_or(0);
+        self.u8(index as u8);
+    }
+
+    pub fn quad(&mut self, quad: &Quad) {
+        self.u32(quad.local.0);
+        self.u16(quad.local.1);
+        self.u32(quad.remote.0);
+        self.u16(quad.remote.1);
+    }
+
+    /// A length followed by the bytes.
+    pub fn bytes<'a>(&mut self, bytes: impl ExactSizeIterator<Item = &'a u8>) {
+        self.u32(bytes.len() as u32);
+        self.buf.extend(bytes);
+    }
+
+    /// A presence byte followed by the value, if there is one.
+    pub fn option<T>(&mut self, value: Option<T>, write: impl FnOnce(&mut Self, T)) {
+        self.bool(value.is_some());
+        if let Some(value) = value {
+            write(self, value);
+        }
+    }
+}
+
+/// Reads values back out of a checkpoint. Running out of bytes, or finding a
+/// value that can't be right, is `InvalidData`.
+pub(super) struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+pub(super) fn invalid(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("bad checkpoint: {}", what),
+    )
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(invalid("truncated"));
+        }
+        let (taken, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid("flag")),
+        }
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn duration(&mut self) -> io::Result<Duration> {
+        Ok(Duration::from_micros(self.u64()?))
+    }
+
+    pub fn state(&mut self) -> io::Result<State> {
+        STATES
+            .get(self.u8()? as usize)
+            .copied()
+            .ok_or_else(|| invalid("state"))
+    }
+
+    pub fn quad(&mut self) -> io::Result<Quad> {
+        Ok(Quad {
+            local: (self.u32()?, self.u16()?),
+            remote: (self.u32()?, self.u16()?),
+        })
+    }
+
+    pub fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    pub fn option<T>(
+        &mut self,
+        read: impl FnOnce(&mut Self) -> io::Result<T>,
+    ) -> io::Result<Option<T>> {
+        match self.bool()? {
+            true => read(self).map(Some),
+            false => Ok(None),
+        }
+    }
+}
+
+impl Connections {
+    /// Takes a connection out of the stack as a checkpoint for `restore`. Nothing
+    /// is sent to the peer, the connection is simply forgotten here, so segments
+    /// still queued for it by `poll_transmit` should be sent first.
+    pub fn checkpoint(&mut self, quad: &Quad) -> io::Result<Vec<u8>> {
+        let c = self
+            .connections
+            .get(quad)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        if c.mptcp().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "MPTCP subflows can't be checkpointed",
+            ));
+        }
+
+        let mut w = Writer::default();
+        w.buf.extend_from_slice(MAGIC);
+        w.u8(VERSION);
+        c.checkpoint(Instant::now(), &mut w);
+
+        self.connections.remove(quad);
+        self.established.retain(|q| q != quad);
+        println!("[TCP] checkpointed {:?}, {} bytes", quad, w.buf.len());
+        Ok(w.buf)
+    }
+
+    /// Rebuilds a connection from a `checkpoint`, returning its quad. It carries on
+    /// as it was, retransmitting whatever was unacknowledged when its timer fires.
+    pub fn restore(&mut self, checkpoint: &[u8]) -> io::Result<Quad> {
+        let mut r = Reader { buf: checkpoint };
+        if r.take(MAGIC.len())? != MAGIC || r.u8()? != VERSION {
+            return Err(invalid("not a checkpoint, or from another version"));
+        }
+        let c = Connection::restore(&mut r, Instant::now())?;
+        if !r.buf.is_empty() {
+            return Err(invalid("trailing bytes"));
+        }
+
+        let quad = c.quad();
+        if self.connections.contains_key(&quad) {
+            return Err(io::Error::from(io::ErrorKind::AddrInUse));
+        }
+        self.connections.insert(quad, c);
+        println!("[TCP] restored {:?}", quad);
+        Ok(quad)
+    }
+}
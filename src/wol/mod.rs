@@ -0,0 +1,221 @@
+// Wake-on-LAN. A magic packet is six 0xFF bytes followed by the MAC of the host
+// to wake, sixteen times over, and optionally a SecureOn password of 4 or 6
+// bytes. Sleeping NICs look for the pattern anywhere in a frame, so it is sent
+// either as the payload of an EtherType 0x0842 frame or as a UDP datagram to the
+// discard port, 9, both broadcast since the host has no address to answer to.
+//
+// We can wake other hosts, and tell whoever is interested when a magic packet
+// for our own MAC comes by.
+
+use std::collections::VecDeque;
+
+use crate::eth::EtherType;
+use crate::iface::Interface;
+use crate::ipv4::{self, ProtoType};
+
+/// The port magic packets are sent to over UDP.
+pub const UDP_PORT: u16 = 9;
+
+/// The 0xFF bytes and MAC repetitions, without a password.
+const MAGIC_LEN: usize = 6 + 16 * 6;
+
+/// Events are dropped, oldest first, past this many unpolled.
+const MAX_EVENTS: usize = 16;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum WakeSource {
+    /// A raw frame from this MAC.
+    Ethernet([u8; 6]),
+
+    /// A UDP datagram from this address and port.
+    Udp(u32, u16),
+}
+
+/// A magic packet for our MAC arrived.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WakeEvent {
+    pub source: WakeSource,
+
+    /// The SecureOn password, empty if there was none.
+    pub password: Vec<u8>,
+}
+
+/// A magic packet for `mac`, with a `password` of 4 or 6 bytes or none at all.
+pub fn magic_packet(mac: [u8; 6], password: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0xFF; 6];
+    for _ in 0..16 {
+        packet.extend_from_slice(&mac);
+    }
+    packet.extend_from_slice(password);
+    packet
+}
+
+/// The MAC the magic packet in `payload` is for, and its password. The pattern
+/// may be anywhere, a password is what follows it when that is 4 or 6 bytes.
+pub fn parse(payload: &[u8]) -> Option<([u8; 6], Vec<u8>)> {
+    (0..payload.len().saturating_sub(MAGIC_LEN - 1)).find_map(|start| {
+        let magic = &payload[start..start + MAGIC_LEN];
+        if magic[..6] != [0xFF; 6] {
+            return None;
+        }
+        let mac = &magic[6..12];
+        if !magic[6..].chunks(6).all(|repeat| repeat == mac) {
+            return None;
+        }
+        let mut target = [0u8; 6];
+        target.clone_from_slice(mac);
+        let rest = &payload[start + MAGIC_LEN..];
+        let password = match rest.len() {
+            4 | 6 => rest.to_vec(),
+            _ => vec![],
+        };
+        Some((target, password))
+    })
+}
+
+#[derive(Default)]
+pub struct Wol {
+    events: VecDeque<WakeEvent>,
+
+    /// Magic packets to send, see `poll_transmit`.
+    outbound: VecDeque<Vec<u8>>,
+}
+
+impl Wol {
+    pub fn new() -> Self {
+        Wol::default()
+    }
+
+    /// Wakes `mac` with a raw EtherType 0x0842 frame broadcast on `iface`'s link.
+    pub fn wake(&mut self, iface: &Interface, mac: [u8; 6], password: &[u8]) {
+        let packet = magic_packet(mac, password);
+        self.outbound
+            .push_back(iface.frame([0xFF; 6], EtherType::WakeOnLan, &packet));
+    }
+
+    /// Wakes `mac` with a UDP datagram to port 9 of `broadcast_ip`, usually the
+    /// broadcast address of the host's subnet.
+    pub fn wake_udp(
+        &mut self,
+        iface: &Interface,
+        mac: [u8; 6],
+        password: &[u8],
+        broadcast_ip: u32,
+    ) {
+        let source_ip = iface.source_for(broadcast_ip).unwrap_or(0);
+        let payload = magic_packet(mac, password);
+        let mut datagram = vec![0u8; 8];
+        datagram[0..2].clone_from_slice(&UDP_PORT.to_be_bytes());
+        datagram[2..4].clone_from_slice(&UDP_PORT.to_be_bytes());
+        datagram[4..6].clone_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        datagram.extend_from_slice(&payload);
+        let checksum =
+            ipv4::pseudo_header_checksum(source_ip, broadcast_ip, ProtoType::UDP, &datagram);
+        // All zeroes means no checksum, RFC 768.
+        let checksum = if checksum == 0 { 0xFFFF } else { checksum };
+        datagram[6..8].clone_from_slice(&checksum.to_be_bytes());
+
+        let mut packet =
+            crate::pkt::build_ipv4_header(source_ip, broadcast_ip, ProtoType::UDP, datagram.len())
+                .to_vec();
+        packet.extend_from_slice(&datagram);
+        self.outbound
+            .push_back(iface.frame([0xFF; 6], EtherType::Ipv4, &packet));
+    }
+
+    /// Handles the payload of an EtherType 0x0842 frame from `source_mac`.
+    pub fn read_frame(&mut self, iface: &Interface, source_mac: [u8; 6], payload: &[u8]) {
+        self.read(iface, WakeSource::Ethernet(source_mac), payload);
+    }
+
+    /// Handles a UDP datagram from `source_ip` for the WoL port, header included.
+    /// Datagrams with a bad checksum are dropped.
+    pub fn read_udp(&mut self, iface: &Interface, source_ip: u32, dest_ip: u32, datagram: &[u8]) {
+        if datagram.len() < 8 {
+            return;
+        }
+        let length = u16::from_be_bytes([datagram[4], datagram[5]]) as usize;
+        let datagram = match datagram.get(..length) {
+            Some(datagram) if length >= 8 => datagram,
+            _ => return,
+        };
+        let checksum = u16::from_be_bytes([datagram[6], datagram[7]]);
+        if checksum != 0
+            && ipv4::pseudo_header_checksum(source_ip, dest_ip, ProtoType::UDP, datagram) != 0
+        {
+            println!("[WOL] bad UDP checksum");
+            return;
+        }
+        let source_port = u16::from_be_bytes([datagram[0], datagram[1]]);
+        self.read(
+            iface,
+            WakeSource::Udp(source_ip, source_port),
+            &datagram[8..],
+        );
+    }
+
+    /// Takes the next magic packet for us that arrived.
+    pub fn poll_event(&mut self) -> Option<WakeEvent> {
+        self.events.pop_front()
+    }
+
+    /// Takes the next frame that should be sent.
+    pub fn poll_transmit(&mut self) -> Option<Vec<u8>> {
+        self.outbound.pop_front()
+    }
+
+    fn read(&mut self, iface: &Interface, source: WakeSource, payload: &[u8]) {
+        let (mac, password) = match parse(payload) {
+            Some(magic) => magic,
+            None => return,
+        };
+        if mac != iface.mac() {
+            println!("[WOL] magic packet for {:X?}", mac);
+            return;
+        }
+        println!("[WOL] woken by {:X?}", source);
+        if self.events.len() == MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(WakeEvent { source, password });
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_wol() {
+    let iface = Interface::default();
+    let mut wol = Wol::new();
+    let other = [0x02, 0, 0, 0, 0, 0x01];
+
+    // Raw frames, with the pattern somewhere in the payload.
+    wol.wake(&iface, iface.mac(), &[]);
+    let frame = wol.poll_transmit().unwrap();
+    assert_eq!(frame[16..18], [0x08, 0x42]);
+    let mut payload = vec![0x00; 10];
+    payload.extend_from_slice(&frame[18..]);
+    wol.read_frame(&iface, other, &payload);
+    let event = WakeEvent {
+        source: WakeSource::Ethernet(other),
+        password: vec![],
+    };
+    assert_eq!(wol.poll_event(), Some(event));
+    wol.read_frame(&iface, other, &magic_packet(other, &[]));
+    assert_eq!(wol.poll_event(), None);
+
+    // UDP, with a SecureOn password.
+    let password = [1, 2, 3, 4, 5, 6];
+    wol.wake_udp(&iface, iface.mac(), &password, 0x0a0000ff);
+    let frame = wol.poll_transmit().unwrap();
+    let datagram = &frame[38..];
+    assert_eq!(datagram[2..4], UDP_PORT.to_be_bytes());
+    wol.read_udp(&iface, 0x0a000002, 0x0a0000ff, datagram);
+    let event = wol.poll_event().unwrap();
+    assert_eq!(event.source, WakeSource::Udp(0x0a000002, UDP_PORT));
+    assert_eq!(event.password, password);
+
+    let mut corrupt = datagram.to_vec();
+    corrupt[20] ^= 1;
+    wol.read_udp(&iface, 0x0a000002, 0x0a0000ff, &corrupt);
+    assert_eq!(wol.poll_event(), None);
+}